@@ -1,3 +1,44 @@
+use std::process::Command;
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    println!(
+        "cargo:rustc-env=FIAT_GIT_HASH={}",
+        command_output("git", &["rev-parse", "--short=8", "HEAD"])
+    );
+    println!(
+        "cargo:rustc-env=FIAT_BUILD_DATE={}",
+        command_output("date", &["-u", "+%Y-%m-%d"])
+    );
+
+    // HEAD itself only changes on checkouts, while the commits move the ref it points to
+    // (and are all logged)
+    let mut watched = vec![".git/HEAD".to_string(), ".git/logs/HEAD".to_string()];
+
+    if let Some(head) = std::fs::read_to_string(".git/HEAD").ok().and_then(|head| {
+        head.strip_prefix("ref: ")
+            .map(|head| head.trim().to_string())
+    }) {
+        watched.push(format!(".git/{}", head));
+    }
+
+    // A path that does not exist would rerun the script on every build
+    for path in watched
+        .iter()
+        .filter(|path| std::path::Path::new(path).exists())
+    {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
+
+fn command_output(command: &str, args: &[&str]) -> String {
+    Command::new(command)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
 }
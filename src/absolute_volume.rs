@@ -0,0 +1,137 @@
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use esp_idf_svc::sys::{
+    esp, esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_SET,
+    esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE, esp_avrc_rn_evt_bit_mask_operation,
+    esp_avrc_rn_evt_cap_mask_t, esp_avrc_rn_param_t, esp_avrc_rn_rsp_t,
+    esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED, esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_INTERIM,
+    esp_avrc_tg_cb_event_t, esp_avrc_tg_cb_event_t_ESP_AVRC_TG_CONNECTION_STATE_EVT,
+    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_REGISTER_NOTIFICATION_EVT,
+    esp_avrc_tg_cb_event_t_ESP_AVRC_TG_SET_ABSOLUTE_VOLUME_CMD_EVT, esp_avrc_tg_cb_param_t,
+    esp_avrc_tg_deinit, esp_avrc_tg_init, esp_avrc_tg_register_callback, esp_avrc_tg_send_rn_rsp,
+    esp_avrc_tg_set_rn_evt_cap, EspError,
+};
+
+use log::{info, warn};
+
+use crate::error::Error;
+
+/// The AVRCP absolute volume ranges up to that
+const MAX_VOLUME: u8 = 127;
+const STEP: u8 = 8;
+
+/// The volume the phone last set, or the one last notified from here
+static VOLUME: AtomicU8 = AtomicU8::new(MAX_VOLUME / 2);
+/// The phone registers for each volume change notification anew, once the previous one was sent
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// The AVRCP target role, in which the adapter is the rendering device whose absolute volume
+/// the phone follows. Set from the wheel keys, it is notified to the phone, rather than commanded
+/// as from a controller
+///
+/// NOTE: The volume is not applied to the output, as the radio's own volume stays in charge,
+/// i.e. phones leaving the scaling to the sink play at their full level
+pub struct AbsoluteVolume(());
+
+impl AbsoluteVolume {
+    /// To be created after Bluedroid is enabled, and dropped before it is disabled
+    pub fn new() -> Result<Self, Error> {
+        esp!(unsafe { esp_avrc_tg_register_callback(Some(handle_tg)) })?;
+        esp!(unsafe { esp_avrc_tg_init() })?;
+
+        let mut events = esp_avrc_rn_evt_cap_mask_t { bits: 0 };
+
+        unsafe {
+            esp_avrc_rn_evt_bit_mask_operation(
+                esp_avrc_bit_mask_op_t_ESP_AVRC_BIT_MASK_OP_SET,
+                &mut events,
+                esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE,
+            );
+        }
+
+        esp!(unsafe { esp_avrc_tg_set_rn_evt_cap(&events) })?;
+
+        Ok(Self(()))
+    }
+
+    /// Steps the volume and notifies the phone, unless it did not register for the notification
+    pub fn step(&self, up: bool) -> Result<(), Error> {
+        let volume = VOLUME.load(Ordering::SeqCst);
+
+        let volume = if up {
+            volume.saturating_add(STEP).min(MAX_VOLUME)
+        } else {
+            volume.saturating_sub(STEP)
+        };
+
+        VOLUME.store(volume, Ordering::SeqCst);
+
+        if REGISTERED.swap(false, Ordering::SeqCst) {
+            notify(esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_CHANGED, volume)?;
+        } else {
+            warn!(
+                "Phone not registered for the volume changes, volume: {}",
+                volume
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for AbsoluteVolume {
+    fn drop(&mut self) {
+        REGISTERED.store(false, Ordering::SeqCst);
+
+        if let Err(err) = esp!(unsafe { esp_avrc_tg_deinit() }) {
+            warn!("Deinitializing the AVRCP target failed: {}", err);
+        }
+    }
+}
+
+fn notify(rsp: esp_avrc_rn_rsp_t, volume: u8) -> Result<(), EspError> {
+    let mut param = esp_avrc_rn_param_t { volume };
+
+    esp!(unsafe {
+        esp_avrc_tg_send_rn_rsp(
+            esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE,
+            rsp,
+            &mut param,
+        )
+    })
+}
+
+/// Called on the Bluedroid task
+unsafe extern "C" fn handle_tg(event: esp_avrc_tg_cb_event_t, param: *mut esp_avrc_tg_cb_param_t) {
+    #[allow(non_upper_case_globals)]
+    match event {
+        esp_avrc_tg_cb_event_t_ESP_AVRC_TG_CONNECTION_STATE_EVT
+            if !(*param).conn_stat.connected =>
+        {
+            REGISTERED.store(false, Ordering::SeqCst);
+        }
+        esp_avrc_tg_cb_event_t_ESP_AVRC_TG_REGISTER_NOTIFICATION_EVT
+            if (*param).reg_ntf.event_id as u32
+                == esp_avrc_rn_event_ids_t_ESP_AVRC_RN_VOLUME_CHANGE =>
+        {
+            REGISTERED.store(true, Ordering::SeqCst);
+
+            let result = notify(
+                esp_avrc_rn_rsp_t_ESP_AVRC_RN_RSP_INTERIM,
+                VOLUME.load(Ordering::SeqCst),
+            );
+
+            if let Err(err) = result {
+                warn!("Notifying the volume failed: {}", err);
+            }
+        }
+        esp_avrc_tg_cb_event_t_ESP_AVRC_TG_SET_ABSOLUTE_VOLUME_CMD_EVT => {
+            let volume = (*param).set_abs_vol.volume;
+
+            info!("Phone set the absolute volume: {}", volume);
+
+            VOLUME.store(volume.min(MAX_VOLUME), Ordering::SeqCst);
+        }
+        _ => (),
+    }
+}
@@ -0,0 +1,440 @@
+use core::mem::MaybeUninit;
+
+use edge_executor::LocalExecutor;
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::mutex::Mutex;
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::adc::{AdcMeasurement, ADC1};
+use esp_idf_svc::hal::can::CAN;
+use esp_idf_svc::hal::gpio::{ADCPin, AnyIOPin, AnyOutputPin, InputPin, OutputPin};
+use esp_idf_svc::hal::i2s::{I2s, I2S0};
+use esp_idf_svc::hal::modem::{BluetoothModemPeripheral, WifiModemPeripheral};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::task::block_on;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::timer::EspTaskTimerService;
+
+use log::{error, info, warn};
+
+use crate::arena::Arena;
+use crate::audio::{create_audio_buffers, SharedAudioBuffers};
+use crate::board::AudioOutput;
+use crate::bus::{
+    diag::{BootReason, Diagnostic},
+    Bus, Service,
+};
+use crate::error::Error;
+use crate::instrument::{self, PollStats};
+use crate::storage::Storage;
+use crate::usb_cutoff::UsbCutoff;
+use crate::{
+    audio, bt, can, commands, console, diag, displays, presence, sim, storage, thermal, updates,
+};
+
+/// Composes the application out of the individual services.
+///
+/// Each `with_*` method spawns one service on the application executor,
+/// allocating whatever buffers the service needs. Services which are not added
+/// simply never get started, which allows for reduced variants of the firmware
+/// (simulations, bench tests, boards without a microphone and so on).
+pub struct App<'a> {
+    bus: &'a Bus,
+    boot: BootReason,
+    executor: LocalExecutor<'a>,
+    poll_stats: &'a PollStats,
+    storage: &'a Storage,
+    arena: &'a Arena<'static>,
+    audio_buffers: Option<&'a SharedAudioBuffers<'a>>,
+}
+
+impl<'a> App<'a> {
+    pub fn new(bus: &'a Bus, boot: BootReason) -> Self {
+        let arena = Box::leak(Box::new(Arena::new()));
+
+        // Reserved upfront, as by the time an update is requested, the heap is too fragmented
+        let ota_buf = leak_uninit::<[u8; updates::OTA_BUF_SIZE]>();
+
+        info!("OTA buf allocated: {:p}", ota_buf);
+
+        let console_buf = leak_uninit::<[u8; console::WRITE_BUF_SIZE]>();
+
+        info!("Console buf allocated: {:p}", console_buf);
+
+        let bufs: [&'static mut [u8]; 2] = [ota_buf, console_buf];
+
+        for buf in bufs {
+            if let Err(buf) = arena.reserve(buf) {
+                warn!(
+                    "Too many arena slots, buffer of {}B not reserved",
+                    buf.len()
+                );
+            }
+        }
+
+        Self {
+            bus,
+            boot,
+            executor: Default::default(),
+            poll_stats: Box::leak(Box::new(PollStats::new())),
+            storage: Box::leak(Box::new(Storage::new())),
+            arena,
+            audio_buffers: None,
+        }
+    }
+
+    /// All services persisting state depend on the storage service, so it should always be added
+    pub fn with_storage(self, nvs: EspDefaultNvsPartition) -> Self {
+        let bus = self.bus;
+        let storage = self.storage;
+
+        self.spawn(
+            Service::Storage,
+            storage::process(bus.subscription(Service::Storage), storage, nvs),
+        )
+    }
+
+    pub fn with_bt(
+        mut self,
+        modem: &'a Mutex<impl RawMutex, impl Peripheral<P = impl BluetoothModemPeripheral> + 'a>,
+        nvs: EspDefaultNvsPartition,
+    ) -> Self {
+        let audio_buffers = self.audio_buffers();
+        let bus = self.bus;
+        let storage = self.storage;
+        let boot = self.boot;
+
+        self.spawn(
+            Service::Bt,
+            bt::process(
+                modem,
+                nvs,
+                bus.subscription(Service::Bt),
+                bus.bt.sender(),
+                bus.audio.sender(),
+                bus.audio_codec.sender(),
+                bus.audio_track.sender(),
+                bus.phone.sender(),
+                bus.phone_call.sender(),
+                bus.hfp_indicators.sender(),
+                bus.takeover.sender(),
+                bus.bonded.sender(),
+                bus.identity.sender(),
+                bus.link.sender(),
+                bus.update.sender(),
+                bus.notification.sender(),
+                bus.can_frames.sender(),
+                bus.diagnostics.sender(),
+                audio_buffers,
+                self.arena,
+                storage,
+                boot,
+            ),
+        )
+    }
+
+    /// Stands in for `with_bt` when developing without a phone, see the `sim` feature
+    pub fn with_sim_phone(self) -> Self {
+        let bus = self.bus;
+
+        self.spawn(
+            Service::Bt,
+            sim::process(
+                bus.subscription(Service::Bt),
+                bus.bt.sender(),
+                bus.audio.sender(),
+                bus.audio_track.sender(),
+                bus.phone.sender(),
+                bus.phone_call.sender(),
+            ),
+        )
+    }
+
+    pub fn with_audio_mux(mut self) -> Self {
+        let audio_buffers = self.audio_buffers();
+        let bus = self.bus;
+
+        self.spawn(
+            Service::AudioMux,
+            audio::process_audio_mux(bus.subscription(Service::AudioMux), audio_buffers),
+        )
+    }
+
+    pub fn with_mic(
+        mut self,
+        adc1: impl Peripheral<P = ADC1> + 'a,
+        pin: impl Peripheral<P = impl ADCPin<Adc = ADC1>> + 'a,
+        i2s0: impl Peripheral<P = I2S0> + 'a,
+    ) -> Self {
+        let audio_buffers = self.audio_buffers();
+        let adc_buf = leak_uninit::<[AdcMeasurement; 2 * audio::MIC_FRAME_MEASUREMENTS]>();
+        let bus = self.bus;
+
+        info!("ADC buf allocated: {:p}", adc_buf);
+
+        self.spawn(
+            Service::Microphone,
+            audio::process_microphone(
+                bus.subscription(Service::Microphone),
+                adc1,
+                pin,
+                i2s0,
+                adc_buf,
+                audio_buffers,
+                || {},
+            ),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn with_speakers(
+        mut self,
+        i2s: impl Peripheral<P = impl I2s> + 'a,
+        bclk: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+        dout: impl Peripheral<P = impl OutputPin> + 'a,
+        ws: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+        mclk: Option<AnyIOPin>,
+        mute: Option<AnyOutputPin>,
+        output: &'a AudioOutput,
+    ) -> Self {
+        let audio_buffers = self.audio_buffers();
+        let i2s_buf = leak_uninit::<[u8; 4000]>();
+        let spdif_buf: &mut [u32] = match output {
+            AudioOutput::Spdif => leak_uninit::<[u32; 1024]>(),
+            AudioOutput::Dac(_) => &mut [],
+        };
+        let bus = self.bus;
+
+        info!("I2S buf allocated: {:p}", i2s_buf);
+
+        self.spawn(
+            Service::Speakers,
+            audio::process_speakers(
+                bus.subscription(Service::Speakers),
+                i2s,
+                bclk,
+                dout,
+                ws,
+                mclk,
+                mute,
+                output,
+                audio_buffers,
+                i2s_buf,
+                spdif_buf,
+            ),
+        )
+    }
+
+    pub fn with_can(
+        self,
+        can: impl Peripheral<P = CAN> + 'a,
+        tx: impl Peripheral<P = impl OutputPin> + 'a,
+        rx: impl Peripheral<P = impl InputPin> + 'a,
+    ) -> Self {
+        let str_buf = Box::leak(Box::new(can::ChunkString::new()));
+        let bus = self.bus;
+        let storage = self.storage;
+
+        self.spawn(
+            Service::Can,
+            can::process(
+                bus.subscription(Service::Can),
+                can,
+                tx,
+                rx,
+                str_buf,
+                bus.radio.sender(),
+                bus.radio_volume.sender(),
+                bus.buttons.sender(),
+                bus.speed.sender(),
+                bus.parking.sender(),
+                bus.radio_commands.sender(),
+                bus.vehicle.sender(),
+                bus.car_clock.sender(),
+                bus.fm_station.sender(),
+                bus.diagnostics.sender(),
+                storage,
+            ),
+        )
+    }
+
+    pub fn with_displays(self) -> Self {
+        let bus = self.bus;
+
+        self.spawn(
+            Service::RadioDisplay,
+            displays::process(
+                bus.subscription(Service::RadioDisplay),
+                bus.radio_display.sender(),
+                bus.cockpit_display.sender(),
+                bus.notification.sender(),
+            ),
+        )
+    }
+
+    pub fn with_commands(
+        mut self,
+        usb_cutoff: impl Peripheral<P = impl OutputPin> + 'a,
+    ) -> Result<Self, Error> {
+        let audio_buffers = self.audio_buffers();
+        let bus = self.bus;
+        let boot = self.boot;
+        let storage = self.storage;
+
+        Ok(self.spawn(
+            Service::Commands,
+            commands::process(
+                bus.subscription(Service::Commands),
+                UsbCutoff::new(usb_cutoff)?,
+                bus.button_commands.sender(),
+                bus.cockpit_display.sender(),
+                audio_buffers,
+                storage,
+                boot,
+            ),
+        ))
+    }
+
+    pub fn with_updates(
+        self,
+        modem: &'a Mutex<impl RawMutex, impl Peripheral<P = impl WifiModemPeripheral> + 'a>,
+        sysloop: EspSystemEventLoop,
+        timer_service: EspTaskTimerService,
+    ) -> Self {
+        let bus = self.bus;
+        let arena = self.arena;
+
+        self.spawn(
+            Service::Wifi,
+            updates::process(
+                bus.subscription(Service::Wifi),
+                modem,
+                sysloop,
+                timer_service,
+                arena,
+                bus.notification.sender(),
+                bus.diagnostics.sender(),
+            ),
+        )
+    }
+
+    pub fn with_presence(self) -> Self {
+        let bus = self.bus;
+
+        self.spawn(
+            Service::Presence,
+            presence::process(bus.subscription(Service::Presence)),
+        )
+    }
+
+    pub fn with_diagnostics(self) -> Self {
+        let bus = self.bus;
+        let storage = self.storage;
+
+        self.spawn(
+            Service::Diagnostics,
+            diag::process(
+                bus.subscription(Service::Diagnostics),
+                bus.notification.sender(),
+                storage,
+            ),
+        )
+    }
+
+    pub fn with_thermal(self) -> Self {
+        let bus = self.bus;
+        let storage = self.storage;
+
+        self.spawn(
+            Service::Thermal,
+            thermal::process(
+                bus.subscription(Service::Thermal),
+                bus.thermal.sender(),
+                bus.diagnostics.sender(),
+                storage,
+            ),
+        )
+    }
+
+    pub fn run(self) -> Result<(), Error> {
+        info!("Running");
+
+        self.executor
+            .spawn(instrument::report(
+                self.poll_stats,
+                self.bus.diagnostics.sender(),
+            ))
+            .detach();
+
+        // self.executor
+        //     .spawn(
+        //         async move {
+        //             loop {
+        //                 Timer::after(Duration::from_secs(10)).await;
+
+        //                 unsafe {
+        //                     heap_caps_print_heap_info(MALLOC_CAP_DEFAULT);
+        //                 }
+        //             }
+        //         },
+        //     )
+        //     .detach();
+
+        block_on(self.executor.run(core::future::pending::<()>()));
+
+        Ok(())
+    }
+
+    fn spawn<F>(self, service: Service, fut: F) -> Self
+    where
+        F: core::future::Future<Output = Result<(), Error>> + 'a,
+    {
+        self.bus.system.sender().modify(|system| {
+            system.set_spawned(service);
+            false
+        });
+
+        let diagnostics = self.bus.diagnostics.sender();
+
+        // The task is detached, so its error is only seen through the diagnostics
+        let fut = async move {
+            let result = fut.await;
+
+            if let Err(err) = &result {
+                error!("Service {:?} failed: {}", service, err);
+
+                diagnostics.send(Diagnostic::ServiceFailed {
+                    service,
+                    code: err.code(),
+                });
+            }
+
+            result
+        };
+
+        self.executor
+            .spawn(self.poll_stats.instrument(service, fut))
+            .detach();
+
+        self
+    }
+
+    fn audio_buffers(&mut self) -> &'a SharedAudioBuffers<'a> {
+        *self.audio_buffers.get_or_insert_with(|| {
+            let incoming = leak_uninit::<[u8; 32768]>();
+            let outgoing = leak_uninit::<[u8; 8192]>();
+
+            info!("Audio bufs allocated {:p}, {:p}", incoming, outgoing);
+
+            Box::leak(Box::new(create_audio_buffers(incoming, outgoing)))
+        })
+    }
+}
+
+/// The buffers live for as long as the firmware runs, hence leaking them is fine
+fn leak_uninit<T>() -> &'static mut T {
+    let buf: &'static mut MaybeUninit<T> = Box::leak(Box::new_uninit());
+
+    unsafe { buf.assume_init_mut() }
+}
@@ -0,0 +1,65 @@
+use core::cell::RefCell;
+use core::ops::{Deref, DerefMut};
+
+const MAX_SLOTS: usize = 4;
+
+/// Large transient buffers, reserved at boot while the heap is not yet fragmented,
+/// and lent out to the services which only need them occasionally (the OTA update, the console)
+///
+/// Buffers allocated internally by ESP-IDF (the BT stack, the HTTP client) cannot be served from here
+pub struct Arena<'a>(RefCell<heapless::Vec<&'a mut [u8], MAX_SLOTS>>);
+
+impl<'a> Arena<'a> {
+    pub const fn new() -> Self {
+        Self(RefCell::new(heapless::Vec::new()))
+    }
+
+    /// Gives the buffer back if all `MAX_SLOTS` slots are taken
+    pub fn reserve(&self, buf: &'a mut [u8]) -> Result<(), &'a mut [u8]> {
+        self.0.borrow_mut().push(buf)
+    }
+
+    /// Lends the smallest free buffer of at least `len` bytes
+    pub fn borrow(&self, len: usize) -> Option<Loan<'_, 'a>> {
+        let mut slots = self.0.borrow_mut();
+
+        let index = slots
+            .iter()
+            .enumerate()
+            .filter(|(_, buf)| buf.len() >= len)
+            .min_by_key(|(_, buf)| buf.len())
+            .map(|(index, _)| index)?;
+
+        Some(Loan {
+            arena: self,
+            buf: Some(slots.swap_remove(index)),
+        })
+    }
+}
+
+/// A buffer borrowed from the arena, which gets it back once dropped
+pub struct Loan<'r, 'a> {
+    arena: &'r Arena<'a>,
+    buf: Option<&'a mut [u8]>,
+}
+
+impl<'r, 'a> Deref for Loan<'r, 'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.buf.as_deref().unwrap()
+    }
+}
+
+impl<'r, 'a> DerefMut for Loan<'r, 'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.buf.as_deref_mut().unwrap()
+    }
+}
+
+impl<'r, 'a> Drop for Loan<'r, 'a> {
+    fn drop(&mut self) {
+        // Cannot fail, as the slot the buffer came from is still free
+        let _ = self.arena.reserve(self.buf.take().unwrap());
+    }
+}
@@ -1,15 +1,18 @@
 use core::cell::RefCell;
 use core::pin::pin;
 
-use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select, select3, Either, Either3};
 
-use embassy_sync::{blocking_mutex::Mutex, signal::Signal};
+use embassy_sync::{
+    blocking_mutex::{raw::RawMutex, Mutex},
+    signal::Signal,
+};
 
 use esp_idf_svc::hal::i2s::I2sTxSupported;
 
 use esp_idf_svc::hal::{
     adc::{AdcContConfig, AdcContDriver, AdcMeasurement, Attenuated, ADC1},
-    gpio::{ADCPin, AnyIOPin, InputPin, OutputPin},
+    gpio::{ADCPin, AnyIOPin, AnyOutputPin, InputPin, Output, OutputPin, PinDriver},
     i2s::{
         config::{
             ClockSource, Config, DataBitWidth, MclkMultiple, SlotMode, StdClkConfig, StdConfig,
@@ -24,15 +27,27 @@ use esp_idf_svc::hal::{
 
 use log::info;
 
-use crate::bus::BusSubscription;
+use crate::board::{self, AudioOutput, SlotFormat};
+use crate::bus::{bt::CodecInfo, BusSubscription};
 use crate::error::Error;
 use crate::ringbuf::RingBuf;
 use crate::select_spawn::SelectSpawn;
+use crate::signal::StatefulReceiver;
+use crate::spdif::SpdifEncoder;
+use crate::storage::{Storage, Value};
 
 pub struct AudioBuffers<'a> {
     ringbuf_incoming: RingBuf<'a>,
     ringbuf_outgoing: RingBuf<'a>,
     a2dp: bool,
+    low_latency: bool,
+    output_latency: core::time::Duration,
+    speaker_volume: u8,
+    mic_volume: u8,
+    gains: Gains,
+    msbc: bool,
+    speed_boost: bool,
+    parking: bool,
 }
 
 impl<'a> AudioBuffers<'a> {
@@ -42,6 +57,14 @@ impl<'a> AudioBuffers<'a> {
             ringbuf_incoming: RingBuf::new(incoming),
             ringbuf_outgoing: RingBuf::new(outgoing),
             a2dp,
+            low_latency: false,
+            output_latency: core::time::Duration::from_secs(0),
+            speaker_volume: MAX_HFP_VOLUME,
+            mic_volume: MAX_HFP_VOLUME,
+            gains: Gains::new(),
+            msbc: false,
+            speed_boost: false,
+            parking: false,
         }
     }
 
@@ -53,12 +76,122 @@ impl<'a> AudioBuffers<'a> {
     #[inline(always)]
     fn set_a2dp(&mut self, a2dp: bool) {
         if self.a2dp != a2dp {
+            info!(
+                "Switching buffers, incoming: {:?}, outgoing: {:?}",
+                self.ringbuf_incoming.metrics(),
+                self.ringbuf_outgoing.metrics()
+            );
+
+            self.ringbuf_incoming.reset_metrics();
+            self.ringbuf_outgoing.reset_metrics();
+
             self.a2dp = a2dp;
             self.ringbuf_incoming.clear();
             self.ringbuf_outgoing.clear();
         }
     }
 
+    /// In low-latency mode, A2DP playback starts with a third of the buffer filled rather than two thirds,
+    /// trading robustness against dropouts for a better AV sync
+    #[inline(always)]
+    pub fn set_low_latency(&mut self, low_latency: bool) {
+        self.low_latency = low_latency;
+    }
+
+    /// The call volumes, as synchronized with the phone over HFP (0 - 15)
+    #[inline(always)]
+    pub fn set_speaker_volume(&mut self, volume: u8) {
+        self.speaker_volume = volume.min(MAX_HFP_VOLUME);
+    }
+
+    #[inline(always)]
+    pub fn set_mic_volume(&mut self, volume: u8) {
+        self.mic_volume = volume.min(MAX_HFP_VOLUME);
+    }
+
+    /// Wideband speech (mSBC) doubles the HFP sample rate to 16KHz
+    #[inline(always)]
+    pub fn set_msbc(&mut self, msbc: bool) {
+        if self.msbc != msbc {
+            self.msbc = msbc;
+
+            AUDIO_BUFFERS_MSBC_NOTIF.signal(());
+        }
+    }
+
+    #[inline(always)]
+    fn hfp_sample_rate(&self) -> u32 {
+        if self.msbc {
+            16000
+        } else {
+            8000
+        }
+    }
+
+    /// The ESP32 ADC samples no slower than 20KHz, so the microphone is sampled at a multiple
+    /// of the HFP sample rate, and decimated down to it
+    #[inline(always)]
+    fn mic_decimation(&self) -> usize {
+        if self.msbc {
+            2
+        } else {
+            3
+        }
+    }
+
+    #[inline(always)]
+    pub fn set_gains(&mut self, gains: Gains) {
+        self.gains = gains;
+    }
+
+    /// Set at motorway speeds, and only applied with `Gains::speed_volume`
+    #[inline(always)]
+    pub fn set_speed_boost(&mut self, speed_boost: bool) {
+        self.speed_boost = speed_boost;
+    }
+
+    /// Set while the parking sensors are active, and only applied with `Gains::parking_duck`
+    #[inline(always)]
+    pub fn set_parking(&mut self, parking: bool) {
+        self.parking = parking;
+    }
+
+    /// The scale of the incoming samples, as a fraction
+    #[inline(always)]
+    fn gain(&self, a2dp: bool) -> (i32, i32) {
+        let (num, den) = if a2dp {
+            (self.gains.music as i32, Gains::UNITY as i32)
+        } else {
+            (
+                self.speaker_volume as i32 * self.gains.call as i32,
+                MAX_HFP_VOLUME as i32 * Gains::UNITY as i32,
+            )
+        };
+
+        let (num, den) = if self.speed_boost && self.gains.speed_volume {
+            (num * SPEED_BOOST.0, den * SPEED_BOOST.1)
+        } else {
+            (num, den)
+        };
+
+        if a2dp && self.parking && self.gains.parking_duck {
+            (num * PARKING_DUCK.0, den * PARKING_DUCK.1)
+        } else {
+            (num, den)
+        }
+    }
+
+    /// The latency of the I2S DMA buffers, as currently configured
+    #[inline(always)]
+    pub fn output_latency(&self) -> core::time::Duration {
+        self.output_latency
+    }
+
+    #[inline(always)]
+    fn set_output_latency(&mut self, latency: core::time::Duration) {
+        self.output_latency = latency;
+    }
+
     #[inline(always)]
     fn outgoing(&mut self) -> &mut RingBuf<'a> {
         &mut self.ringbuf_outgoing
@@ -76,7 +209,9 @@ impl<'a> AudioBuffers<'a> {
                 AUDIO_BUFFERS_INCOMING_NOTIF.signal(());
             }
 
-            if self.is_outgoing_above_watermark(a2dp) {
+            // Each incoming SCO packet is answered with an outgoing one, so that the microphone
+            // data is delivered at the SCO cadence rather than in bursts
+            if !a2dp {
                 outgoing_notif();
             }
 
@@ -95,6 +230,12 @@ impl<'a> AudioBuffers<'a> {
         }
     }
 
+    /// Pops whatever is left in the incoming buffer, regardless of the watermark
+    #[inline(always)]
+    fn drain_incoming(&mut self, buf: &mut [u8]) -> usize {
+        self.ringbuf_incoming.pop(buf)
+    }
+
     #[inline(always)]
     fn push_outgoing(&mut self, data: &[u8], a2dp: bool) -> usize {
         if self.a2dp == a2dp {
@@ -104,10 +245,15 @@ impl<'a> AudioBuffers<'a> {
         }
     }
 
+    /// Always fills the whole SCO frame, with silence if the microphone fell behind
     #[inline(always)]
     pub fn pop_outgoing(&mut self, buf: &mut [u8], a2dp: bool) -> usize {
-        if self.is_outgoing_above_watermark(a2dp) {
-            self.ringbuf_outgoing.pop(buf)
+        if self.a2dp == a2dp && !a2dp {
+            let len = self.ringbuf_outgoing.pop(buf);
+
+            buf[len..].fill(0);
+
+            buf.len()
         } else {
             0
         }
@@ -117,19 +263,14 @@ impl<'a> AudioBuffers<'a> {
     fn is_incoming_above_watermark(&self, a2dp: bool) -> bool {
         self.a2dp == a2dp
             && self.ringbuf_incoming.len()
-                >= (if a2dp {
+                >= (if a2dp && self.low_latency {
+                    self.ringbuf_incoming.buf_len() / 3
+                } else if a2dp {
                     self.ringbuf_incoming.buf_len() / 3 * 2
                 } else {
                     self.ringbuf_incoming.buf_len() / 12 * 2
                 })
     }
-
-    #[inline(always)]
-    fn is_outgoing_above_watermark(&self, a2dp: bool) -> bool {
-        self.a2dp == a2dp
-            && !a2dp
-            && self.ringbuf_outgoing.len() >= self.ringbuf_outgoing.buf_len() / 3 * 2
-    }
 }
 
 pub type SharedAudioBuffers<'a> = Mutex<EspRawMutex, RefCell<AudioBuffers<'a>>>;
@@ -141,7 +282,153 @@ pub fn create_audio_buffers<'a>(
     Mutex::new(RefCell::new(AudioBuffers::new(true, incoming, outgoing)))
 }
 
+const GAINS_KEY: &str = "gains";
+/// The gains of each car the adapter was installed in, keyed by its PROXI
+const CAR_GAINS_KEY: &str = "car_gains";
+
+/// A PROXI followed by the packed gains
+const CAR_GAINS_ENTRY_LEN: usize = 10;
+
+/// About +3.5dB, against the road noise
+const SPEED_BOOST: (i32, i32) = (3, 2);
+
+/// About -12dB, so that the beeping of the parking sensors is heard over the music
+const PARKING_DUCK: (i32, i32) = (1, 4);
+
+/// Software gains of the music (A2DP) and the call (HFP) audio, in quarters
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Gains {
+    pub music: u8,
+    pub call: u8,
+    /// The wheel volume keys adjust the call gain during calls, on top of the radio volume
+    pub volume_keys: bool,
+    /// Raises the gains at motorway speeds
+    pub speed_volume: bool,
+    /// Lowers the music while the parking sensors are active
+    pub parking_duck: bool,
+}
+
+impl Gains {
+    pub const UNITY: u8 = 4;
+    pub const MIN: u8 = 2;
+    pub const MAX: u8 = 12;
+
+    pub const fn new() -> Self {
+        Self {
+            music: Self::UNITY,
+            call: Self::UNITY,
+            volume_keys: false,
+            speed_volume: false,
+            parking_duck: false,
+        }
+    }
+
+    /// The gains tuned for the car, or the last saved ones in a car that was not tuned yet
+    pub async fn load(storage: &Storage, proxi: Option<[u8; 6]>) -> Result<Self, Error> {
+        let car = match proxi {
+            Some(proxi) => storage
+                .get(CAR_GAINS_KEY)
+                .await?
+                .and_then(|cars| car_gains(&cars, &proxi)),
+            None => None,
+        };
+
+        let value = match car {
+            Some(value) => Some(value),
+            None => storage.get_u32(GAINS_KEY).await?,
+        };
+
+        Ok(value.map(Self::from_u32).unwrap_or(Self::new()))
+    }
+
+    pub async fn save(&self, storage: &Storage, proxi: Option<[u8; 6]>) -> Result<(), Error> {
+        let value = self.to_u32();
+
+        if let Some(proxi) = proxi {
+            let mut cars = storage.get(CAR_GAINS_KEY).await?.unwrap_or_default();
+
+            set_car_gains(&mut cars, &proxi, value);
+
+            storage.set(CAR_GAINS_KEY, &cars).await?;
+        }
+
+        storage.set_u32(GAINS_KEY, value).await
+    }
+
+    fn from_u32(value: u32) -> Self {
+        Self {
+            music: (value as u8).clamp(Self::MIN, Self::MAX),
+            call: ((value >> 8) as u8).clamp(Self::MIN, Self::MAX),
+            volume_keys: value & 0x10000 != 0,
+            speed_volume: value & 0x20000 != 0,
+            parking_duck: value & 0x40000 != 0,
+        }
+    }
+
+    fn to_u32(&self) -> u32 {
+        self.music as u32
+            | (self.call as u32) << 8
+            | (self.volume_keys as u32) << 16
+            | (self.speed_volume as u32) << 17
+            | (self.parking_duck as u32) << 18
+    }
+
+    /// Cycles through the gain steps
+    pub fn next(gain: u8) -> u8 {
+        if gain >= Self::MAX {
+            Self::MIN
+        } else {
+            gain + 1
+        }
+    }
+
+    /// As a percentage, for displaying
+    pub fn percent(gain: u8) -> u32 {
+        gain as u32 * 100 / Self::UNITY as u32
+    }
+}
+
+fn car_gains(cars: &[u8], proxi: &[u8; 6]) -> Option<u32> {
+    cars.chunks_exact(CAR_GAINS_ENTRY_LEN)
+        .find(|entry| entry[..6] == proxi[..])
+        .and_then(|entry| entry[6..].try_into().ok())
+        .map(u32::from_le_bytes)
+}
+
+/// The most recently tuned car goes last, so that the car not tuned for the longest time
+/// is the one to lose its gains (and to fall back to the last saved ones) once the value is full
+fn set_car_gains(cars: &mut Value, proxi: &[u8; 6], value: u32) {
+    cars.truncate(cars.len() - cars.len() % CAR_GAINS_ENTRY_LEN);
+
+    if let Some(index) = cars
+        .chunks_exact(CAR_GAINS_ENTRY_LEN)
+        .position(|entry| entry[..6] == proxi[..])
+    {
+        let start = index * CAR_GAINS_ENTRY_LEN;
+
+        cars.copy_within(start + CAR_GAINS_ENTRY_LEN.., start);
+        cars.truncate(cars.len() - CAR_GAINS_ENTRY_LEN);
+    } else if cars.len() + CAR_GAINS_ENTRY_LEN > cars.capacity() {
+        cars.copy_within(CAR_GAINS_ENTRY_LEN.., 0);
+        cars.truncate(cars.len() - CAR_GAINS_ENTRY_LEN);
+    }
+
+    // Cannot fail, as room was made above
+    let _ = cars.extend_from_slice(proxi);
+    let _ = cars.extend_from_slice(&value.to_le_bytes());
+}
+
+/// The HFP volume steps go from 0 to 15
+pub const MAX_HFP_VOLUME: u8 = 15;
+
+/// S/PDIF receivers cannot lock onto 8KHz or 16KHz, so the HFP audio is upsampled to 48KHz
+const SPDIF_HFP_SAMPLE_RATE: u32 = 48000;
+
 static AUDIO_BUFFERS_INCOMING_NOTIF: Signal<EspRawMutex, ()> = Signal::new();
+static AUDIO_BUFFERS_MSBC_NOTIF: Signal<EspRawMutex, ()> = Signal::new();
+
+/// Divisible by all the decimations of the microphone
+pub const MIC_FRAME_MEASUREMENTS: usize = 480;
 
 pub async fn process_audio_mux(
     bus: BusSubscription<'_>,
@@ -180,12 +467,24 @@ pub async fn process_microphone(
         {
             bus.service.starting();
 
+            AUDIO_BUFFERS_MSBC_NOTIF.reset();
+
+            let (hfp_rate, decimation) = audio_buffers.lock(|buffers| {
+                let buffers = buffers.borrow();
+
+                (buffers.hfp_sample_rate(), buffers.mic_decimation())
+            });
+
+            let mic_rate = hfp_rate * decimation as u32;
+
+            info!("Creating ADC input at {}Hz, HFP: {}Hz", mic_rate, hfp_rate);
+
             let mut driver = AdcContDriver::new(
                 &mut adc1,
                 &mut i2s0,
                 &AdcContConfig::new()
-                    .sample_freq(20000.Hz())
-                    .frame_measurements(500)
+                    .sample_freq(mic_rate.Hz())
+                    .frame_measurements(MIC_FRAME_MEASUREMENTS)
                     .frames_count(4),
                 Attenuated::db11(&mut pin),
             )?;
@@ -199,8 +498,10 @@ pub async fn process_microphone(
                     &mut driver,
                     buf,
                     audio_buffers,
+                    decimation,
                     &notify_outgoing,
                 )))
+                .chain(&mut pin!(wait_msbc_changed()))
                 .await?;
 
             driver.stop()?;
@@ -208,10 +509,20 @@ pub async fn process_microphone(
     }
 }
 
+/// Re-creates the ADC input for the sample rate of the new HFP codec, once the service loops
+async fn wait_msbc_changed() -> Result<(), Error> {
+    AUDIO_BUFFERS_MSBC_NOTIF.wait().await;
+
+    info!("HFP codec changed, re-creating the ADC input");
+
+    Ok(())
+}
+
 async fn process_microphone_reading<'d>(
     driver: &mut AdcContDriver<'d>,
     adc_buf: &mut [AdcMeasurement],
     audio_buffers: &SharedAudioBuffers<'_>,
+    decimation: usize,
     notify_outgoing: impl Fn(),
 ) -> Result<(), Error> {
     loop {
@@ -240,11 +551,19 @@ async fn process_microphone_reading<'d>(
                 audio_buffers.lock(|buffers| {
                     if !buffers.borrow().is_a2dp() {
                         let mut buffers = buffers.borrow_mut();
+                        let volume = buffers.mic_volume as u32;
                         let outgoing = buffers.outgoing();
 
-                        for src_offset in (0..len).step_by(2) {
-                            let sample =
-                                adc_buf[src_offset].data() + adc_buf[src_offset + 1].data();
+                        for measurements in adc_buf[..len].chunks_exact(decimation) {
+                            let sum = measurements
+                                .iter()
+                                .map(|measurement| measurement.data() as u32)
+                                .sum::<u32>();
+
+                            // At the scale of two measurements summed up, whatever the decimation
+                            let sample = (sum * 2 / decimation as u32 * volume
+                                / MAX_HFP_VOLUME as u32)
+                                as u16;
 
                             let ls = (sample & 0xff) as u8;
                             let ms = (sample >> 8) as u8;
@@ -263,15 +582,25 @@ async fn process_microphone_reading<'d>(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn process_speakers(
     bus: BusSubscription<'_>,
     mut i2s: impl Peripheral<P = impl I2s>,
     mut bclk: impl Peripheral<P = impl InputPin + OutputPin>,
     mut dout: impl Peripheral<P = impl OutputPin>,
     mut ws: impl Peripheral<P = impl InputPin + OutputPin>,
+    mut mclk: Option<AnyIOPin>,
+    mute: Option<AnyOutputPin>,
+    output: &AudioOutput,
     audio_buffers: &SharedAudioBuffers<'_>,
     buf: &mut [u8],
+    spdif_buf: &mut [u32],
 ) -> Result<(), Error> {
+    let mut spdif = matches!(output, AudioOutput::Spdif).then(|| SpdifEncoder::new(spdif_buf));
+    let mut mute = mute.map(PinDriver::output).transpose()?;
+
+    set_mute(&mut mute, true)?;
+
     loop {
         bus.service.wait_enabled().await?;
 
@@ -279,27 +608,85 @@ pub async fn process_speakers(
             bus.service.starting();
 
             let mut a2dp_conf = audio_buffers.lock(|buffers| buffers.borrow().is_a2dp());
+            let mut codec = bus.audio_codec.state(|codec| *codec);
 
             loop {
-                info!("Creating I2S output with A2DP: {}", a2dp_conf);
+                set_mute(&mut mute, true)?;
+
+                let hfp_rate = audio_buffers.lock(|buffers| buffers.borrow().hfp_sample_rate());
+
+                info!(
+                    "Creating I2S output for {:?} with A2DP: {}, codec: {:?}, HFP: {}Hz",
+                    output, a2dp_conf, codec, hfp_rate
+                );
+
+                let mut driver = i2s_create(
+                    &mut i2s,
+                    &mut bclk,
+                    &mut dout,
+                    &mut ws,
+                    mclk.as_mut(),
+                    output,
+                    a2dp_conf,
+                    &codec,
+                    hfp_rate,
+                )?;
 
-                let mut driver = i2s_create(&mut i2s, &mut bclk, &mut dout, &mut ws, a2dp_conf)?;
+                let latency =
+                    board::DMA.latency(i2s_sample_rate(output, a2dp_conf, &codec, hfp_rate));
+
+                info!("I2S DMA latency: {}ms", latency.as_millis());
+
+                audio_buffers.lock(|buffers| buffers.borrow_mut().set_output_latency(latency));
+
+                // Until the first frame is above the watermark, output silence rather than
+                // whatever was left in the DMA buffers
+                buf.fill(0);
+                driver.preload_data(buf)?;
 
                 driver.tx_enable()?;
 
                 bus.service.started();
 
-                let res = select(
+                let res = select3(
                     bus.service.wait_disabled(),
-                    process_speakers_writing(&mut driver, buf, audio_buffers, &mut a2dp_conf),
+                    process_speakers_writing(
+                        &mut driver,
+                        buf,
+                        spdif.as_mut(),
+                        &mut mute,
+                        audio_buffers,
+                        &mut a2dp_conf,
+                        hfp_rate,
+                    ),
+                    wait_codec_changed(&bus.audio_codec, &codec),
                 )
                 .await;
 
+                if let Either3::Third(new) = res {
+                    info!("A2DP codec reconfigured: {:?}", new);
+
+                    // Play out what was received with the old configuration before re-clocking
+                    process_speakers_draining(
+                        &mut driver,
+                        buf,
+                        spdif.as_mut(),
+                        audio_buffers,
+                        a2dp_conf,
+                        hfp_rate,
+                    )
+                    .await?;
+
+                    codec = new;
+                }
+
+                set_mute(&mut mute, true)?;
+
                 driver.tx_disable()?;
 
                 match res {
-                    Either::Second(Ok(())) => continue,
-                    Either::First(other) | Either::Second(other) => break other,
+                    Either3::Second(Ok(())) | Either3::Third(_) => continue,
+                    Either3::First(other) | Either3::Second(other) => break other,
                 }
             }?;
         }
@@ -309,20 +696,27 @@ pub async fn process_speakers(
 async fn process_speakers_writing<'d>(
     driver: &mut I2sDriver<'d, impl I2sTxSupported>,
     buf: &mut [u8],
+    mut spdif: Option<&mut SpdifEncoder<'_>>,
+    mute: &mut Option<PinDriver<'_, AnyOutputPin, Output>>,
     audio_buffers: &SharedAudioBuffers<'_>,
     a2dp_conf: &mut bool,
+    hfp_rate: u32,
 ) -> Result<(), Error> {
+    let mut fade_in = FadeIn::new();
+    let mut muted = true;
+
     loop {
-        let (len, a2dp) = audio_buffers.lock(|buffers| {
+        let (len, a2dp, gain) = audio_buffers.lock(|buffers| {
             let mut buffers = buffers.borrow_mut();
             let a2dp = buffers.a2dp;
+            let gain = buffers.gain(a2dp);
 
             if *a2dp_conf == a2dp {
                 let len = buffers.pop_incoming(buf, a2dp);
 
-                (len, a2dp)
+                (len, a2dp, gain)
             } else {
-                (0, a2dp)
+                (0, a2dp, gain)
             }
         });
 
@@ -330,7 +724,16 @@ async fn process_speakers_writing<'d>(
             *a2dp_conf = a2dp;
             break;
         } else if len > 0 {
-            driver.write_all_async(&buf[..len]).await?;
+            apply_gain(&mut buf[..len], gain);
+
+            fade_in.apply(&mut buf[..len]);
+
+            speakers_write(driver, &buf[..len], spdif.as_deref_mut(), a2dp, hfp_rate).await?;
+
+            if muted && fade_in.is_done() {
+                set_mute(mute, false)?;
+                muted = false;
+            }
         } else {
             AUDIO_BUFFERS_INCOMING_NOTIF.wait().await;
         }
@@ -339,32 +742,251 @@ async fn process_speakers_writing<'d>(
     Ok(())
 }
 
+async fn process_speakers_draining<'d>(
+    driver: &mut I2sDriver<'d, impl I2sTxSupported>,
+    buf: &mut [u8],
+    mut spdif: Option<&mut SpdifEncoder<'_>>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    a2dp: bool,
+    hfp_rate: u32,
+) -> Result<(), Error> {
+    loop {
+        let len = audio_buffers.lock(|buffers| buffers.borrow_mut().drain_incoming(buf));
+
+        if len == 0 {
+            break;
+        }
+
+        speakers_write(driver, &buf[..len], spdif.as_deref_mut(), a2dp, hfp_rate).await?;
+    }
+
+    Ok(())
+}
+
+async fn speakers_write<'d>(
+    driver: &mut I2sDriver<'d, impl I2sTxSupported>,
+    data: &[u8],
+    spdif: Option<&mut SpdifEncoder<'_>>,
+    a2dp: bool,
+    hfp_rate: u32,
+) -> Result<(), Error> {
+    if let Some(spdif) = spdif {
+        let repeat = if a2dp {
+            1
+        } else {
+            (SPDIF_HFP_SAMPLE_RATE / hfp_rate) as usize
+        };
+
+        for chunk in data.chunks(spdif.chunk_len(repeat)) {
+            driver.write_all_async(spdif.encode(chunk, repeat)).await?;
+        }
+    } else {
+        driver.write_all_async(data).await?;
+    }
+
+    Ok(())
+}
+
+async fn wait_codec_changed(
+    audio_codec: &StatefulReceiver<'_, impl RawMutex, CodecInfo>,
+    codec: &CodecInfo,
+) -> CodecInfo {
+    loop {
+        audio_codec.recv().await;
+
+        let new = audio_codec.state(|codec| *codec);
+
+        if new != *codec {
+            break new;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn i2s_create<'a>(
     i2s: impl Peripheral<P = impl I2s> + 'a,
     bclk: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
     dout: impl Peripheral<P = impl OutputPin> + 'a,
     ws: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+    mclk: Option<&'a mut AnyIOPin>,
+    output: &AudioOutput,
     a2dp: bool,
+    codec: &CodecInfo,
+    hfp_rate: u32,
 ) -> Result<I2sDriver<'a, I2sTx>, Error> {
-    Ok(I2sDriver::new_std_tx(
-        i2s,
-        &StdConfig::new(
-            Config::new().auto_clear(true),
-            StdClkConfig::new(
-                if a2dp { 44100 } else { 8000 },
-                ClockSource::Pll160M,
-                MclkMultiple::M256,
-            ),
-            StdSlotConfig::msb_slot_default(DataBitWidth::Bits16, SlotMode::Stereo),
+    let sample_rate = i2s_sample_rate(output, a2dp, codec, hfp_rate);
+
+    let channel_config = Config::new()
+        .auto_clear(true)
+        .dma_buffer_count(board::DMA.buffers)
+        .frames_per_buffer(board::DMA.frames_per_buffer);
+
+    let config = match output {
+        AudioOutput::Dac(dac) => {
+            let slot_mode = if a2dp && codec.channels == 1 {
+                SlotMode::Mono
+            } else {
+                SlotMode::Stereo
+            };
+
+            let slot_config = match dac.slot_format {
+                SlotFormat::Msb => StdSlotConfig::msb_slot_default(DataBitWidth::Bits16, slot_mode),
+                SlotFormat::Philips => {
+                    StdSlotConfig::philips_slot_default(DataBitWidth::Bits16, slot_mode)
+                }
+            }
+            .slot_bit_width(dac.slot_bit_width);
+
+            StdConfig::new(
+                channel_config,
+                StdClkConfig::new(sample_rate, ClockSource::Pll160M, dac.mclk_multiple),
+                slot_config,
+                Default::default(),
+            )
+        }
+        AudioOutput::Spdif => StdConfig::new(
+            channel_config,
+            StdClkConfig::new(sample_rate, ClockSource::Pll160M, MclkMultiple::M256),
+            StdSlotConfig::msb_slot_default(DataBitWidth::Bits32, SlotMode::Stereo),
             Default::default(),
         ),
-        bclk,
-        dout,
-        AnyIOPin::none(),
-        ws,
-    )?)
+    };
+
+    let mclk = match output {
+        AudioOutput::Dac(dac) if dac.mclk => mclk,
+        _ => None,
+    };
+
+    Ok(I2sDriver::new_std_tx(i2s, &config, bclk, dout, mclk, ws)?)
+}
+
+fn set_mute(
+    mute: &mut Option<PinDriver<'_, AnyOutputPin, Output>>,
+    muted: bool,
+) -> Result<(), Error> {
+    if let Some(mute) = mute {
+        if muted {
+            mute.set_high()?;
+        } else {
+            mute.set_low()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Ramps up the output once the first frames arrive, avoiding a pop after the silence
+struct FadeIn {
+    pos: u32,
+}
+
+impl FadeIn {
+    const SAMPLES: u32 = 4096;
+
+    const fn new() -> Self {
+        Self { pos: 0 }
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= Self::SAMPLES
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for sample in data.chunks_exact_mut(2) {
+            if self.pos >= Self::SAMPLES {
+                break;
+            }
+
+            let value = i16::from_le_bytes([sample[0], sample[1]]) as i32;
+            let value = (value * self.pos as i32 / Self::SAMPLES as i32) as i16;
+
+            sample.copy_from_slice(&value.to_le_bytes());
+
+            self.pos += 1;
+        }
+    }
+}
+
+/// Scales the 16-bit LE samples by `num / den`, clipping what a boost pushes out of range
+fn apply_gain(data: &mut [u8], (num, den): (i32, i32)) {
+    if num == den {
+        return;
+    }
+
+    for sample in data.chunks_exact_mut(2) {
+        let value = i16::from_le_bytes([sample[0], sample[1]]) as i32;
+        let value = (value * num / den).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        sample.copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// The I2S frame rate, which is what the DMA buffers are clocked out at
+fn i2s_sample_rate(output: &AudioOutput, a2dp: bool, codec: &CodecInfo, hfp_rate: u32) -> u32 {
+    match output {
+        AudioOutput::Dac(_) if a2dp => codec.sample_rate,
+        AudioOutput::Dac(_) => hfp_rate,
+        // Two 32-bit I2S words per S/PDIF subframe
+        AudioOutput::Spdif if a2dp => codec.sample_rate * 2,
+        AudioOutput::Spdif => SPDIF_HFP_SAMPLE_RATE * 2,
+    }
 }
 
 fn as_u8_slice(slice: &[u16]) -> &[u8] {
     unsafe { core::slice::from_raw_parts(slice.as_ptr() as *const _, slice.len() * 2) }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxi(n: u8) -> [u8; 6] {
+        [n, 0, 0, 0, 0, n]
+    }
+
+    #[test]
+    fn per_car() {
+        let mut cars = Value::new();
+
+        set_car_gains(&mut cars, &proxi(1), 1);
+        set_car_gains(&mut cars, &proxi(2), 2);
+        set_car_gains(&mut cars, &proxi(1), 3);
+
+        assert_eq!(cars.len(), 2 * CAR_GAINS_ENTRY_LEN);
+        assert_eq!(car_gains(&cars, &proxi(1)), Some(3));
+        assert_eq!(car_gains(&cars, &proxi(2)), Some(2));
+        assert_eq!(car_gains(&cars, &proxi(3)), None);
+    }
+
+    #[test]
+    fn least_recently_tuned_evicted() {
+        let mut cars = Value::new();
+        let max = cars.capacity() / CAR_GAINS_ENTRY_LEN;
+
+        for n in 0..max as u8 {
+            set_car_gains(&mut cars, &proxi(n), n as u32);
+        }
+
+        // Tuned again, so it is not the one to be evicted
+        set_car_gains(&mut cars, &proxi(0), 100);
+        set_car_gains(&mut cars, &proxi(100), 200);
+
+        assert_eq!(cars.len(), max * CAR_GAINS_ENTRY_LEN);
+        assert_eq!(car_gains(&cars, &proxi(0)), Some(100));
+        assert_eq!(car_gains(&cars, &proxi(1)), None);
+        assert_eq!(car_gains(&cars, &proxi(100)), Some(200));
+    }
+
+    #[test]
+    fn gains_round_trip() {
+        let gains = Gains {
+            music: Gains::MAX,
+            call: Gains::MIN,
+            volume_keys: true,
+            speed_volume: false,
+            parking_duck: true,
+        };
+
+        assert_eq!(Gains::from_u32(gains.to_u32()), gains);
+    }
+}
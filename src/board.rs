@@ -0,0 +1,90 @@
+use esp_idf_svc::hal::i2s::config::{MclkMultiple, SlotBitWidth};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SlotFormat {
+    Msb,
+    Philips,
+}
+
+/// How the I2S output has to be configured for the DAC / amplifier on the board
+#[derive(Copy, Clone, Debug)]
+pub struct DacProfile {
+    pub name: &'static str,
+    pub slot_format: SlotFormat,
+    pub slot_bit_width: SlotBitWidth,
+    pub mclk: bool,
+    pub mclk_multiple: MclkMultiple,
+}
+
+/// Amplifiers with a built-in DAC, like the MAX98357A
+pub const AMP: DacProfile = DacProfile {
+    name: "AMP",
+    slot_format: SlotFormat::Msb,
+    slot_bit_width: SlotBitWidth::Auto,
+    mclk: false,
+    mclk_multiple: MclkMultiple::M256,
+};
+
+pub const PCM5102: DacProfile = DacProfile {
+    name: "PCM5102",
+    slot_format: SlotFormat::Philips,
+    slot_bit_width: SlotBitWidth::Bits32,
+    mclk: true,
+    mclk_multiple: MclkMultiple::M256,
+};
+
+pub const UDA1334: DacProfile = DacProfile {
+    name: "UDA1334",
+    slot_format: SlotFormat::Philips,
+    slot_bit_width: SlotBitWidth::Auto,
+    mclk: true,
+    mclk_multiple: MclkMultiple::M256,
+};
+
+pub const DAC: DacProfile = if cfg!(feature = "dac-pcm5102") {
+    PCM5102
+} else if cfg!(feature = "dac-uda1334") {
+    UDA1334
+} else {
+    AMP
+};
+
+/// DMA buffering of the I2S output
+#[derive(Copy, Clone, Debug)]
+pub struct DmaConfig {
+    pub buffers: u32,
+    pub frames_per_buffer: u32,
+}
+
+impl DmaConfig {
+    /// How long a frame takes from being written to the driver to being clocked out,
+    /// with all DMA buffers full
+    pub fn latency(&self, sample_rate: u32) -> core::time::Duration {
+        core::time::Duration::from_micros(
+            self.buffers as u64 * self.frames_per_buffer as u64 * 1_000_000 / sample_rate as u64,
+        )
+    }
+}
+
+/// The ESP-IDF defaults
+pub const DMA: DmaConfig = DmaConfig {
+    buffers: 6,
+    frames_per_buffer: 240,
+};
+
+#[derive(Copy, Clone, Debug)]
+pub enum AudioOutput {
+    Dac(DacProfile),
+    /// S/PDIF sent over the I2S data line, for amps and DSPs with a digital input
+    Spdif,
+}
+
+/// The optional hardware mute line of the amplifier, asserted (high) while the I2S output is
+/// reconfigured, and released once the audio is ramped up
+pub const HW_MUTE: bool = cfg!(feature = "hw-mute");
+
+pub const OUTPUT: AudioOutput = if cfg!(feature = "spdif") {
+    AudioOutput::Spdif
+} else {
+    AudioOutput::Dac(DAC)
+};
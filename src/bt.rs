@@ -1,11 +1,19 @@
+use core::cell::Cell;
+use core::fmt::{Display, Write};
 use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_futures::select::{select, Either};
+
+use embassy_sync::blocking_mutex::raw::{NoopRawMutex, RawMutex};
 
 use embassy_sync::mutex::Mutex;
-use esp_idf_svc::bt::a2dp::{AudioStatus, ConnectionStatus};
+use embassy_sync::signal::Signal;
+use esp_idf_svc::bt::a2dp::{AudioStatus, Codec, ConnectionStatus};
 use esp_idf_svc::bt::avrc::{KeyCode, Notification, PlaybackStatus};
-use esp_idf_svc::bt::hfp::client::{self, CallSetupStatus};
+use esp_idf_svc::bt::hfp::client::{
+    self, CallHeldStatus, CallSetupStatus, CurrentCallStatus, VolumeControlTarget,
+};
 use esp_idf_svc::{
     bt::{
         a2dp::{A2dpEvent, EspA2dp, SinkEnabled},
@@ -16,25 +24,78 @@ use esp_idf_svc::{
             IOCapabilities,
         },
         hfp::client::{EspHfpc, HfpcEvent},
-        BtClassic, BtClassicEnabled, BtDriver,
+        BtClassic, BtClassicEnabled, BtDriver, BtStatus,
     },
     nvs::EspDefaultNvsPartition,
+    sys::{
+        esp, esp_avrc_ct_send_set_player_value_cmd, esp_avrc_ps_attr_ids_t_ESP_AVRC_PS_REPEAT_MODE,
+        esp_avrc_ps_attr_ids_t_ESP_AVRC_PS_SHUFFLE_MODE,
+        esp_avrc_ps_rpt_value_ids_t_ESP_AVRC_PS_REPEAT_GROUP,
+        esp_avrc_ps_rpt_value_ids_t_ESP_AVRC_PS_REPEAT_OFF,
+        esp_avrc_ps_shf_value_ids_t_ESP_AVRC_PS_SHUFFLE_ALL,
+        esp_avrc_ps_shf_value_ids_t_ESP_AVRC_PS_SHUFFLE_OFF, esp_bluedroid_deinit,
+        esp_bluedroid_disable, esp_bt_dev_set_device_name, esp_bt_gap_get_bond_device_list,
+        esp_bt_gap_read_rssi_delta, esp_bt_gap_remove_bond_device, esp_bt_gap_set_pin,
+        esp_bt_pin_type_t_ESP_BT_PIN_TYPE_FIXED, esp_hf_chld_type_t,
+        esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_HOLD_ACC, esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_MERGE,
+        esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_REL, esp_hf_client_send_chld_cmd,
+        esp_hf_client_volume_update,
+        esp_hf_volume_control_target_t_ESP_HF_VOLUME_CONTROL_TARGET_SPK,
+        esp_vhci_host_check_send_available, esp_vhci_host_send_packet,
+    },
 };
 
 use esp_idf_svc::hal::{modem::BluetoothModemPeripheral, peripheral::Peripheral};
 
+use embassy_time::{Duration, Instant, Timer};
+
 use log::*;
 
+use crate::absolute_volume::AbsoluteVolume;
+use crate::arena::Arena;
 use crate::audio::SharedAudioBuffers;
 use crate::bus::{
     bt::{
-        AudioState, AudioTrackState, BtCommand, BtState, PhoneCallInfo, PhoneCallState, TrackInfo,
+        Arbitration, AudioState, AudioTrackState, Bonded, BtCommand, BtState, CodecInfo,
+        HfpIndicators, Identity, LinkQuality, PhoneCallInfo, PhoneCallState, Takeover, TrackInfo,
+        VolumeControl, MAX_BONDED,
     },
-    BusSubscription,
+    can::{self, RawFrame},
+    diag::{BootReason, Diagnostic, Thermal},
+    set_text, BusSubscription, UpdateRequest,
 };
+use crate::console;
+use crate::devices::{self, create_devices, BtAddr, Devices, SharedDevices};
 use crate::error::Error;
+use crate::metadata::{create_metadata_retry, SharedMetadataRetry};
 use crate::select_spawn::SelectSpawn;
-use crate::signal::{Receiver, Sender, StatefulSender};
+use crate::service::ServiceLifecycle;
+use crate::signal::{QueueSender, Receiver, Sender, StatefulReceiver, StatefulSender};
+use crate::storage::Storage;
+use crate::trace::{self, Profile};
+
+pub const DEVICE_NAME: &str = "Fiat";
+
+const DELAY: core::time::Duration = core::time::Duration::from_millis(150);
+const LOW_LATENCY_DELAY: core::time::Duration = core::time::Duration::from_millis(50);
+
+/// How often the play status is polled from phones which do not support playback notifications
+const PLAY_STATUS_POLL: Duration = Duration::from_secs(2);
+const PLAY_STATUS_POLL_THROTTLED: Duration = Duration::from_secs(10);
+
+/// How often the unanswered metadata requests are checked for a retry
+const METADATA_POLL: Duration = Duration::from_millis(500);
+
+const PAIRING_WINDOW: Duration = Duration::from_secs(120);
+
+const LINK_POLL: Duration = Duration::from_secs(5);
+
+/// How long the refusal of a device is shown
+const REFUSED_DURATION: core::time::Duration = core::time::Duration::from_secs(5);
+
+/// Backoff of the attempts to reconnect to the last connected device
+const RECONNECT_MIN: Duration = Duration::from_secs(2);
+const RECONNECT_MAX: Duration = Duration::from_secs(64);
 
 #[allow(clippy::too_many_arguments)]
 pub async fn process(
@@ -43,22 +104,71 @@ pub async fn process(
     bus: BusSubscription<'_>,
     bt: Sender<'_, impl RawMutex + Sync, BtState>,
     audio: Sender<'_, impl RawMutex + Sync, AudioState>,
+    audio_codec: StatefulSender<'_, impl RawMutex + Sync, CodecInfo>,
     audio_track: StatefulSender<'_, impl RawMutex + Sync, TrackInfo>,
     phone: Sender<'_, impl RawMutex + Sync, AudioState>,
     phone_call: StatefulSender<'_, impl RawMutex + Sync, PhoneCallInfo>,
+    hfp_indicators: StatefulSender<'_, impl RawMutex + Sync, HfpIndicators>,
+    takeover: StatefulSender<'_, impl RawMutex + Sync, Takeover>,
+    bonded: StatefulSender<'_, impl RawMutex + Sync, Bonded>,
+    identity: StatefulSender<'_, impl RawMutex + Sync, Identity>,
+    link: StatefulSender<'_, impl RawMutex + Sync, LinkQuality>,
+    update: Sender<'_, impl RawMutex, UpdateRequest>,
+    notification: StatefulSender<'_, impl RawMutex, can::Notification>,
+    can_frames: Sender<'_, impl RawMutex, RawFrame>,
+    diagnostics: QueueSender<'_, impl RawMutex, Diagnostic>,
     audio_buffers: &SharedAudioBuffers<'_>,
+    arena: &Arena<'_>,
+    storage: &Storage,
+    boot: BootReason,
 ) -> Result<(), Error> {
+    let devices = create_devices(Devices::load(storage).await?);
+
+    let (policy, id) = devices.lock(|devices| {
+        let devices = devices.borrow();
+
+        (devices.arbitration(), devices.identity())
+    });
+
+    takeover.modify(|takeover| {
+        takeover.policy = policy;
+        true
+    });
+
+    identity.modify(|identity| {
+        *identity = id;
+        true
+    });
+
+    let play_status_poll = AtomicBool::new(false);
+    let metadata = create_metadata_retry();
+
+    if boot.is_unclean() {
+        // Give the supply rail some time to stabilize before the radio starts drawing current
+        info!("Unclean boot ({:?}), delaying Bluetooth init", boot);
+        Timer::after(Duration::from_secs(1)).await;
+    }
+
     loop {
         bus.service.wait_enabled().await?;
 
         bus.service.starting();
 
+        let testing = Cell::new(false);
+
         {
             let mut modem = modem.lock().await;
 
-            let driver = BtDriver::<BtClassic>::new(&mut modem, Some(nvs.clone()))?;
+            let driver = match BtDriver::<BtClassic>::new(&mut modem, Some(nvs.clone())) {
+                Ok(driver) => driver,
+                Err(err) => {
+                    diagnostics.send(Diagnostic::BtControllerFailed { code: err.code() });
+
+                    return Err(err.into());
+                }
+            };
 
-            driver.set_device_name("Fiat")?;
+            driver.set_device_name(&device_name(&id))?;
 
             info!("Bluetooth initialized");
 
@@ -70,16 +180,24 @@ pub async fn process(
 
             info!("AVRCC created");
 
+            let absolute_volume = AbsoluteVolume::new()?;
+
+            info!("AVRCP target created");
+
             let a2dp = EspA2dp::new_sink(&driver)?;
 
             info!("A2DP created");
 
+            // NOTE: There are no SMS notifications, as the Bluedroid stack of ESP-IDF implements
+            // no MAP (Message Access Profile) client, and HFP does not carry the messages
             let hfpc = EspHfpc::new(&driver, None)?;
 
             info!("HFPC created");
 
             unsafe {
-                gap.initialize_nonstatic(|event| handle_gap(&gap, &bt, event))?;
+                gap.initialize_nonstatic(|event| {
+                    handle_gap(&gap, &bt, &devices, &bonded, &link, event)
+                })?;
             }
 
             gap.set_cod(
@@ -92,26 +210,43 @@ pub async fn process(
             )?;
 
             gap.set_ssp_io_cap(IOCapabilities::None)?;
-            gap.set_pin("1234")?;
-            gap.set_scan_mode(true, DiscoveryMode::Discoverable)?;
+            gap.set_pin(id.pin())?;
 
             info!("GAP initialized");
 
+            publish_bonded(&bonded);
+            publish_state(&bt, &devices);
+
+            seed_allowed(&devices, storage).await?;
+
             audio_track.modify(|track| {
                 track.state = AudioTrackState::Initialized;
+                track.device = None;
                 track.version += 1;
                 true
             });
 
             unsafe {
-                avrcc.initialize_nonstatic(|event| handle_avrcc(&avrcc, &audio_track, event))?;
+                avrcc.initialize_nonstatic(|event| {
+                    handle_avrcc(&avrcc, &audio_track, &play_status_poll, &metadata, event)
+                })?;
             }
 
             info!("AVRCC initialized");
 
             unsafe {
                 a2dp.initialize_nonstatic(|event| {
-                    handle_a2dp(&a2dp, &audio, audio_buffers, event)
+                    handle_a2dp(
+                        &a2dp,
+                        &bt,
+                        &audio,
+                        &audio_codec,
+                        audio_buffers,
+                        &devices,
+                        &takeover,
+                        &audio_track,
+                        event,
+                    )
                 })?;
             }
 
@@ -119,13 +254,28 @@ pub async fn process(
 
             unsafe {
                 hfpc.initialize_nonstatic(|event| {
-                    handle_hfpc(&hfpc, &phone, &phone_call, audio_buffers, event)
+                    handle_hfpc(
+                        &hfpc,
+                        &phone,
+                        &phone_call,
+                        &hfp_indicators,
+                        audio_buffers,
+                        &devices,
+                        event,
+                    )
                 })?;
             }
 
             info!("HFPC initialized");
 
-            a2dp.set_delay(core::time::Duration::from_millis(150))?;
+            let _spp = console::Spp::new()?;
+
+            info!("Console initialized");
+
+            a2dp.set_delay(delay(audio_buffers, false))?;
+
+            let pairing = &Signal::<NoopRawMutex, _>::new();
+            let test_request = &Signal::<NoopRawMutex, _>::new();
 
             let _started = bus.service.started();
 
@@ -134,271 +284,1474 @@ pub async fn process(
                     &bus.radio_commands,
                     &a2dp,
                     &avrcc,
-                    &hfpc
+                    &absolute_volume,
+                    &hfpc,
+                    &devices,
+                    &bt,
+                    &takeover,
+                    &bonded,
+                    &identity,
+                    &audio_track,
+                    &notification,
+                    pairing,
+                    test_request,
+                    audio_buffers,
+                    storage,
                 )))
                 .chain(&mut pin!(process_commands(
                     &bus.button_commands,
                     &a2dp,
                     &avrcc,
-                    &hfpc
+                    &absolute_volume,
+                    &hfpc,
+                    &devices,
+                    &bt,
+                    &takeover,
+                    &bonded,
+                    &identity,
+                    &audio_track,
+                    &notification,
+                    pairing,
+                    test_request,
+                    audio_buffers,
+                    storage,
+                )))
+                .chain(&mut pin!(process_metadata(&avrcc, &metadata)))
+                .chain(&mut pin!(process_play_status(
+                    &avrcc,
+                    &play_status_poll,
+                    &bus.thermal,
+                    &bus.service,
+                )))
+                .chain(&mut pin!(process_pairing(&gap, &devices, pairing)))
+                .chain(&mut pin!(wait_test_mode(
+                    test_request,
+                    &testing,
+                    &bus.service
+                )))
+                .chain(&mut pin!(process_link(&devices, &link, &bus.service)))
+                .chain(&mut pin!(process_shedding(&bus.phone_call, &bus.service)))
+                .chain(&mut pin!(process_reconnect(
+                    &a2dp,
+                    &hfpc,
+                    &devices,
+                    &notification,
+                    storage
+                )))
+                .chain(&mut pin!(console::process(
+                    &bus,
+                    &update,
+                    &notification,
+                    &can_frames,
+                    &diagnostics,
+                    arena,
+                    &devices,
+                    storage
                 )))
                 .await?;
         }
+
+        // The controller is only free for the RF test mode once the stack is torn down
+        if testing.get() {
+            return test_mode(&mut *modem.lock().await).await;
+        }
+
+        // The AVRCP disconnection is not reported when the stack is torn down while connected
+        play_status_poll.store(false, Ordering::SeqCst);
+        metadata.lock(|metadata| metadata.borrow_mut().reset());
+
+        bus.service.sys_set_shedding(false);
+
+        bt.send(BtState::Uninitialized);
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_commands<'d, M>(
     commands: &Receiver<'_, impl RawMutex, BtCommand>,
-    _a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>,
+    a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>,
     avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>,
+    absolute_volume: &AbsoluteVolume,
     hfpc: &EspHfpc<'d, M, &BtDriver<'d, M>>,
+    devices: &SharedDevices,
+    bt: &Sender<'_, impl RawMutex, BtState>,
+    takeover: &StatefulSender<'_, impl RawMutex, Takeover>,
+    bonded: &StatefulSender<'_, impl RawMutex, Bonded>,
+    identity: &StatefulSender<'_, impl RawMutex, Identity>,
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    notification: &StatefulSender<'_, impl RawMutex, can::Notification>,
+    pairing: &Signal<impl RawMutex, ()>,
+    test_request: &Signal<impl RawMutex, ()>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    storage: &Storage,
 ) -> Result<(), Error>
 where
     M: BtClassicEnabled,
 {
     loop {
         match commands.recv().await {
-            BtCommand::Answer => hfpc.answer()?,
-            BtCommand::Reject => hfpc.reject()?,
-            BtCommand::Hangup => hfpc.reject()?,
-            BtCommand::Pause => avrcc.send_passthrough(0, KeyCode::Pause, true)?,
-            BtCommand::Resume => avrcc.send_passthrough(0, KeyCode::Play, true)?,
-            BtCommand::NextTrack => avrcc.send_passthrough(0, KeyCode::ChannelUp, true)?,
-            BtCommand::PreviousTrack => avrcc.send_passthrough(0, KeyCode::ChannelDown, true)?,
-        }
-    }
-}
+            BtCommand::Answer => hfp_failed(hfpc.answer()),
+            BtCommand::Reject | BtCommand::Hangup => hfp_failed(hfpc.reject()),
+            BtCommand::VoiceAssistant => hfp_failed(hfpc.start_voice_recognition()),
+            BtCommand::Dtmf(key) => hfp_failed(hfpc.send_dtmf(key)),
+            BtCommand::Hold | BtCommand::Swap => {
+                hfp_failed(call_hold(esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_HOLD_ACC))
+            }
+            BtCommand::Merge => hfp_failed(call_hold(esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_MERGE)),
+            BtCommand::RejectWaiting => {
+                hfp_failed(call_hold(esp_hf_chld_type_t_ESP_HF_CHLD_TYPE_REL))
+            }
+            BtCommand::CallVolume(volume) => {
+                audio_buffers.lock(|buffers| buffers.borrow_mut().set_speaker_volume(volume));
 
-fn handle_gap<'d, M>(
-    gap: &EspGap<'d, M, &BtDriver<'d, M>>,
-    _bt: &Sender<'_, impl RawMutex, BtState>,
-    event: GapEvent<'_>,
-) where
-    M: BtClassicEnabled,
-{
-    match event {
-        GapEvent::DeviceDiscovered { bd_addr, props } => {
-            info!("Found device: {:?}", bd_addr);
+                hfp_failed(esp!(unsafe {
+                    esp_hf_client_volume_update(
+                        esp_hf_volume_control_target_t_ESP_HF_VOLUME_CONTROL_TARGET_SPK,
+                        volume as _,
+                    )
+                }));
+            }
+            BtCommand::VolumeUp => {
+                avrcp_failed(change_volume(avrcc, absolute_volume, devices, true))
+            }
+            BtCommand::VolumeDown => {
+                avrcp_failed(change_volume(avrcc, absolute_volume, devices, false))
+            }
+            BtCommand::CycleVolumeControl => {
+                let cycled = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
 
-            for prop in props {
-                info!("Prop: {:?}", prop.prop());
+                    devices.connected().map(|addr| {
+                        let control = devices.cycle_volume_control(&addr);
+
+                        (
+                            control,
+                            devices.passthrough_volume().clone(),
+                            devices.absolute_volume().clone(),
+                        )
+                    })
+                });
+
+                if let Some((control, passthrough, absolute)) = cycled {
+                    info!("Volume control: {:?}", control);
+
+                    publish_volume_control(audio_track, devices);
+                    devices::save_volume_control(storage, &passthrough, &absolute).await?;
+                }
+            }
+            BtCommand::Pause => avrcp_failed(avrcc.send_passthrough(0, KeyCode::Pause, true)),
+            BtCommand::Resume => avrcp_failed(avrcc.send_passthrough(0, KeyCode::Play, true)),
+            BtCommand::Stop => avrcp_failed(avrcc.send_passthrough(0, KeyCode::Stop, true)),
+            BtCommand::NextTrack => {
+                avrcp_failed(avrcc.send_passthrough(0, KeyCode::ChannelUp, true))
             }
+            BtCommand::PreviousTrack => {
+                avrcp_failed(avrcc.send_passthrough(0, KeyCode::ChannelDown, true))
+            }
+            BtCommand::ToggleShuffle => {
+                let mut shuffle = false;
 
-            //let _ = gap.stop_discovery();
-        }
-        GapEvent::PairingUserConfirmationRequest { bd_addr, .. } => {
-            gap.reply_ssp_confirm(&bd_addr, true).unwrap();
-        }
-        _ => (),
-    }
-}
+                audio_track.modify(|track| {
+                    track.settings.shuffle = !track.settings.shuffle;
+                    track.version += 1;
+                    shuffle = track.settings.shuffle;
+                    true
+                });
 
-fn handle_a2dp<'d, M>(
-    _a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>,
-    audio: &Sender<'_, impl RawMutex, AudioState>,
-    audio_buffers: &SharedAudioBuffers<'_>,
-    event: A2dpEvent<'_>,
-) where
-    M: BtClassicEnabled,
-{
-    match event {
-        A2dpEvent::Initialized => audio.send(AudioState::Initialized),
-        A2dpEvent::Deinitialized => audio.send(AudioState::Uninitialized),
-        A2dpEvent::ConnectionState { status, .. } => match status {
-            ConnectionStatus::Connected => audio.send(AudioState::Connected),
-            ConnectionStatus::Disconnected => audio.send(AudioState::Initialized),
-            _ => (),
-        },
-        A2dpEvent::AudioState { status, .. } => match status {
-            AudioStatus::Started => audio.send(AudioState::Streaming),
-            AudioStatus::SuspendedByRemote => audio.send(AudioState::Suspended),
-            AudioStatus::Stopped => audio.send(AudioState::Connected),
-        },
-        A2dpEvent::SinkData(data) => {
-            audio_buffers.lock(|buffers| {
-                buffers.borrow_mut().push_incoming(data, true, || {});
-            });
-        }
-        _ => (),
-    }
-}
+                info!("Shuffle: {}", shuffle);
 
-fn handle_avrcc<'d, M>(
-    avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>,
-    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
-    event: AvrccEvent<'_>,
-) where
-    M: BtClassicEnabled,
-{
-    match &event {
-        AvrccEvent::Connected(_) => {
-            audio_track.modify(|track| {
-                track.state = AudioTrackState::Connected;
-                track.version += 1;
-                true
-            });
-            avrcc.request_capabilities(0).unwrap();
-        }
-        AvrccEvent::Disconnected(_) => audio_track.modify(|track| {
-            track.state = AudioTrackState::Initialized;
-            track.version += 1;
-            true
-        }),
-        AvrccEvent::NotificationCapabilities { .. } => {
-            request_info(avrcc);
-        }
-        AvrccEvent::Notification(notification) => {
-            request_info(avrcc); // TODO: Necessary?
+                avrcp_failed(set_player_value(
+                    esp_avrc_ps_attr_ids_t_ESP_AVRC_PS_SHUFFLE_MODE,
+                    if shuffle {
+                        esp_avrc_ps_shf_value_ids_t_ESP_AVRC_PS_SHUFFLE_ALL
+                    } else {
+                        esp_avrc_ps_shf_value_ids_t_ESP_AVRC_PS_SHUFFLE_OFF
+                    },
+                ));
+            }
+            BtCommand::ToggleRepeat => {
+                let mut repeat = false;
 
-            match notification {
-                Notification::Playback(status) => match status {
-                    PlaybackStatus::Stopped => {
-                        audio_track.modify(|track| {
-                            track.reset();
-                            track.version += 1;
+                audio_track.modify(|track| {
+                    track.settings.repeat = !track.settings.repeat;
+                    track.version += 1;
+                    repeat = track.settings.repeat;
+                    true
+                });
+
+                info!("Repeat: {}", repeat);
+
+                avrcp_failed(set_player_value(
+                    esp_avrc_ps_attr_ids_t_ESP_AVRC_PS_REPEAT_MODE,
+                    if repeat {
+                        esp_avrc_ps_rpt_value_ids_t_ESP_AVRC_PS_REPEAT_GROUP
+                    } else {
+                        esp_avrc_ps_rpt_value_ids_t_ESP_AVRC_PS_REPEAT_OFF
+                    },
+                ));
+            }
+            BtCommand::ToggleLowLatency => {
+                let toggled = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
+
+                    devices.connected().map(|addr| {
+                        let low_latency = devices.toggle_low_latency(&addr);
+
+                        (low_latency, devices.low_latency().clone())
+                    })
+                });
+
+                if let Some((low_latency, list)) = toggled {
+                    info!("Low latency: {}", low_latency);
+
+                    a2dp_failed(set_latency(a2dp, audio_buffers, low_latency));
+                    devices::save_low_latency(storage, &list).await?;
+                }
+            }
+            BtCommand::BlockDevice => {
+                let blocked = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
+
+                    devices.connected().map(|addr| {
+                        let added = devices.block(&addr);
+
+                        (
+                            addr,
+                            added,
+                            devices.hfp() == Some(addr),
+                            devices.blocked().clone(),
+                        )
+                    })
+                });
+
+                if let Some((addr, added, hfp, list)) = blocked {
+                    if added {
+                        info!("Blocking device: {:?}", addr);
+
+                        devices::save_blocked(storage, &list).await?;
+                    } else {
+                        // Still disconnected, but it may connect again, as it is not blocked
+                        warn!("Too many blocked devices, not blocking {:?}", addr);
+
+                        notification.modify(|notification| {
+                            notification.post("TOO MANY BLOCKED", REFUSED_DURATION);
                             true
                         });
                     }
-                    PlaybackStatus::Playing
-                    | PlaybackStatus::SeekForward
-                    | PlaybackStatus::SeekBackward
-                    | PlaybackStatus::Paused => {
-                        audio_track.modify(|track| {
-                            track.paused = matches!(status, PlaybackStatus::Paused);
-                            track.version += 1;
-                            true
-                        });
+
+                    a2dp_failed(a2dp.disconnect(&addr.into()));
+
+                    // With multipoint, HFP might be connected to another phone
+                    if hfp {
+                        hfp_failed(hfpc.disconnect(&addr.into()));
                     }
-                    _ => (),
-                },
-                Notification::TrackChanged
-                | Notification::TrackStarted
-                | Notification::TrackEnded => {
-                    audio_track.modify(|track| {
-                        track.reset();
-                        track.version += 1;
-                        true
-                    });
-                }
-                Notification::PlaybackPosition(position) => {
-                    audio_track.modify(|track| {
-                        track.offset = core::time::Duration::from_secs(*position as _);
-                        track.version += 1;
-                        true
-                    });
                 }
-                _ => (),
             }
-        }
-        AvrccEvent::Metadata { id, text } => match id {
-            MetadataId::Title => audio_track.modify(|track| {
-                set_text(&mut track.song, text);
-                track.version += 1;
-                true
-            }),
-            MetadataId::Artist => audio_track.modify(|track| {
-                set_text(&mut track.artist, text);
-                track.version += 1;
-                true
-            }),
-            MetadataId::Album => audio_track.modify(|track| {
-                set_text(&mut track.album, text);
-                track.version += 1;
-                true
-            }),
-            MetadataId::PlayingTime => audio_track.modify(|track| {
-                track.duration = core::time::Duration::from_secs(0); // TODO (*text).into();
-                track.version += 1;
-                true
-            }),
-            _ => (),
-        },
-        _ => (),
-    }
-}
+            BtCommand::UnblockAll => {
+                info!("Unblocking all devices");
 
-fn handle_hfpc<'d, M>(
-    hfpc: &EspHfpc<'d, M, &BtDriver<'d, M>>,
-    phone: &Sender<'_, impl RawMutex, AudioState>,
-    phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
-    audio_buffers: &SharedAudioBuffers<'_>,
-    event: HfpcEvent<'_>,
-) -> usize
-where
-    M: BtClassicEnabled,
-{
-    match event {
-        HfpcEvent::ConnectionState { status, .. } => {
-            match status {
-                client::ConnectionStatus::Connected | client::ConnectionStatus::SlcConnected => {
-                    phone.send(AudioState::Connected)
-                }
-                client::ConnectionStatus::Disconnected => phone.send(AudioState::Initialized),
-                _ => (),
+                devices.lock(|devices| devices.borrow_mut().unblock_all());
+                devices::save_blocked(storage, &Default::default()).await?;
             }
+            BtCommand::SetArbitration(arbitration) => {
+                info!("Arbitration: {:?}", arbitration);
 
-            0
-        }
-        HfpcEvent::AudioState { status, .. } => {
-            match status {
-                client::AudioStatus::Connected | client::AudioStatus::ConnectedMsbc => {
-                    phone.send(AudioState::Streaming)
+                devices.lock(|devices| devices.borrow_mut().set_arbitration(arbitration));
+
+                takeover.modify(|takeover| {
+                    takeover.policy = arbitration;
+                    true
+                });
+
+                devices::save_arbitration(storage, arbitration).await?;
+            }
+            BtCommand::AcceptTakeover => {
+                let switched = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
+
+                    devices.pending().map(|pending| {
+                        let previous = devices.connected();
+
+                        devices.set_connected(Some(pending));
+                        devices.set_pending(None);
+
+                        (previous, devices.is_low_latency(&pending))
+                    })
+                });
+
+                set_takeover_pending(takeover, false);
+
+                if let Some((previous, low_latency)) = switched {
+                    a2dp_failed(set_latency(a2dp, audio_buffers, low_latency));
+                    publish_volume_control(audio_track, devices);
+
+                    if let Some(previous) = previous {
+                        a2dp_failed(a2dp.disconnect(&previous.into()));
+                    }
                 }
-                client::AudioStatus::Disconnected => phone.send(AudioState::Suspended),
-                _ => (),
             }
+            BtCommand::RejectTakeover => {
+                let pending = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
 
-            0
-        }
-        HfpcEvent::CallSetupState(state) if state != CallSetupStatus::Idle => {
-            hfpc.request_current_calls().unwrap();
+                    let pending = devices.pending();
+                    devices.set_pending(None);
 
-            phone_call.modify(|call| {
-                let state = match state {
-                    CallSetupStatus::Idle => unreachable!(),
-                    CallSetupStatus::Incoming => PhoneCallState::Ringing,
-                    CallSetupStatus::OutgoingDialing => PhoneCallState::Dialing,
-                    CallSetupStatus::OutgoingAlerting => PhoneCallState::DialingAlerting,
-                };
+                    pending
+                });
 
-                call.state = state;
-                call.version += 1;
-                true
-            });
+                set_takeover_pending(takeover, false);
 
-            0
-        }
-        HfpcEvent::CallState(active) => {
-            if active {
-                hfpc.request_current_calls().unwrap();
+                if let Some(pending) = pending {
+                    a2dp_failed(a2dp.disconnect(&pending.into()));
+                }
             }
+            BtCommand::Unpair(addr) => {
+                info!("Unpairing {:?}", addr);
 
-            phone_call.modify(|call| {
-                if active {
-                    call.state = PhoneCallState::CallActive;
-                } else {
-                    call.reset();
+                unpair(&addr);
+
+                let allowed = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
+
+                    devices.disallow(&addr).then(|| devices.allowed().clone())
+                });
+
+                // Frees the slot of the device, as the allowed devices are never evicted
+                if let Some(list) = allowed {
+                    devices::save_allowed(storage, &list).await?;
                 }
 
-                call.version += 1;
-                true
-            });
+                publish_bonded(bonded);
+            }
+            BtCommand::PrepareSleep => {
+                let (connected, hfp) = devices.lock(|devices| {
+                    let devices = devices.borrow();
 
-            0
+                    (devices.connected(), devices.hfp())
+                });
+
+                if connected.is_some() {
+                    if let Err(err) = avrcc.send_passthrough(0, KeyCode::Pause, true) {
+                        warn!("Pausing failed: {}", err);
+                    }
+                }
+
+                if let Some(hfp) = hfp {
+                    if let Err(err) = hfpc.disconnect_audio(&hfp.into()) {
+                        warn!("Disconnecting SCO failed: {}", err);
+                    }
+                }
+            }
+            BtCommand::UnpairAll => {
+                info!("Unpairing all devices");
+
+                match bonded_devices() {
+                    Ok(addrs) => addrs.iter().for_each(unpair),
+                    Err(err) => warn!("Reading the bonded devices failed: {}", err),
+                }
+
+                devices.lock(|devices| devices.borrow_mut().allow_all(Default::default()));
+                devices::save_allowed(storage, &Default::default()).await?;
+
+                publish_bonded(bonded);
+                publish_state(bt, devices);
+            }
+            BtCommand::SetIdentity(new) => {
+                info!("Identity: {}, PIN: {}", device_name(&new), new.pin());
+
+                devices.lock(|devices| devices.borrow_mut().set_identity(new));
+
+                set_identity(&new)?;
+
+                identity.modify(|identity| {
+                    *identity = new;
+                    true
+                });
+
+                devices::save_identity(storage, &new).await?;
+            }
+            BtCommand::OpenPairing => pairing.signal(()),
+            BtCommand::TestMode => test_request.signal(()),
+        }
+    }
+}
+
+async fn process_play_status<'d, M>(
+    avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>,
+    play_status_poll: &AtomicBool,
+    thermal: &StatefulReceiver<'_, impl RawMutex, Thermal>,
+    service: &ServiceLifecycle<'_, impl RawMutex>,
+) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    loop {
+        Timer::after(
+            if thermal.state(|thermal| thermal.throttled) || service.is_shedding() {
+                PLAY_STATUS_POLL_THROTTLED
+            } else {
+                PLAY_STATUS_POLL
+            },
+        )
+        .await;
+
+        // Fails e.g. while AVRCP is disconnecting, until `AvrccEvent::Disconnected` stops polling
+        if play_status_poll.load(Ordering::SeqCst) {
+            avrcp_failed(avrcc.request_play_status(5));
+        }
+    }
+}
+
+/// Retries the metadata requests left unanswered, see `MetadataRetry`
+async fn process_metadata<'d, M>(
+    avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>,
+    metadata: &SharedMetadataRetry,
+) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    loop {
+        Timer::after(METADATA_POLL).await;
+
+        if metadata.lock(|metadata| metadata.borrow_mut().poll(Instant::now())) {
+            info!("No AVRCP metadata yet, requesting it again");
+
+            // Still pending, so retried on the next poll
+            avrcp_failed(request_metadata(avrcc));
+        }
+    }
+}
+
+/// The adapter is discoverable only for a while after start and after `BtCommand::OpenPairing`,
+/// rather than to everyone passing by the parked car. Bonded phones can connect regardless
+async fn process_pairing<'d, M>(
+    gap: &EspGap<'d, M, &BtDriver<'d, M>>,
+    devices: &SharedDevices,
+    pairing: &Signal<impl RawMutex, ()>,
+) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    loop {
+        info!("Pairing window open");
+
+        devices.lock(|devices| devices.borrow_mut().set_pairing(true));
+        gap.set_scan_mode(true, DiscoveryMode::Discoverable)?;
+
+        if let Either::First(_) = select(Timer::after(PAIRING_WINDOW), pairing.wait()).await {
+            // Without any bonded phone the adapter would be of no use if not discoverable
+            if bonded_devices()?.is_empty() {
+                continue;
+            }
+
+            info!("Pairing window closed");
+
+            devices.lock(|devices| devices.borrow_mut().set_pairing(false));
+            gap.set_scan_mode(true, DiscoveryMode::NonDiscoverable)?;
+
+            pairing.wait().await;
+        }
+    }
+}
+
+/// Samples the link quality of the connected phone, as phones in a pocket or in the
+/// glovebox are a common cause of audio dropouts
+async fn process_link(
+    devices: &SharedDevices,
+    link: &StatefulSender<'_, impl RawMutex, LinkQuality>,
+    service: &ServiceLifecycle<'_, impl RawMutex>,
+) -> Result<(), Error> {
+    loop {
+        Timer::after(LINK_POLL).await;
+
+        // The link quality is of no use during the call anyway
+        if service.is_shedding() {
+            continue;
+        }
+
+        match devices.lock(|devices| devices.borrow().connected()) {
+            Some(mut addr) => {
+                // E.g. while the link is being torn down, so the sample is skipped
+                if let Err(err) = esp!(unsafe { esp_bt_gap_read_rssi_delta(addr.as_mut_ptr()) }) {
+                    warn!("Reading the RSSI failed: {}", err);
+                }
+            }
+            None => link.modify(|link| {
+                if link.device.is_some() {
+                    link.version += 1;
+                    link.device = None;
+                    link.rssi_delta = 0;
+                    true
+                } else {
+                    false
+                }
+            }),
+        }
+    }
+}
+
+/// Hints the low-priority services to throttle themselves for the duration of the call
+async fn process_shedding(
+    phone_call: &StatefulReceiver<'_, impl RawMutex, PhoneCallInfo>,
+    service: &ServiceLifecycle<'_, impl RawMutex>,
+) -> Result<(), Error> {
+    loop {
+        phone_call.recv().await;
+
+        // Also with a call waiting or on hold, as the SCO audio keeps running meanwhile
+        let active = phone_call.state(|call| call.state.is_in_call());
+
+        if active != service.is_shedding() {
+            info!("Load shedding: {}", active);
+
+            service.sys_set_shedding(active);
+        }
+    }
+}
+
+/// Phones do not always reconnect on their own, so the adapter initiates the connection
+/// to the last connected device, for as long as no device is connected
+async fn process_reconnect<'d, M>(
+    a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>,
+    hfpc: &EspHfpc<'d, M, &BtDriver<'d, M>>,
+    devices: &SharedDevices,
+    notification: &StatefulSender<'_, impl RawMutex, can::Notification>,
+    storage: &Storage,
+) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    let mut backoff = RECONNECT_MIN;
+
+    loop {
+        Timer::after(backoff).await;
+
+        let (connected, hfp, last) = devices.lock(|devices| {
+            let devices = devices.borrow();
+
+            let last = devices.last().filter(|addr| !devices.is_blocked(addr));
+
+            (devices.connected(), devices.hfp(), last)
+        });
+
+        // A device admitted during the pairing window is allowed from now on,
+        // be it connected over A2DP or (with multipoint) over HFP only
+        let mut refused = false;
+
+        for addr in [connected, hfp].into_iter().flatten() {
+            let allowed = devices.lock(|devices| {
+                let mut devices = devices.borrow_mut();
+
+                devices
+                    .allow(&addr)
+                    .map(|added| added.then(|| devices.allowed().clone()))
+            });
+
+            match allowed {
+                Some(Some(list)) => devices::save_allowed(storage, &list).await?,
+                Some(None) => (),
+                None => {
+                    warn!("Too many allowed devices, refusing {:?}", addr);
+
+                    notification.modify(|notification| {
+                        notification.post("TOO MANY PHONES", REFUSED_DURATION);
+                        true
+                    });
+
+                    // Refused again on reconnecting, for as long as the pairing window is open,
+                    // and by `is_admissible` once it is closed
+                    a2dp_failed(a2dp.disconnect(&addr.into()));
+                    hfp_failed(hfpc.disconnect(&addr.into()));
+
+                    refused = true;
+                }
+            }
+        }
+
+        if refused {
+            backoff = RECONNECT_MIN;
+        } else if let Some(connected) = connected {
+            if last != Some(connected) {
+                devices.lock(|devices| devices.borrow_mut().set_last(connected));
+                devices::save_last(storage, &connected).await?;
+            }
+
+            backoff = RECONNECT_MIN;
+        } else if let Some(last) = last {
+            info!("Reconnecting to {:?}", last);
+
+            if let Err(err) = a2dp.connect_source(&last.into()) {
+                warn!("A2DP reconnect failed: {}", err);
+            }
+
+            if let Err(err) = hfpc.connect(&last.into()) {
+                warn!("HFP reconnect failed: {}", err);
+            }
+
+            backoff = (backoff * 2).min(RECONNECT_MAX);
+        }
+    }
+}
+
+fn change_volume<'d, M>(
+    avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>,
+    absolute_volume: &AbsoluteVolume,
+    devices: &SharedDevices,
+    up: bool,
+) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    let control = devices.lock(|devices| {
+        let devices = devices.borrow();
+
+        devices
+            .connected()
+            .map(|addr| devices.volume_control(&addr))
+    });
+
+    match control {
+        Some(VolumeControl::Passthrough) => {
+            let key = if up {
+                KeyCode::VolumeUp
+            } else {
+                KeyCode::VolumeDown
+            };
+
+            avrcc.send_passthrough(0, key, true)?;
+        }
+        // A notification the phone did not wait for is of no harm, hence only logged
+        Some(VolumeControl::Absolute) => {
+            if let Err(err) = absolute_volume.step(up) {
+                warn!("Notifying the absolute volume failed: {}", err);
+            }
+        }
+        // The radio adjusts its own volume from the same keys
+        Some(VolumeControl::Local) | None => (),
+    }
+
+    Ok(())
+}
+
+fn publish_volume_control(
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    devices: &SharedDevices,
+) {
+    let control = devices.lock(|devices| {
+        let devices = devices.borrow();
+
+        devices
+            .connected()
+            .map(|addr| devices.volume_control(&addr))
+            .unwrap_or(VolumeControl::Local)
+    });
+
+    audio_track.modify(|track| {
+        if track.settings.volume != control {
+            track.settings.volume = control;
+            track.version += 1;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+fn set_latency<'d, M>(
+    a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    low_latency: bool,
+) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    audio_buffers.lock(|buffers| buffers.borrow_mut().set_low_latency(low_latency));
+
+    a2dp.set_delay(delay(audio_buffers, low_latency))?;
+
+    Ok(())
+}
+
+/// The delay reported to the phone includes the latency of the I2S output
+fn delay(audio_buffers: &SharedAudioBuffers<'_>, low_latency: bool) -> core::time::Duration {
+    let output_latency = audio_buffers.lock(|buffers| buffers.borrow().output_latency());
+
+    if low_latency {
+        LOW_LATENCY_DELAY + output_latency
+    } else {
+        DELAY + output_latency
+    }
+}
+
+fn handle_gap<'d, M>(
+    gap: &EspGap<'d, M, &BtDriver<'d, M>>,
+    bt: &Sender<'_, impl RawMutex, BtState>,
+    devices: &SharedDevices,
+    bonded: &StatefulSender<'_, impl RawMutex, Bonded>,
+    link: &StatefulSender<'_, impl RawMutex, LinkQuality>,
+    event: GapEvent<'_>,
+) where
+    M: BtClassicEnabled,
+{
+    match event {
+        GapEvent::DeviceDiscovered { bd_addr, props } => {
+            info!("Found device: {:?}", bd_addr);
+
+            for prop in props {
+                info!("Prop: {:?}", prop.prop());
+            }
+
+            //let _ = gap.stop_discovery();
+        }
+        GapEvent::PairingUserConfirmationRequest { bd_addr, .. } => {
+            let refused = devices.lock(|devices| {
+                let devices = devices.borrow();
+
+                devices.is_blocked(&bd_addr.into()) || !devices.is_pairing()
+            });
+
+            if refused {
+                info!("Refusing to pair with device: {:?}", bd_addr);
+            }
+
+            gap.reply_ssp_confirm(&bd_addr, !refused).unwrap();
+        }
+        GapEvent::AuthenticationCompleted {
+            bd_addr, status, ..
+        } => {
+            publish_bonded(bonded);
+
+            if status == BtStatus::Success {
+                publish_state(bt, devices);
+            } else {
+                warn!("Pairing with {:?} failed: {:?}", bd_addr, status);
+
+                bt.send(BtState::PairFailed);
+            }
+        }
+        GapEvent::RssiDelta { bd_addr, delta, .. } => link.modify(|link| {
+            link.version += 1;
+            link.device = Some(bd_addr.into());
+            link.rssi_delta = delta;
+
+            if link.is_weak() {
+                link.weak_samples += 1;
+            }
+
+            true
+        }),
+        _ => (),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_a2dp<'d, M>(
+    a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>,
+    bt: &Sender<'_, impl RawMutex, BtState>,
+    audio: &Sender<'_, impl RawMutex, AudioState>,
+    audio_codec: &StatefulSender<'_, impl RawMutex, CodecInfo>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    devices: &SharedDevices,
+    takeover: &StatefulSender<'_, impl RawMutex, Takeover>,
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    event: A2dpEvent<'_>,
+) where
+    M: BtClassicEnabled,
+{
+    if !matches!(event, A2dpEvent::SinkData(_)) {
+        trace::record(Profile::A2dp, || describe_a2dp(&event));
+    }
+
+    match event {
+        A2dpEvent::Initialized => audio.send(AudioState::Initialized),
+        A2dpEvent::Deinitialized => audio.send(AudioState::Uninitialized),
+        A2dpEvent::ConnectionState {
+            bd_addr, status, ..
+        } => match status {
+            ConnectionStatus::Connected => {
+                let addr = bd_addr.into();
+
+                let admission = devices.lock(|devices| admit(&mut devices.borrow_mut(), addr));
+
+                match admission {
+                    Admission::Accept {
+                        low_latency,
+                        previous,
+                    } => {
+                        if let Some(previous) = previous {
+                            info!("Switching from device {:?}", previous);
+                            disconnect(a2dp, &previous);
+                        }
+
+                        if let Err(err) = set_latency(a2dp, audio_buffers, low_latency) {
+                            warn!("Setting latency failed: {}", err);
+                        }
+
+                        audio.send(AudioState::Connected)
+                    }
+                    Admission::Hold => {
+                        info!("Device {:?} waits for the takeover to be accepted", bd_addr);
+                        set_takeover_pending(takeover, true);
+                    }
+                    Admission::Refuse => {
+                        info!("Refusing device {:?}", bd_addr);
+                        disconnect(a2dp, &addr);
+                    }
+                }
+
+                publish_state(bt, devices);
+                publish_volume_control(audio_track, devices);
+            }
+            ConnectionStatus::Disconnected => {
+                let addr = bd_addr.into();
+
+                let connected = devices.lock(|devices| {
+                    let mut devices = devices.borrow_mut();
+
+                    if devices.pending() == Some(addr) {
+                        devices.set_pending(None);
+                    } else if devices.connected() == Some(addr) {
+                        // A device waiting for the takeover takes over anyway
+                        let pending = devices.pending();
+
+                        devices.set_connected(pending);
+                        devices.set_pending(None);
+                    }
+
+                    devices
+                        .connected()
+                        .map(|addr| devices.is_low_latency(&addr))
+                });
+
+                set_takeover_pending(takeover, false);
+
+                if let Some(low_latency) = connected {
+                    if let Err(err) = set_latency(a2dp, audio_buffers, low_latency) {
+                        warn!("Setting latency failed: {}", err);
+                    }
+                } else {
+                    audio.send(AudioState::Initialized)
+                }
+
+                publish_state(bt, devices);
+                publish_volume_control(audio_track, devices);
+            }
+            _ => (),
+        },
+        A2dpEvent::AudioState { status, .. } => match status {
+            AudioStatus::Started => audio.send(AudioState::Streaming),
+            AudioStatus::SuspendedByRemote => audio.send(AudioState::Suspended),
+            AudioStatus::Stopped => audio.send(AudioState::Connected),
+        },
+        A2dpEvent::AudioSinkConfigured { codec, .. } => {
+            if let Some(new) = codec_info(&codec) {
+                info!("A2DP codec configured: {:?}", new);
+
+                // The speakers drain what is buffered and re-clock the I2S output on their own
+                audio_codec.modify(|codec| {
+                    if *codec != new {
+                        *codec = new;
+                        true
+                    } else {
+                        false
+                    }
+                });
+            }
+        }
+        A2dpEvent::SinkData(data) => {
+            audio_buffers.lock(|buffers| {
+                buffers.borrow_mut().push_incoming(data, true, || {});
+            });
+        }
+        _ => (),
+    }
+}
+
+enum Admission {
+    Accept {
+        low_latency: bool,
+        previous: Option<BtAddr>,
+    },
+    Hold,
+    Refuse,
+}
+
+fn admit(devices: &mut Devices, addr: BtAddr) -> Admission {
+    if !devices.is_admissible(&addr) {
+        return Admission::Refuse;
+    }
+
+    let previous = devices.connected().filter(|previous| *previous != addr);
+
+    match (previous, devices.arbitration()) {
+        (Some(_), Arbitration::Reject) => Admission::Refuse,
+        (Some(_), Arbitration::Ask) => {
+            devices.set_pending(Some(addr));
+            Admission::Hold
+        }
+        (previous, _) => {
+            devices.set_connected(Some(addr));
+
+            Admission::Accept {
+                low_latency: devices.is_low_latency(&addr),
+                previous,
+            }
+        }
+    }
+}
+
+fn set_takeover_pending(takeover: &StatefulSender<'_, impl RawMutex, Takeover>, pending: bool) {
+    takeover.modify(|takeover| {
+        if takeover.pending != pending {
+            takeover.pending = pending;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+fn disconnect<'d, M>(a2dp: &EspA2dp<'d, M, &BtDriver<'d, M>, impl SinkEnabled>, addr: &BtAddr)
+where
+    M: BtClassicEnabled,
+{
+    if let Err(err) = a2dp.disconnect(&(*addr).into()) {
+        warn!("Disconnecting {:?} failed: {}", addr, err);
+    }
+}
+
+fn unpair(addr: &BtAddr) {
+    if let Err(err) = remove_bond(addr) {
+        warn!("Unpairing {:?} failed: {}", addr, err);
+    }
+}
+
+fn publish_bonded(bonded: &StatefulSender<'_, impl RawMutex, Bonded>) {
+    match bonded_devices() {
+        Ok(devices) => bonded.modify(|bonded| {
+            bonded.version += 1;
+            bonded.devices = devices;
+            true
+        }),
+        Err(err) => warn!("Reading the bonded devices failed: {}", err),
+    }
+}
+
+/// Ends the stack for the RF test mode, which is only entered in service mode
+async fn wait_test_mode(
+    test_request: &Signal<impl RawMutex, ()>,
+    testing: &Cell<bool>,
+    service: &ServiceLifecycle<'_, impl RawMutex>,
+) -> Result<(), Error> {
+    loop {
+        test_request.wait().await;
+
+        if service.is_service_mode() {
+            testing.set(true);
+
+            return Ok(());
+        }
+
+        warn!("The test mode is only available in service mode");
+    }
+}
+
+/// Puts the controller in the Device Under Test mode of the Bluetooth test specification,
+/// so that it can be driven by an RF tester. Only a power cycle exits it
+async fn test_mode(modem: impl Peripheral<P = impl BluetoothModemPeripheral>) -> Result<(), Error> {
+    // HCI commands, prefixed with the H4 packet type
+    const WRITE_SCAN_ENABLE: &[u8] = &[0x01, 0x1a, 0x0c, 0x01, 0x03];
+    const SET_EVENT_FILTER_AUTO_ACCEPT: &[u8] = &[0x01, 0x05, 0x0c, 0x03, 0x02, 0x00, 0x02];
+    const ENABLE_DUT_MODE: &[u8] = &[0x01, 0x03, 0x18, 0x00];
+
+    warn!("Entering the Bluetooth test mode");
+
+    let _driver = BtDriver::<BtClassic>::new(modem, None)?;
+
+    // The profiles are not used by the tester, and Bluedroid would otherwise own the HCI
+    esp!(unsafe { esp_bluedroid_disable() })?;
+    esp!(unsafe { esp_bluedroid_deinit() })?;
+
+    for command in [
+        WRITE_SCAN_ENABLE,
+        SET_EVENT_FILTER_AUTO_ACCEPT,
+        ENABLE_DUT_MODE,
+    ] {
+        while !unsafe { esp_vhci_host_check_send_available() } {
+            Timer::after(Duration::from_millis(10)).await;
+        }
+
+        let mut packet = heapless::Vec::<u8, 8>::from_slice(command).unwrap();
+
+        unsafe { esp_vhci_host_send_packet(packet.as_mut_ptr(), packet.len() as _) };
+
+        Timer::after(Duration::from_millis(100)).await;
+    }
+
+    warn!("Bluetooth test mode active, power cycle to exit");
+
+    core::future::pending().await
+}
+
+/// Phones bonded before the allow-list was introduced are allowed as they are
+async fn seed_allowed(devices: &SharedDevices, storage: &Storage) -> Result<(), Error> {
+    if !devices.lock(|devices| devices.borrow().allowed().is_empty()) {
+        return Ok(());
+    }
+
+    let bonded = bonded_devices()?;
+
+    if !bonded.is_empty() {
+        devices.lock(|devices| devices.borrow_mut().allow_all(bonded.clone()));
+        devices::save_allowed(storage, &bonded).await?;
+    }
+
+    Ok(())
+}
+
+/// Connected while a phone is admitted, paired while there are bonded phones to reconnect to
+fn publish_state(bt: &Sender<'_, impl RawMutex, BtState>, devices: &SharedDevices) {
+    let connected = devices.lock(|devices| devices.borrow().connected().is_some());
+
+    let state = if connected {
+        BtState::Connected
+    } else if bonded_devices()
+        .map(|devices| !devices.is_empty())
+        .unwrap_or(false)
+    {
+        BtState::Paired
+    } else {
+        BtState::Initialized
+    };
+
+    bt.send(state);
+}
+
+pub fn device_name(identity: &Identity) -> heapless::String<16> {
+    let mut name = heapless::String::new();
+
+    let _ = name.push_str(DEVICE_NAME);
+
+    if identity.suffix > 0 {
+        let _ = write!(name, " {}", identity.suffix);
+    }
+
+    name
+}
+
+/// Applies a changed name and PIN to the running Bluetooth stack
+fn set_identity(identity: &Identity) -> Result<(), Error> {
+    let mut name = heapless::Vec::<u8, 17>::from_slice(device_name(identity).as_bytes()).unwrap();
+    name.push(0).unwrap();
+
+    let mut pin = identity.pin;
+
+    esp!(unsafe { esp_bt_dev_set_device_name(name.as_ptr() as _) })?;
+    esp!(unsafe {
+        esp_bt_gap_set_pin(
+            esp_bt_pin_type_t_ESP_BT_PIN_TYPE_FIXED,
+            pin.len() as _,
+            pin.as_mut_ptr(),
+        )
+    })?;
+
+    Ok(())
+}
+
+fn bonded_devices() -> Result<heapless::Vec<[u8; 6], MAX_BONDED>, Error> {
+    let mut list = [[0; 6]; MAX_BONDED];
+    let mut count = list.len() as _;
+
+    esp!(unsafe { esp_bt_gap_get_bond_device_list(&mut count, list.as_mut_ptr()) })?;
+
+    Ok(list[..count as usize].iter().copied().collect())
+}
+
+fn remove_bond(addr: &[u8; 6]) -> Result<(), Error> {
+    let mut addr = *addr;
+
+    esp!(unsafe { esp_bt_gap_remove_bond_device(addr.as_mut_ptr()) })?;
+
+    Ok(())
+}
+
+fn codec_info(codec: &Codec) -> Option<CodecInfo> {
+    let info = match codec {
+        Codec::Sbc(info) => info,
+        other => {
+            warn!("Unsupported A2DP codec: {:?}", other);
+            return None;
+        }
+    };
+
+    let sample_rate = match info[0] & 0xf0 {
+        0x80 => 16000,
+        0x40 => 32000,
+        0x20 => 44100,
+        0x10 => 48000,
+        _ => return None,
+    };
+
+    let channels = if info[0] & 0x08 != 0 { 1 } else { 2 };
+
+    Some(CodecInfo {
+        sample_rate,
+        channels,
+    })
+}
+
+fn handle_avrcc<'d, M>(
+    avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>,
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    play_status_poll: &AtomicBool,
+    metadata: &SharedMetadataRetry,
+    event: AvrccEvent<'_>,
+) where
+    M: BtClassicEnabled,
+{
+    trace::record(Profile::Avrcp, || describe_avrcp(&event));
+
+    match &event {
+        AvrccEvent::Connected(bd_addr) => {
+            audio_track.modify(|track| {
+                track.state = AudioTrackState::Connected;
+                track.device = Some((*bd_addr).into());
+                track.version += 1;
+                true
+            });
+            avrcp_failed(avrcc.request_capabilities(0));
+        }
+        AvrccEvent::Disconnected(_) => {
+            play_status_poll.store(false, Ordering::SeqCst);
+            metadata.lock(|metadata| metadata.borrow_mut().reset());
+
+            audio_track.modify(|track| {
+                track.state = AudioTrackState::Initialized;
+                track.version += 1;
+                true
+            })
+        }
+        AvrccEvent::NotificationCapabilities { capabilities, .. } => {
+            // Without playback notifications `paused` would never change, so fall back to polling
+            let poll = !capabilities.contains(NotificationType::Playback);
+
+            info!("AVRCP play status polling: {}", poll);
+            play_status_poll.store(poll, Ordering::SeqCst);
+
+            request_info(avrcc);
+            metadata.lock(|metadata| metadata.borrow_mut().requested(Instant::now()));
+        }
+        AvrccEvent::PlayStatus {
+            status, position, ..
+        } => {
+            audio_track.modify(|track| {
+                let paused = !matches!(
+                    status,
+                    PlaybackStatus::Playing
+                        | PlaybackStatus::SeekForward
+                        | PlaybackStatus::SeekBackward
+                );
+                let offset = core::time::Duration::from_millis(*position as _);
+
+                if track.paused != paused || track.offset.as_secs() != offset.as_secs() {
+                    track.paused = paused;
+                    track.offset = offset;
+                    track.version += 1;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        AvrccEvent::Notification(notification) => {
+            request_info(avrcc); // TODO: Necessary?
+
+            match notification {
+                Notification::Playback(status) => match status {
+                    PlaybackStatus::Stopped => {
+                        audio_track.modify(|track| {
+                            track.reset();
+                            track.version += 1;
+                            true
+                        });
+                    }
+                    PlaybackStatus::Playing
+                    | PlaybackStatus::SeekForward
+                    | PlaybackStatus::SeekBackward
+                    | PlaybackStatus::Paused => {
+                        audio_track.modify(|track| {
+                            track.paused = matches!(status, PlaybackStatus::Paused);
+                            track.version += 1;
+                            true
+                        });
+
+                        // Requested above as well, but phones rejecting it while paused
+                        // should be asked again once playing
+                        if matches!(status, PlaybackStatus::Playing) {
+                            metadata
+                                .lock(|metadata| metadata.borrow_mut().requested(Instant::now()));
+                        }
+                    }
+                    _ => (),
+                },
+                Notification::TrackChanged
+                | Notification::TrackStarted
+                | Notification::TrackEnded => {
+                    audio_track.modify(|track| {
+                        track.reset();
+                        track.version += 1;
+                        true
+                    });
+                }
+                Notification::PlaybackPosition(position) => {
+                    audio_track.modify(|track| {
+                        track.offset = core::time::Duration::from_secs(*position as _);
+                        track.version += 1;
+                        true
+                    });
+                }
+                _ => (),
+            }
+        }
+        AvrccEvent::Metadata { id, text } => {
+            metadata.lock(|metadata| metadata.borrow_mut().reset());
+
+            handle_metadata(audio_track, *id, text);
+        }
+        _ => (),
+    }
+}
+
+fn handle_metadata(
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    id: MetadataId,
+    text: &str,
+) {
+    match id {
+        MetadataId::Title => audio_track.modify(|track| {
+            set_text(&mut track.song, text);
+            track.version += 1;
+            true
+        }),
+        MetadataId::Artist => audio_track.modify(|track| {
+            set_text(&mut track.artist, text);
+            track.version += 1;
+            true
+        }),
+        MetadataId::Album => audio_track.modify(|track| {
+            set_text(&mut track.album, text);
+            track.version += 1;
+            true
+        }),
+        MetadataId::PlayingTime => audio_track.modify(|track| {
+            // The playing time is reported in milliseconds, as a string
+            track.duration = core::time::Duration::from_millis(text.trim().parse().unwrap_or(0));
+            track.version += 1;
+            true
+        }),
+        _ => (),
+    }
+}
+
+fn handle_hfpc<'d, M>(
+    hfpc: &EspHfpc<'d, M, &BtDriver<'d, M>>,
+    phone: &Sender<'_, impl RawMutex, AudioState>,
+    phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
+    hfp_indicators: &StatefulSender<'_, impl RawMutex, HfpIndicators>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    devices: &SharedDevices,
+    event: HfpcEvent<'_>,
+) -> usize
+where
+    M: BtClassicEnabled,
+{
+    if !matches!(event, HfpcEvent::RecvData(_) | HfpcEvent::SendData(_)) {
+        trace::record(Profile::Hfp, || describe_hfp(&event));
+    }
+
+    update_indicators(hfp_indicators, &event);
+
+    match event {
+        HfpcEvent::ConnectionState {
+            bd_addr, status, ..
+        } => {
+            let refused = devices.lock(|devices| !devices.borrow().is_admissible(&bd_addr.into()));
+
+            if refused && !matches!(status, client::ConnectionStatus::Disconnected) {
+                info!("Disconnecting refused device: {:?}", bd_addr);
+
+                if let Err(err) = hfpc.disconnect(&bd_addr) {
+                    warn!("Disconnecting failed: {}", err);
+                }
+
+                return 0;
+            }
+
+            let addr = bd_addr.into();
+
+            match status {
+                client::ConnectionStatus::Connected | client::ConnectionStatus::SlcConnected => {
+                    devices.lock(|devices| devices.borrow_mut().set_hfp(Some(addr)));
+                    set_call_device(phone_call, Some(addr));
+
+                    phone.send(AudioState::Connected)
+                }
+                client::ConnectionStatus::Disconnected => {
+                    let current = devices.lock(|devices| {
+                        let mut devices = devices.borrow_mut();
+
+                        let current = devices.hfp() == Some(addr);
+                        if current {
+                            devices.set_hfp(None);
+                        }
+
+                        current
+                    });
+
+                    if current {
+                        set_call_device(phone_call, None);
+
+                        phone.send(AudioState::Initialized)
+                    }
+                }
+                _ => (),
+            }
+
+            0
+        }
+        HfpcEvent::AudioState { status, .. } => {
+            match status {
+                client::AudioStatus::Connected | client::AudioStatus::ConnectedMsbc => {
+                    let msbc = matches!(status, client::AudioStatus::ConnectedMsbc);
+
+                    info!("HFP audio connected, wideband: {}", msbc);
+
+                    audio_buffers.lock(|buffers| buffers.borrow_mut().set_msbc(msbc));
+
+                    phone.send(AudioState::Streaming)
+                }
+                client::AudioStatus::Disconnected => phone.send(AudioState::Suspended),
+                _ => (),
+            }
+
+            0
+        }
+        HfpcEvent::CallSetupState(CallSetupStatus::Idle) => {
+            // The waiting call was either answered, rejected or given up by the caller
+            phone_call.modify(|call| {
+                if matches!(call.state, PhoneCallState::CallWaiting) {
+                    call.state = PhoneCallState::CallActive;
+                    call.waiting.clear();
+                    call.version += 1;
+                    true
+                } else {
+                    false
+                }
+            });
+
+            0
+        }
+        HfpcEvent::CallSetupState(state) => {
+            hfp_failed(hfpc.request_current_calls());
+
+            phone_call.modify(|call| {
+                let state = match state {
+                    CallSetupStatus::Idle => unreachable!(),
+                    CallSetupStatus::Incoming if call.state.is_in_call() => {
+                        PhoneCallState::CallWaiting
+                    }
+                    CallSetupStatus::Incoming => PhoneCallState::Ringing,
+                    CallSetupStatus::OutgoingDialing => PhoneCallState::Dialing,
+                    CallSetupStatus::OutgoingAlerting => PhoneCallState::DialingAlerting,
+                };
+
+                call.state = state;
+                call.version += 1;
+                true
+            });
+
+            0
+        }
+        HfpcEvent::CallWaiting(number) => {
+            phone_call.modify(|call| {
+                call.state = PhoneCallState::CallWaiting;
+                set_text(&mut call.waiting, number);
+                call.version += 1;
+                true
+            });
+
+            0
+        }
+        HfpcEvent::CallHeldState(held) => {
+            hfp_failed(hfpc.request_current_calls());
+
+            phone_call.modify(|call| {
+                let state = match held {
+                    CallHeldStatus::None if call.state.is_in_call() => PhoneCallState::CallActive,
+                    CallHeldStatus::None => return false,
+                    CallHeldStatus::HeldAndActive | CallHeldStatus::Held => PhoneCallState::OnHold,
+                };
+
+                call.state = state;
+                call.waiting.clear();
+                call.version += 1;
+                true
+            });
+
+            0
+        }
+        HfpcEvent::CallState(active) => {
+            if active {
+                hfp_failed(hfpc.request_current_calls());
+            }
+
+            phone_call.modify(|call| {
+                if !active {
+                    call.reset();
+                } else if !call.state.is_in_call() {
+                    call.state = PhoneCallState::CallActive;
+                }
+
+                call.version += 1;
+                true
+            });
+
+            0
+        }
+        HfpcEvent::CallingLineIdent(number) => {
+            set_caller(phone_call, number);
+
+            0
+        }
+        // Listed for each call, so only the active (or ringing) one is the caller
+        HfpcEvent::CurrentCall { status, number, .. } => {
+            match status {
+                CurrentCallStatus::Active
+                | CurrentCallStatus::Dialing
+                | CurrentCallStatus::Alerting
+                | CurrentCallStatus::Incoming => set_caller(phone_call, number),
+                CurrentCallStatus::Waiting
+                | CurrentCallStatus::Held
+                | CurrentCallStatus::HeldByResponseAndHold => set_waiting(phone_call, number),
+            }
+
+            0
+        }
+        HfpcEvent::VolumeControl { target, volume } => {
+            info!("Phone set the {:?} volume: {}", target, volume);
+
+            audio_buffers.lock(|buffers| {
+                let mut buffers = buffers.borrow_mut();
+
+                match target {
+                    VolumeControlTarget::Speaker => buffers.set_speaker_volume(volume),
+                    VolumeControlTarget::Microphone => buffers.set_mic_volume(volume),
+                }
+            });
+
+            0
         }
-        // HfpcEvent::CurrentCall { outgoing, status, number, .. } => {
-        //     match status {
-        //         CurrentCallStatus::Active => todo!(),
-        //         CurrentCallStatus::Held => todo!(),
-        //         CurrentCallStatus::Dialing => todo!(),
-        //         CurrentCallStatus::Alerting => todo!(),
-        //         CurrentCallStatus::Incoming => todo!(),
-        //         CurrentCallStatus::Waiting => todo!(),
-        //         CurrentCallStatus::HeldByResponseAndHold => todo!(),
-        //     }
-
-        //     0
-        // }
         HfpcEvent::RecvData(data) => {
             audio_buffers.lock(|buffers| {
                 buffers.borrow_mut().push_incoming(data, false, || {
@@ -415,39 +1768,222 @@ where
     }
 }
 
+// NOTE: Only the number is known. Resolving it to a contact name needs a PBAP client,
+// which esp-idf-svc does not provide
+fn set_caller(phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>, number: &str) {
+    phone_call.modify(|call| {
+        if call.phone != number {
+            set_text(&mut call.phone, number);
+            call.version += 1;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+/// A lone call put on hold is still the caller, rather than a second call
+fn set_waiting(phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>, number: &str) {
+    phone_call.modify(|call| {
+        if call.phone != number && call.waiting != number {
+            set_text(&mut call.waiting, number);
+            call.version += 1;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+fn set_call_device(
+    phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
+    device: Option<BtAddr>,
+) {
+    phone_call.modify(|call| {
+        if call.device != device {
+            call.device = device;
+            call.version += 1;
+            true
+        } else {
+            false
+        }
+    });
+}
+
+/// Mirrors the CIEV indicators as they are, for the features not interested in the call state
+///
+/// NOTE: The HFP 1.7 HF indicators (AT+BIND, e.g. the enhanced safety or the battery level of the
+/// unit) are not supported, as the HF client of Bluedroid neither negotiates nor sends them
+fn update_indicators(
+    hfp_indicators: &StatefulSender<'_, impl RawMutex, HfpIndicators>,
+    event: &HfpcEvent<'_>,
+) {
+    hfp_indicators.modify(|indicators| {
+        match event {
+            HfpcEvent::CallState(active) => indicators.call = *active,
+            HfpcEvent::CallSetupState(state) => {
+                indicators.call_setup = match state {
+                    CallSetupStatus::Idle => 0,
+                    CallSetupStatus::Incoming => 1,
+                    CallSetupStatus::OutgoingDialing => 2,
+                    CallSetupStatus::OutgoingAlerting => 3,
+                }
+            }
+            HfpcEvent::CallHeldState(held) => {
+                indicators.call_held = match held {
+                    CallHeldStatus::None => 0,
+                    CallHeldStatus::HeldAndActive => 1,
+                    CallHeldStatus::Held => 2,
+                }
+            }
+            HfpcEvent::ServiceAvailability(available) => indicators.service = *available,
+            HfpcEvent::NetworkRoaming(roaming) => indicators.roaming = *roaming,
+            HfpcEvent::SignalStrength(signal) => indicators.signal = *signal,
+            HfpcEvent::BatteryLevel(battery) => indicators.battery = *battery,
+            _ => return false,
+        }
+
+        indicators.version += 1;
+        true
+    });
+}
+
+/// The variant and the key fields of the event, for the trace records
+fn describe_a2dp(event: &A2dpEvent<'_>) -> (&'static str, [u32; trace::MAX_ARGS]) {
+    match event {
+        A2dpEvent::Initialized => ("Initialized", [0; 2]),
+        A2dpEvent::Deinitialized => ("Deinitialized", [0; 2]),
+        A2dpEvent::ConnectionState {
+            bd_addr, status, ..
+        } => (
+            "ConnectionState",
+            [*status as u32, trace_addr(&(*bd_addr).into())],
+        ),
+        A2dpEvent::AudioState { status, .. } => ("AudioState", [*status as u32, 0]),
+        A2dpEvent::AudioSinkConfigured { .. } => ("AudioSinkConfigured", [0; 2]),
+        _ => ("Other", [0; 2]),
+    }
+}
+
+fn describe_avrcp(event: &AvrccEvent<'_>) -> (&'static str, [u32; trace::MAX_ARGS]) {
+    match event {
+        AvrccEvent::Connected(bd_addr) => ("Connected", [0, trace_addr(&(*bd_addr).into())]),
+        AvrccEvent::Disconnected(bd_addr) => ("Disconnected", [0, trace_addr(&(*bd_addr).into())]),
+        AvrccEvent::NotificationCapabilities { capabilities, .. } => {
+            ("NotificationCapabilities", [capabilities.as_u32(), 0])
+        }
+        AvrccEvent::PlayStatus {
+            status, position, ..
+        } => ("PlayStatus", [*status as u32, *position as u32]),
+        AvrccEvent::Notification(Notification::Playback(status)) => {
+            ("Playback", [*status as u32, 0])
+        }
+        AvrccEvent::Notification(Notification::PlaybackPosition(position)) => {
+            ("PlaybackPosition", [*position as u32, 0])
+        }
+        AvrccEvent::Notification(_) => ("Notification", [0; 2]),
+        AvrccEvent::Metadata { id, .. } => ("Metadata", [*id as u32, 0]),
+        _ => ("Other", [0; 2]),
+    }
+}
+
+/// NOTE: The caller numbers are left out, so that a dump carries no personal data
+fn describe_hfp(event: &HfpcEvent<'_>) -> (&'static str, [u32; trace::MAX_ARGS]) {
+    match event {
+        HfpcEvent::ConnectionState {
+            bd_addr, status, ..
+        } => (
+            "ConnectionState",
+            [*status as u32, trace_addr(&(*bd_addr).into())],
+        ),
+        HfpcEvent::AudioState { status, .. } => ("AudioState", [*status as u32, 0]),
+        HfpcEvent::CallState(active) => ("CallState", [*active as u32, 0]),
+        HfpcEvent::CallSetupState(state) => ("CallSetupState", [*state as u32, 0]),
+        HfpcEvent::CallHeldState(held) => ("CallHeldState", [*held as u32, 0]),
+        HfpcEvent::ServiceAvailability(available) => {
+            ("ServiceAvailability", [*available as u32, 0])
+        }
+        HfpcEvent::NetworkRoaming(roaming) => ("NetworkRoaming", [*roaming as u32, 0]),
+        HfpcEvent::SignalStrength(signal) => ("SignalStrength", [*signal as u32, 0]),
+        HfpcEvent::BatteryLevel(battery) => ("BatteryLevel", [*battery as u32, 0]),
+        HfpcEvent::CallingLineIdent(_) => ("CallingLineIdent", [0; 2]),
+        HfpcEvent::CallWaiting(_) => ("CallWaiting", [0; 2]),
+        HfpcEvent::CurrentCall { .. } => ("CurrentCall", [0; 2]),
+        HfpcEvent::VolumeControl { target, volume } => {
+            ("VolumeControl", [*target as u32, *volume as u32])
+        }
+        _ => ("Other", [0; 2]),
+    }
+}
+
+/// The low bytes of the address, enough to tell the phones apart
+fn trace_addr(addr: &BtAddr) -> u32 {
+    u32::from_be_bytes([addr[2], addr[3], addr[4], addr[5]])
+}
+
+/// Profile failures are only logged, rather than restarting the whole stack and with it
+/// the streaming, as a phone might e.g. reject a command, or not have connected the profile
+///
+/// NOTE: The profiles are not split into services with their own lifecycles, as they all borrow
+/// the one `BtDriver`, i.e. are initialized and torn down together with its Bluedroid stack
+fn hfp_failed(result: Result<(), impl Display>) {
+    profile_failed(Profile::Hfp, result);
+}
+
+fn avrcp_failed(result: Result<(), impl Display>) {
+    profile_failed(Profile::Avrcp, result);
+}
+
+fn a2dp_failed(result: Result<(), impl Display>) {
+    profile_failed(Profile::A2dp, result);
+}
+
+fn profile_failed(profile: Profile, result: Result<(), impl Display>) {
+    if let Err(err) = result {
+        warn!("{:?} command failed: {}", profile, err);
+    }
+}
+
+/// Three-way calling (AT+CHLD), which esp-idf-svc does not wrap
+fn call_hold(chld: esp_hf_chld_type_t) -> Result<(), Error> {
+    esp!(unsafe { esp_hf_client_send_chld_cmd(chld, 0) })?;
+
+    Ok(())
+}
+
+// NOTE: esp-idf-svc does not surface the player application setting change notifications,
+// so the settings on the bus are the ones last set from here, rather than read back
+fn set_player_value(attr: u32, value: u32) -> Result<(), Error> {
+    esp!(unsafe { esp_avrc_ct_send_set_player_value_cmd(0, attr as _, value as _) })?;
+
+    Ok(())
+}
+
 fn request_info<'d, M>(avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>)
 where
     M: BtClassicEnabled,
 {
     // TODO: Do it based on available capabilities
 
-    avrcc
-        .register_notification(1, NotificationType::PlaybackPosition, 1000)
-        .unwrap();
-    avrcc
-        .register_notification(2, NotificationType::Playback, 0)
-        .unwrap();
-    avrcc
-        .register_notification(3, NotificationType::TrackChanged, 0)
-        .unwrap();
-    avrcc
-        .request_metadata(
-            4,
-            MetadataId::Title | MetadataId::Artist | MetadataId::Album | MetadataId::PlayingTime,
-        )
-        .unwrap();
-}
+    // NOTE: The next track in the playback queue (AVRCP 1.6 NowPlaying folder) cannot be fetched,
+    // as the ESP-IDF AVRCP controller does not support the browsing channel
 
-fn set_text<const N: usize>(buf: &mut heapless::String<N>, text: &str) {
-    buf.clear();
+    avrcp_failed(avrcc.register_notification(1, NotificationType::PlaybackPosition, 1000));
+    avrcp_failed(avrcc.register_notification(2, NotificationType::Playback, 0));
+    avrcp_failed(avrcc.register_notification(3, NotificationType::TrackChanged, 0));
 
-    for c in text.chars() {
-        if buf.push(c).is_err() {
-            buf.pop();
-            buf.pop();
-            buf.pop();
+    // Left to `MetadataRetry` if rejected, as some phones do right after connecting
+    avrcp_failed(request_metadata(avrcc));
+}
 
-            let _ = buf.push_str("...");
-        }
-    }
+fn request_metadata<'d, M>(avrcc: &EspAvrcc<'d, M, &BtDriver<'d, M>>) -> Result<(), Error>
+where
+    M: BtClassicEnabled,
+{
+    avrcc.request_metadata(
+        4,
+        MetadataId::Title | MetadataId::Artist | MetadataId::Album | MetadataId::PlayingTime,
+    )?;
+
+    Ok(())
 }
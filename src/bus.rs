@@ -5,25 +5,82 @@ use esp_idf_svc::hal::task::embassy_sync::EspRawMutex;
 use crate::{
     can::message::SteeringWheelButton,
     service::{ServiceLifecycle, System},
-    signal::{BroadcastSignal, Receiver, StatefulBroadcastSignal, StatefulReceiver},
+    signal::{
+        self, BroadcastSignal, Queue, QueueReceiver, Receiver, StatefulBroadcastSignal,
+        StatefulReceiver,
+    },
 };
 
 use self::{
-    bt::{AudioState, BtCommand, BtState, PhoneCallInfo, TrackInfo},
-    can::{DisplayText, RadioState},
+    bt::{
+        AudioState, Bonded, BtCommand, BtState, CodecInfo, HfpIndicators, Identity, LinkQuality,
+        PhoneCallInfo, Takeover, TrackInfo,
+    },
+    can::{CarClock, DisplayText, Notification, RadioState, RawFrame, Vehicle},
+    diag::{Diagnostic, Thermal},
 };
 
-pub type DisplayString = heapless::String<32>;
+/// Track metadata (song, artist, album), as received from the phone
+pub type TrackString = heapless::String<64>;
 
+/// Text destined for the bus displays, which further split it into CAN chunks
+pub type DisplayString = heapless::String<48>;
+
+/// Characters per line of the radio display, of the radio variant the firmware is built for
+///
+/// NOTE: Not detected at runtime, as none of the decoded frames carries it (the unit status has
+/// no variant, and which PROXI bits encode the radio is not known)
+pub const RADIO_WIDTH: usize = if cfg!(feature = "radio-8") {
+    8
+} else if cfg!(feature = "radio-16") {
+    16
+} else {
+    12
+};
+
+/// Capacity of the radio display text, which the radio scrolls if longer than a line.
+/// Four lines' worth, so that a scroll through the text takes as long with any width
+pub const RADIO_DISPLAY_LEN: usize = RADIO_WIDTH * 4;
+
+/// Capacity of the cockpit (cluster) display text, which is not scrolled
+pub const COCKPIT_DISPLAY_LEN: usize = 13;
+
+const ELLIPSIS: &str = "...";
+
+/// Copies `text` into `buf`, ending it with an ellipsis if it does not fit
+pub fn set_text<const N: usize>(buf: &mut heapless::String<N>, text: &str) {
+    buf.clear();
+
+    for c in text.chars() {
+        if buf.push(c).is_err() {
+            truncate(buf);
+            break;
+        }
+    }
+}
+
+/// Ends a string which did not fit in its buffer with an ellipsis
+pub fn truncate<const N: usize>(buf: &mut heapless::String<N>) {
+    while buf.len() + ELLIPSIS.len() > N && buf.pop().is_some() {}
+
+    let _ = buf.push_str(ELLIPSIS);
+}
+
+// NOTE: With the `serde` feature the messages below can be serialized for the telemetry, yet
+// a desktop viewer cannot depend on this crate because of ESP-IDF. Sharing the definitions
+// needs them split into a crate of their own, along with `Service` and `set_text`
 pub mod bt {
-    use super::DisplayString;
+    use super::{DisplayString, TrackString};
 
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum BtState {
         Uninitialized,
         Initialized,
         Paired,
         Connected,
+        /// The last pairing attempt failed, e.g. as it was refused
+        PairFailed,
     }
 
     impl BtState {
@@ -33,6 +90,7 @@ pub mod bt {
     }
 
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum AudioState {
         Uninitialized,
         Initialized,
@@ -52,15 +110,20 @@ pub mod bt {
     }
 
     #[derive(Debug, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct TrackInfo {
         pub version: u32,
         pub state: AudioTrackState,
-        pub artist: DisplayString,
-        pub album: DisplayString,
-        pub song: DisplayString,
+        pub artist: TrackString,
+        pub album: TrackString,
+        pub song: TrackString,
         pub offset: core::time::Duration,
         pub duration: core::time::Duration,
         pub paused: bool,
+        /// The phone playing the track
+        pub device: Option<[u8; 6]>,
+        /// Kept across tracks, unlike the rest
+        pub settings: PlayerSettings,
     }
 
     impl TrackInfo {
@@ -68,12 +131,14 @@ pub mod bt {
             Self {
                 version: 0,
                 state: AudioTrackState::Uninitialized,
-                artist: DisplayString::new(),
-                album: DisplayString::new(),
-                song: DisplayString::new(),
+                artist: TrackString::new(),
+                album: TrackString::new(),
+                song: TrackString::new(),
                 offset: core::time::Duration::from_secs(0),
                 duration: core::time::Duration::from_secs(0),
                 paused: false,
+                device: None,
+                settings: PlayerSettings::new(),
             }
         }
 
@@ -87,7 +152,50 @@ pub mod bt {
         }
     }
 
+    /// The AVRCP player application settings of the phone, and how its volume is controlled
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct PlayerSettings {
+        pub shuffle: bool,
+        /// Repeating all tracks
+        pub repeat: bool,
+        pub volume: VolumeControl,
+    }
+
+    impl PlayerSettings {
+        pub const fn new() -> Self {
+            Self {
+                shuffle: false,
+                repeat: false,
+                volume: VolumeControl::Local,
+            }
+        }
+    }
+
+    /// How the wheel volume keys reach the phone, remembered per phone
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum VolumeControl {
+        /// Not at all, only the radio adjusts its volume
+        Local,
+        /// As AVRCP VolumeUp / VolumeDown passthrough keys, for phones ignoring the absolute volume
+        Passthrough,
+        /// As AVRCP absolute volume
+        Absolute,
+    }
+
+    impl VolumeControl {
+        pub fn next(&self) -> Self {
+            match self {
+                Self::Local => Self::Passthrough,
+                Self::Passthrough => Self::Absolute,
+                Self::Absolute => Self::Local,
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum AudioTrackState {
         Uninitialized,
         Initialized,
@@ -107,11 +215,16 @@ pub mod bt {
     }
 
     #[derive(Debug, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct PhoneCallInfo {
         pub version: u32,
         pub state: PhoneCallState,
         pub phone: DisplayString,
         pub duration: core::time::Duration,
+        /// The caller of the second call, while it is waiting to be answered
+        pub waiting: DisplayString,
+        /// The phone connected over HFP, which need not be the one streaming audio
+        pub device: Option<[u8; 6]>,
     }
 
     impl PhoneCallInfo {
@@ -121,31 +234,223 @@ pub mod bt {
                 state: PhoneCallState::Idle,
                 phone: DisplayString::new(),
                 duration: core::time::Duration::from_secs(0),
+                waiting: DisplayString::new(),
+                device: None,
             }
         }
 
         pub fn reset(&mut self) {
             self.phone.clear();
             self.duration = core::time::Duration::from_secs(0);
+            self.waiting.clear();
+        }
+    }
+
+    /// The raw HFP (CIEV) indicators of the phone, as opposed to the cooked `PhoneCallInfo`
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct HfpIndicators {
+        pub version: u32,
+        pub call: bool,
+        /// 0 - none, 1 - incoming, 2 - outgoing, 3 - outgoing alerting
+        pub call_setup: u8,
+        /// 0 - none, 1 - held and active, 2 - held only
+        pub call_held: u8,
+        pub service: bool,
+        pub roaming: bool,
+        /// 0 - 5
+        pub signal: u8,
+        /// 0 - 5
+        pub battery: u8,
+    }
+
+    impl HfpIndicators {
+        pub const fn new() -> Self {
+            Self {
+                version: 0,
+                call: false,
+                call_setup: 0,
+                call_held: 0,
+                service: false,
+                roaming: false,
+                signal: 0,
+                battery: 0,
+            }
         }
     }
 
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum PhoneCallState {
         Idle,
         Dialing,
         DialingAlerting,
         Ringing,
         CallActive,
+        /// A second call is coming in during the active one
+        CallWaiting,
+        /// One of the calls is on hold
+        OnHold,
     }
 
     impl PhoneCallState {
         pub fn is_active(&self) -> bool {
             !matches!(self, Self::Idle)
         }
+
+        /// Whether a call is already established, possibly alongside a second one
+        pub fn is_in_call(&self) -> bool {
+            matches!(self, Self::CallActive | Self::CallWaiting | Self::OnHold)
+        }
+    }
+
+    /// The PCM format negotiated for the A2DP stream
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct CodecInfo {
+        pub sample_rate: u32,
+        pub channels: u8,
+    }
+
+    impl CodecInfo {
+        pub const fn new() -> Self {
+            Self {
+                sample_rate: 44100,
+                channels: 2,
+            }
+        }
+    }
+
+    /// What to do when another phone connects while one is already connected
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum Arbitration {
+        Reject,
+        Ask,
+        Switch,
+    }
+
+    impl Arbitration {
+        pub fn next(&self) -> Self {
+            match self {
+                Self::Reject => Self::Ask,
+                Self::Ask => Self::Switch,
+                Self::Switch => Self::Reject,
+            }
+        }
+    }
+
+    impl From<u32> for Arbitration {
+        fn from(value: u32) -> Self {
+            match value {
+                0 => Self::Reject,
+                2 => Self::Switch,
+                _ => Self::Ask,
+            }
+        }
+    }
+
+    impl From<Arbitration> for u32 {
+        fn from(value: Arbitration) -> Self {
+            match value {
+                Arbitration::Reject => 0,
+                Arbitration::Ask => 1,
+                Arbitration::Switch => 2,
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Takeover {
+        pub policy: Arbitration,
+        /// A second phone is connected and waits for the driver to accept or reject it
+        pub pending: bool,
+    }
+
+    impl Takeover {
+        pub const fn new() -> Self {
+            Self {
+                policy: Arbitration::Ask,
+                pending: false,
+            }
+        }
+    }
+
+    /// How the adapter presents itself to the phones
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Identity {
+        /// Tells apart the adapters of several cars parked next to each other ("Fiat 2"), 0 for none
+        pub suffix: u8,
+        pub pin: [u8; 4],
+    }
+
+    impl Identity {
+        pub const MAX_SUFFIX: u8 = 9;
+
+        pub const fn new() -> Self {
+            Self {
+                suffix: 0,
+                pin: *b"1234",
+            }
+        }
+
+        pub fn pin(&self) -> &str {
+            core::str::from_utf8(&self.pin).unwrap_or("1234")
+        }
+    }
+
+    /// The quality of the link to the connected phone, as the RSSI relative to the golden
+    /// receive power range (0 while within it)
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct LinkQuality {
+        pub version: u32,
+        pub device: Option<[u8; 6]>,
+        pub rssi_delta: i8,
+        /// Samples below the threshold since boot, to correlate with audio dropouts
+        pub weak_samples: u32,
+    }
+
+    impl LinkQuality {
+        pub const WEAK_RSSI_DELTA: i8 = -10;
+
+        pub const fn new() -> Self {
+            Self {
+                version: 0,
+                device: None,
+                rssi_delta: 0,
+                weak_samples: 0,
+            }
+        }
+
+        pub fn is_weak(&self) -> bool {
+            self.device.is_some() && self.rssi_delta < Self::WEAK_RSSI_DELTA
+        }
+    }
+
+    pub const MAX_BONDED: usize = 8;
+
+    /// The devices bonded with the adapter, as persisted by the Bluetooth stack
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Bonded {
+        pub version: u32,
+        pub devices: heapless::Vec<[u8; 6], MAX_BONDED>,
+    }
+
+    impl Bonded {
+        pub const fn new() -> Self {
+            Self {
+                version: 0,
+                devices: heapless::Vec::new(),
+            }
+        }
     }
 
     #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum BtCommand {
         Answer,
         Reject,
@@ -154,6 +459,40 @@ pub mod bt {
         Resume,
         NextTrack,
         PreviousTrack,
+        /// Unlike `Pause`, makes the phone release the audio focus to other apps
+        Stop,
+        ToggleLowLatency,
+        ToggleShuffle,
+        ToggleRepeat,
+        BlockDevice,
+        UnblockAll,
+        SetArbitration(Arbitration),
+        AcceptTakeover,
+        RejectTakeover,
+        Unpair([u8; 6]),
+        SetIdentity(Identity),
+        UnpairAll,
+        /// Make the adapter discoverable for another pairing window
+        OpenPairing,
+        /// Put the controller in the RF test (DUT) mode, honoured only in service mode
+        TestMode,
+        /// The car is about to power down the adapter
+        PrepareSleep,
+        VoiceAssistant,
+        Dtmf(char),
+        /// Put the active call on hold and answer the waiting one
+        Hold,
+        /// Switch between the active and the held call
+        Swap,
+        /// Join the active and the held call into a conference
+        Merge,
+        RejectWaiting,
+        /// Set the call volume (0 - 15) locally and report it to the phone
+        CallVolume(u8),
+        /// The wheel volume keys, applied as per the volume control of the phone
+        VolumeUp,
+        VolumeDown,
+        CycleVolumeControl,
     }
 }
 
@@ -161,8 +500,10 @@ pub mod can {
     use core::fmt::Write;
 
     use super::bt::{PhoneCallInfo, TrackInfo};
+    use super::{set_text, truncate, DisplayString};
 
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum RadioState {
         Unknown,
         Fm,
@@ -177,8 +518,11 @@ pub mod can {
     }
 
     #[derive(Debug, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct DisplayText<const N: usize> {
         pub version: u32,
+        /// Set by the adapter's own menu and prompts, so that the cockpit shows the text
+        /// with the menu layout (0x06 rather than 0x0a). Ignored for the radio
         pub menu: bool,
         pub text: heapless::String<N>,
     }
@@ -198,6 +542,13 @@ pub mod can {
             self.text.clear();
         }
 
+        /// Plain text, not shown as a menu unless `menu` is set anew
+        pub fn update_text(&mut self, text: &str) {
+            self.version += 1;
+            self.menu = false;
+            set_text(&mut self.text, text);
+        }
+
         pub fn update_phone_info(&mut self, phone: &PhoneCallInfo) {
             self.version += 1;
             self.text.clear();
@@ -207,106 +558,504 @@ pub mod can {
             let mins = secs / 60;
             let secs = secs % 60;
 
-            let _ = write!(&mut self.text, "{} {:02}:{:02}", phone.phone, mins, secs);
+            let result = if phone.waiting.is_empty() {
+                write!(&mut self.text, "{} {:02}:{:02}", phone.phone, mins, secs)
+            } else {
+                write!(
+                    &mut self.text,
+                    "{} {:02}:{:02} +{}",
+                    phone.phone, mins, secs, phone.waiting
+                )
+            };
+
+            if result.is_err() {
+                truncate(&mut self.text);
+            }
         }
 
         pub fn update_track_info(&mut self, track: &TrackInfo) {
             self.version += 1;
             self.text.clear();
 
-            let secs = track.offset.as_secs();
+            let mut position = heapless::String::<16>::new();
 
-            let mins = secs / 60;
-            let secs = secs % 60;
+            write_time(&mut position, track.offset);
 
-            let _ = write!(
+            // Phones not reporting the playing time leave the duration at zero
+            if track.duration.as_secs() > 0 {
+                let _ = position.push('/');
+                write_time(&mut position, track.duration);
+            }
+
+            if write!(
                 &mut self.text,
-                "{};{};{:02}:{:02}",
-                track.album, track.artist, mins, secs
-            );
+                "{};{};{}",
+                track.album, track.artist, position
+            )
+            .is_ok()
+            {
+                return;
+            }
+
+            // Narrow displays rather lose the album than the artist and the position
+            self.text.clear();
+
+            if write!(&mut self.text, "{};{}", track.artist, position).is_err() {
+                truncate(&mut self.text);
+            }
+        }
+    }
+
+    fn write_time<const N: usize>(text: &mut heapless::String<N>, time: core::time::Duration) {
+        let secs = time.as_secs();
+
+        let _ = write!(text, "{:02}:{:02}", secs / 60, secs % 60);
+    }
+
+    /// A frame sent on the bus as is, e.g. from the debug console
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct RawFrame {
+        pub id: u32,
+        pub data: heapless::Vec<u8, 8>,
+    }
+
+    /// The car the adapter is installed in, as identified by its PROXI configuration.
+    /// The audio gains are tuned per car
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Vehicle {
+        pub proxi: Option<[u8; 6]>,
+    }
+
+    impl Vehicle {
+        pub const fn new() -> Self {
+            Self { proxi: None }
+        }
+    }
+
+    /// The station the radio is tuned to, while its source is FM
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct FmStation {
+        /// As reported by the radio, `None` unless the source is FM
+        pub frequency: Option<u16>,
+        /// The RDS name, if broadcast
+        pub name: DisplayString,
+    }
+
+    impl FmStation {
+        pub const fn new() -> Self {
+            Self {
+                frequency: None,
+                name: DisplayString::new(),
+            }
+        }
+    }
+
+    /// The date and time of the car's own clock
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct CarClock {
+        pub year: u16,
+        pub month: u8,
+        pub day: u8,
+        pub hour: u8,
+        pub minute: u8,
+    }
+
+    /// A transient message temporarily taking over the display
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Notification {
+        pub version: u32,
+        pub text: DisplayString,
+        pub duration: core::time::Duration,
+    }
+
+    impl Notification {
+        pub const fn new() -> Self {
+            Self {
+                version: 0,
+                text: DisplayString::new(),
+                duration: core::time::Duration::from_secs(0),
+            }
+        }
+
+        pub fn post(&mut self, text: &str, duration: core::time::Duration) {
+            self.version += 1;
+            set_text(&mut self.text, text);
+
+            self.duration = duration;
+        }
+    }
+}
+
+pub mod diag {
+    use embassy_time::Duration;
+
+    use super::Service;
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum BootReason {
+        PowerOn,
+        Brownout,
+        Watchdog,
+        Panic,
+        Other,
+    }
+
+    impl BootReason {
+        /// Brown-outs and watchdog resets are typically caused by the supply rail
+        /// collapsing while the engine is cranking
+        pub fn is_unclean(&self) -> bool {
+            matches!(self, Self::Brownout | Self::Watchdog)
+        }
+
+        /// Resets the firmware itself is likely at fault for, though a watchdog might also
+        /// fire while cranking
+        pub fn is_crash(&self) -> bool {
+            matches!(self, Self::Panic | Self::Watchdog)
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum Diagnostic {
+        Boot(BootReason),
+        SlowPoll {
+            service: Service,
+            #[cfg_attr(feature = "serde", serde(with = "super::micros"))]
+            max_poll: Duration,
+        },
+        Overheat {
+            celsius: i16,
+        },
+        /// No buffer of the arena was free when a service needed one
+        ArenaExhausted {
+            len: usize,
+        },
+        /// Downloading or verifying a firmware update failed
+        UpdateFailed,
+        /// The CAN RX queue overflowed that many times within a second
+        CanOverflow {
+            count: u16,
+        },
+        /// Transmissions retried after a transient error, and frames dropped as they kept failing,
+        /// within a second
+        CanTxErrors {
+            retries: u16,
+            dropped: u16,
+        },
+        /// The CAN driver did not become error-active, i.e. the bus timing is likely wrong
+        CanNotErrorActive {
+            tx_errors: u32,
+            rx_errors: u32,
+        },
+        /// No CAN frame was received after the start, so the radio is assumed on BT
+        BenchMode,
+        /// The battery dropped that low with the engine off, so the services were stopped
+        LowVoltage {
+            millivolts: u16,
+        },
+        /// CPU usage in permille, indexed by `Service`
+        CpuUsage([u16; MAX_SERVICES]),
+        /// The CAN controller went bus-off, i.e. it transmitted so many erroneous frames that it
+        /// disconnected itself from the bus
+        CanBusOff {
+            tx_errors: u32,
+        },
+        /// The Bluetooth controller failed to initialize, with that ESP-IDF error code
+        BtControllerFailed {
+            code: i32,
+        },
+        /// The service ended with that ESP-IDF error code and is not restarted until the next boot
+        ServiceFailed {
+            service: Service,
+            code: i32,
+        },
+    }
+
+    impl Diagnostic {
+        pub fn severity(&self) -> Severity {
+            match self {
+                Self::Boot(reason) if reason.is_unclean() => Severity::Warn,
+                Self::Boot(_) | Self::BenchMode | Self::CpuUsage(_) => Severity::Info,
+                Self::SlowPoll { .. }
+                | Self::ArenaExhausted { .. }
+                | Self::CanOverflow { .. }
+                | Self::CanTxErrors { .. }
+                | Self::LowVoltage { .. } => Severity::Warn,
+                Self::Overheat { .. }
+                | Self::UpdateFailed
+                | Self::CanNotErrorActive { .. }
+                | Self::CanBusOff { .. }
+                | Self::BtControllerFailed { .. }
+                | Self::ServiceFailed { .. } => Severity::Critical,
+            }
+        }
+    }
+
+    pub const MAX_SERVICES: usize = 16;
+
+    /// Info and warnings are only logged, while critical faults are also shown to the driver
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub enum Severity {
+        Info,
+        Warn,
+        Critical,
+    }
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Thermal {
+        pub celsius: i16,
+        pub peak: i16,
+        /// Nonessential activity should be reduced
+        pub throttled: bool,
+    }
+
+    impl Thermal {
+        pub const fn new() -> Self {
+            Self {
+                celsius: 0,
+                peak: i16::MIN,
+                throttled: false,
+            }
         }
     }
 }
 
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UpdateRequest {
+    /// Only report whether a newer firmware is available, then return to the normal mode
+    Check,
+    Install,
+}
+
 #[derive(Debug, EnumSetType)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Service {
     Bt,
     AudioMux,
     Microphone,
     Speakers,
     Can,
+    /// Drives the cockpit display as well, as both are fed by the same display arbiter
     RadioDisplay,
-    CockpitDisplay,
     Commands,
     Wifi,
+    Diagnostics,
+    Storage,
+    Thermal,
+    Presence,
+}
+
+impl Service {
+    pub const COUNT: usize = Self::Presence as usize + 1;
+
+    /// The subscribers of the topics all services receive
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::Bt,
+        Self::AudioMux,
+        Self::Microphone,
+        Self::Speakers,
+        Self::Can,
+        Self::RadioDisplay,
+        Self::Commands,
+        Self::Wifi,
+        Self::Diagnostics,
+        Self::Storage,
+        Self::Thermal,
+        Self::Presence,
+    ];
+
+    /// Stops compiling once a service is added, so that `COUNT` is not forgotten
+    const fn is_last(&self) -> bool {
+        match self {
+            Self::Bt
+            | Self::AudioMux
+            | Self::Microphone
+            | Self::Speakers
+            | Self::Can
+            | Self::RadioDisplay
+            | Self::Commands
+            | Self::Wifi
+            | Self::Diagnostics
+            | Self::Storage
+            | Self::Thermal => false,
+            Self::Presence => true,
+        }
+    }
+}
+
+const _: () = assert!(Service::Presence.is_last());
+const _: () = assert!(Service::COUNT <= diag::MAX_SERVICES);
+const _: () = assert!(signal::distinct(&Service::ALL));
+
+/// `embassy_time::Duration` has no serde support of its own
+#[cfg(feature = "serde")]
+mod micros {
+    use embassy_time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_micros())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_micros(u64::deserialize(deserializer)?))
+    }
 }
 
-pub struct Bus {
+/// The topics of all services
+///
+/// `R` and `C` are the text capacities of the radio and of the cockpit displays, so that
+/// the displays' formatters size - and break - their texts for the displays of the car
+pub struct Bus<const R: usize = RADIO_DISPLAY_LEN, const C: usize = COCKPIT_DISPLAY_LEN> {
     pub system: StatefulBroadcastSignal<NoopRawMutex, System>,
     pub bt: BroadcastSignal<EspRawMutex, BtState>,
     pub audio: BroadcastSignal<EspRawMutex, AudioState>,
+    pub audio_codec: StatefulBroadcastSignal<EspRawMutex, CodecInfo>,
     pub audio_track: StatefulBroadcastSignal<EspRawMutex, TrackInfo>,
     pub phone: BroadcastSignal<EspRawMutex, AudioState>,
     pub phone_call: StatefulBroadcastSignal<EspRawMutex, PhoneCallInfo>,
+    pub hfp_indicators: StatefulBroadcastSignal<EspRawMutex, HfpIndicators>,
+    pub takeover: StatefulBroadcastSignal<EspRawMutex, Takeover>,
+    pub bonded: StatefulBroadcastSignal<EspRawMutex, Bonded>,
+    pub identity: StatefulBroadcastSignal<EspRawMutex, Identity>,
+    pub link: StatefulBroadcastSignal<EspRawMutex, LinkQuality>,
     pub button_commands: BroadcastSignal<NoopRawMutex, BtCommand>,
     pub radio_commands: BroadcastSignal<NoopRawMutex, BtCommand>,
     pub radio: BroadcastSignal<NoopRawMutex, RadioState>,
-    pub buttons: BroadcastSignal<NoopRawMutex, EnumSet<SteeringWheelButton>>,
-    pub cockpit_display: StatefulBroadcastSignal<NoopRawMutex, DisplayText<13>>,
-    pub radio_display: StatefulBroadcastSignal<NoopRawMutex, DisplayText<32>>,
-    pub update: BroadcastSignal<NoopRawMutex, ()>,
+    /// The volume of the radio, from 0 to `can::message::MAX_RADIO_VOLUME`
+    ///
+    /// NOTE: The adapter plays no chimes or prompts of its own yet, which would be scaled with it
+    pub radio_volume: BroadcastSignal<NoopRawMutex, u8, 1>,
+    pub buttons: BroadcastSignal<NoopRawMutex, EnumSet<SteeringWheelButton>, 1>,
+    /// The vehicle speed in km/h, on change
+    pub speed: BroadcastSignal<NoopRawMutex, u16, 1>,
+    /// Whether the parking sensors are active, on change
+    pub parking: BroadcastSignal<NoopRawMutex, bool, 1>,
+    pub cockpit_display: StatefulBroadcastSignal<NoopRawMutex, DisplayText<C>>,
+    pub radio_display: StatefulBroadcastSignal<NoopRawMutex, DisplayText<R>>,
+    pub notification: StatefulBroadcastSignal<NoopRawMutex, Notification>,
+    pub vehicle: StatefulBroadcastSignal<NoopRawMutex, Vehicle>,
+    /// `None` until the car broadcast its clock. Not updated while the CAN driver is filtered
+    pub car_clock: StatefulBroadcastSignal<NoopRawMutex, Option<CarClock>>,
+    pub fm_station: StatefulBroadcastSignal<NoopRawMutex, FmStation>,
+    pub can_frames: BroadcastSignal<NoopRawMutex, RawFrame, 1>,
+    pub update: BroadcastSignal<NoopRawMutex, UpdateRequest, 1>,
+    pub diagnostics: Queue<EspRawMutex, Diagnostic>,
+    pub thermal: StatefulBroadcastSignal<NoopRawMutex, Thermal>,
 }
 
-impl Bus {
+impl<const R: usize, const C: usize> Bus<R, C> {
     pub const fn new() -> Self {
         Self {
             system: StatefulBroadcastSignal::new(System::new()),
             bt: BroadcastSignal::new(),
             audio: BroadcastSignal::new(),
+            audio_codec: StatefulBroadcastSignal::new(CodecInfo::new()),
             audio_track: StatefulBroadcastSignal::new(TrackInfo::new()),
             phone: BroadcastSignal::new(),
             phone_call: StatefulBroadcastSignal::new(PhoneCallInfo::new()),
+            hfp_indicators: StatefulBroadcastSignal::new(HfpIndicators::new()),
+            takeover: StatefulBroadcastSignal::new(Takeover::new()),
+            bonded: StatefulBroadcastSignal::new(Bonded::new()),
+            identity: StatefulBroadcastSignal::new(Identity::new()),
+            link: StatefulBroadcastSignal::new(LinkQuality::new()),
             button_commands: BroadcastSignal::new(),
             radio_commands: BroadcastSignal::new(),
             radio: BroadcastSignal::new(),
-            buttons: BroadcastSignal::new(),
+            radio_volume: BroadcastSignal::subscribed([Service::RadioDisplay]),
+            buttons: BroadcastSignal::subscribed([Service::Commands]),
+            speed: BroadcastSignal::subscribed([Service::Commands]),
+            parking: BroadcastSignal::subscribed([Service::Commands]),
             cockpit_display: StatefulBroadcastSignal::new(DisplayText::new()),
             radio_display: StatefulBroadcastSignal::new(DisplayText::new()),
-            update: BroadcastSignal::new(),
+            notification: StatefulBroadcastSignal::new(Notification::new()),
+            vehicle: StatefulBroadcastSignal::new(Vehicle::new()),
+            car_clock: StatefulBroadcastSignal::new(None),
+            fm_station: StatefulBroadcastSignal::new(FmStation::new()),
+            can_frames: BroadcastSignal::subscribed([Service::Can]),
+            update: BroadcastSignal::subscribed([Service::Wifi]),
+            diagnostics: Queue::new(Service::Diagnostics),
+            thermal: StatefulBroadcastSignal::new(Thermal::new()),
         }
     }
 
-    pub fn subscription(&self, service: Service) -> BusSubscription<'_> {
+    pub fn subscription(&self, service: Service) -> BusSubscription<'_, R, C> {
         BusSubscription {
             service: ServiceLifecycle::new(service, &self.system),
             bt: self.bt.receiver(service),
             audio: self.audio.receiver(service),
+            audio_codec: self.audio_codec.receiver(service),
             audio_track: self.audio_track.receiver(service),
             phone: self.phone.receiver(service),
             phone_call: self.phone_call.receiver(service),
+            hfp_indicators: self.hfp_indicators.receiver(service),
+            takeover: self.takeover.receiver(service),
+            bonded: self.bonded.receiver(service),
+            identity: self.identity.receiver(service),
+            link: self.link.receiver(service),
             button_commands: self.button_commands.receiver(service),
             radio_commands: self.radio_commands.receiver(service),
             radio: self.radio.receiver(service),
+            radio_volume: self.radio_volume.receiver(service),
             buttons: self.buttons.receiver(service),
+            speed: self.speed.receiver(service),
+            parking: self.parking.receiver(service),
             cockpit_display: self.cockpit_display.receiver(service),
             radio_display: self.radio_display.receiver(service),
+            notification: self.notification.receiver(service),
+            vehicle: self.vehicle.receiver(service),
+            car_clock: self.car_clock.receiver(service),
+            fm_station: self.fm_station.receiver(service),
+            can_frames: self.can_frames.receiver(service),
             update: self.update.receiver(service),
+            diagnostics: self.diagnostics.receiver(service),
+            thermal: self.thermal.receiver(service),
         }
     }
 }
 
-pub struct BusSubscription<'a> {
+pub struct BusSubscription<
+    'a,
+    const R: usize = RADIO_DISPLAY_LEN,
+    const C: usize = COCKPIT_DISPLAY_LEN,
+> {
     pub service: ServiceLifecycle<'a, NoopRawMutex>,
     pub bt: Receiver<'a, EspRawMutex, BtState>,
     pub audio: Receiver<'a, EspRawMutex, AudioState>,
+    pub audio_codec: StatefulReceiver<'a, EspRawMutex, CodecInfo>,
     pub audio_track: StatefulReceiver<'a, EspRawMutex, TrackInfo>,
     pub phone: Receiver<'a, EspRawMutex, AudioState>,
     pub phone_call: StatefulReceiver<'a, EspRawMutex, PhoneCallInfo>,
+    pub hfp_indicators: StatefulReceiver<'a, EspRawMutex, HfpIndicators>,
+    pub takeover: StatefulReceiver<'a, EspRawMutex, Takeover>,
+    pub bonded: StatefulReceiver<'a, EspRawMutex, Bonded>,
+    pub identity: StatefulReceiver<'a, EspRawMutex, Identity>,
+    pub link: StatefulReceiver<'a, EspRawMutex, LinkQuality>,
     pub button_commands: Receiver<'a, NoopRawMutex, BtCommand>,
     pub radio_commands: Receiver<'a, NoopRawMutex, BtCommand>,
     pub radio: Receiver<'a, NoopRawMutex, RadioState>,
+    pub radio_volume: Receiver<'a, NoopRawMutex, u8>,
     pub buttons: Receiver<'a, NoopRawMutex, EnumSet<SteeringWheelButton>>,
-    pub cockpit_display: StatefulReceiver<'a, NoopRawMutex, DisplayText<13>>,
-    pub radio_display: StatefulReceiver<'a, NoopRawMutex, DisplayText<32>>,
-    pub update: Receiver<'a, NoopRawMutex, ()>,
+    pub speed: Receiver<'a, NoopRawMutex, u16>,
+    pub parking: Receiver<'a, NoopRawMutex, bool>,
+    pub cockpit_display: StatefulReceiver<'a, NoopRawMutex, DisplayText<C>>,
+    pub radio_display: StatefulReceiver<'a, NoopRawMutex, DisplayText<R>>,
+    pub notification: StatefulReceiver<'a, NoopRawMutex, Notification>,
+    pub vehicle: StatefulReceiver<'a, NoopRawMutex, Vehicle>,
+    pub car_clock: StatefulReceiver<'a, NoopRawMutex, Option<CarClock>>,
+    pub fm_station: StatefulReceiver<'a, NoopRawMutex, FmStation>,
+    pub can_frames: Receiver<'a, NoopRawMutex, RawFrame>,
+    pub update: Receiver<'a, NoopRawMutex, UpdateRequest>,
+    pub diagnostics: QueueReceiver<'a, EspRawMutex, Diagnostic>,
+    pub thermal: StatefulReceiver<'a, NoopRawMutex, Thermal>,
 }
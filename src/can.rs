@@ -1,42 +1,155 @@
+use core::cell::Cell;
 use core::cmp::min;
 use core::pin::pin;
 
-use embassy_futures::select::{select, select3, select_slice, Either, Either3};
+use embassy_futures::select::{select, select4, select_slice, Either, Either4};
 
 use embassy_sync::{
     blocking_mutex::raw::{NoopRawMutex, RawMutex},
     signal::Signal,
 };
 
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 
 use enumset::EnumSet;
 
 use esp_idf_svc::hal::{
-    can::{AsyncCanDriver, CanConfig, Frame, OwnedAsyncCanDriver, CAN},
+    can::{
+        config::{Filter, Timing},
+        Alert, AsyncCanDriver, CanConfig, Frame, OwnedAsyncCanDriver, CAN,
+    },
     gpio::{InputPin, OutputPin},
     peripheral::Peripheral,
 };
+use esp_idf_svc::sys::{
+    esp, twai_get_status_info, twai_state_t_TWAI_STATE_BUS_OFF, twai_state_t_TWAI_STATE_RUNNING,
+    twai_status_info_t, EspError, ESP_ERR_TIMEOUT, ESP_FAIL,
+};
+
+use log::{info, warn};
 
 use crate::{
     bus::{
         bt::{AudioState, BtCommand},
-        can::{DisplayText, RadioState},
-        BusSubscription,
+        can::{CarClock, DisplayText, FmStation, RadioState, RawFrame, Vehicle},
+        diag::Diagnostic,
+        set_text, BusSubscription,
     },
+    radio_mux::{MuxAction, MuxEvent, RadioMux},
     select_spawn::SelectSpawn,
-    signal::{Receiver, Sender, StatefulReceiver},
+    signal::{QueueSender, Receiver, Sender, StatefulReceiver, StatefulSender},
+    sniffer::Sniffer,
 };
 use crate::{
     error::Error,
-    service::{ServiceLifecycle, SystemState},
+    service::{Ignition, ServiceLifecycle, SystemState},
+    storage::Storage,
+    version,
 };
 
 use self::message::{
-    BodyComputer, Bt, Display, Message, Proxi, Publisher, RadioSource, SteeringWheel,
-    SteeringWheelButton, Topic,
+    Battery, BodyComputer, Bt, DateTime, DiagStatus, Display, Door, Doors, Message, Proxi,
+    Publisher, RadioSource, RadioStation, RadioVolume, Speed, SteeringWheel, SteeringWheelButton,
+    Topic,
 };
 
+/// Decoded text of a single CAN frame
+pub type ChunkString = heapless::String<12>;
+
+/// Without a cooperating radio, the BT source is assumed to be always selected
+const STANDALONE: bool = cfg!(feature = "standalone");
+
+const VEHICLE_KEY: &str = "vehicle";
+/// The debounce time of the steering wheel buttons in ms, read whenever the driver is created
+pub const DEBOUNCE_KEY: &str = "debounce";
+
+const DEFAULT_DEBOUNCE_MS: u32 = 100;
+pub const MAX_DEBOUNCE_MS: u32 = 1000;
+
+/// The factory cluster menu sends no "closed" frame, so it is assumed closed once the
+/// instrument panel stopped writing menu text for that long
+const FACTORY_MENU_HOLD: Duration = Duration::from_secs(2);
+
+/// The minimum time between two texts sent to a display. The cockpit one only shows the menu,
+/// which should rather keep up with the button presses
+const RADIO_DISPLAY_INTERVAL: Duration = Duration::from_secs(1);
+const COCKPIT_DISPLAY_INTERVAL: Duration = Duration::from_millis(200);
+
+const OVERFLOW_WINDOW: Duration = Duration::from_secs(1);
+/// Windows in a row with overflows, after which the driver is re-created filtered
+const OVERFLOW_SUSTAINED: usize = 3;
+
+/// A driver not error-active by then is reported, as the timing likely does not match the bus
+const ERROR_ACTIVE_WITHIN: Duration = Duration::from_secs(10);
+/// The TX and RX error counters of an error-active node stay below that
+const ERROR_PASSIVE_COUNT: u32 = 128;
+
+/// The backoff of the retransmissions after a transient TX error
+const TX_RETRY_MIN: Duration = Duration::from_millis(10);
+const TX_RETRY_MAX: Duration = Duration::from_millis(160);
+/// Including the first transmission
+const TX_ATTEMPTS: usize = 5;
+/// Frames dropped in a row, after which the TX errors are considered persistent
+const TX_DROPPED_PERSISTENT: usize = 5;
+
+/// The parking sensors are considered inactive once their unit was silent for that long
+const PARKING_HOLD: Duration = Duration::from_secs(1);
+
+/// With the engine off, the services are stopped below that battery voltage, so that the adapter
+/// does not drain the battery any further, and only started again once the key is turned on
+///
+/// NOTE: Decoded off the bus, as the only ADC unit usable alongside the WiFi (ADC1) is taken
+/// by the microphone
+const LOW_VOLTAGE_MV: u16 = 11_800;
+
+/// Without any frame received by then, the adapter is assumed to be on the bench
+const BENCH_AFTER: Duration = Duration::from_secs(5);
+
+/// The bit timing of the car's bus, per vehicle profile
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BusTiming {
+    pub bitrate: u32,
+    /// In permille of the bit time
+    pub sample_point: u16,
+}
+
+impl BusTiming {
+    /// The B-CAN of the Blue&Me-era platforms (Grande Punto, 500, Panda, MiTo)
+    pub const B_CAN: Self = Self {
+        bitrate: 50_000,
+        sample_point: 800,
+    };
+
+    /// The CAN-IHS of the later platforms
+    pub const CAN_IHS: Self = Self {
+        bitrate: 125_000,
+        sample_point: 800,
+    };
+
+    /// NOTE: The HAL only offers the timing presets of ESP-IDF, which sample at 80%,
+    /// so a profile with another bitrate or sample point fails the build
+    const fn hal(&self) -> Timing {
+        match (self.bitrate, self.sample_point) {
+            (25_000, 800) => Timing::B25K,
+            (50_000, 800) => Timing::B50K,
+            (100_000, 800) => Timing::B100K,
+            (125_000, 800) => Timing::B125K,
+            (250_000, 800) => Timing::B250K,
+            (500_000, 800) => Timing::B500K,
+            (1_000_000, 800) => Timing::B1M,
+            _ => panic!("No ESP-IDF timing preset for the bitrate and sample point"),
+        }
+    }
+}
+
+const BUS_TIMING: BusTiming = if cfg!(feature = "can-ihs") {
+    BusTiming::CAN_IHS
+} else {
+    BusTiming::B_CAN
+};
+
+const HAL_TIMING: Timing = BUS_TIMING.hal();
+
 pub mod message {
     use core::iter::repeat;
     use core::num::NonZeroUsize;
@@ -45,23 +158,119 @@ pub mod message {
 
     use esp_idf_svc::hal::can::Frame;
 
-    const UNIT_BODY_COMPUTER: u16 = 0x4000;
-    const UNIT_INSTRUMENT_PANEL: u16 = 0x4003;
-    const UNIT_RADIO: u16 = 0x4005;
-    const UNIT_PARKING_SENSORS: u16 = 0x4018;
-    const UNIT_BT: u16 = 0x4021;
-
-    const TOPIC_UNITS_STATUS: u16 = 0xe09;
-    const TOPIC_PROXI: u16 = 0x1e11;
-    const TOPIC_STEERING_WHEEL: u16 = 0x0635;
-    const TOPIC_DATETIME: u16 = 0xc21;
-    const TOPIC_DISPLAY: u16 = 0xa39;
-    const TOPIC_BT: u16 = 0x631;
-    const TOPIC_RADIO_STATION: u16 = 0xa19;
-    const TOPIC_RADIO_SOURCE: u16 = 0xa11;
+    use crate::service::Ignition;
+
+    /// The unit and topic ids of a platform
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct MessageMap {
+        pub unit_body_computer: u16,
+        pub unit_instrument_panel: u16,
+        pub unit_radio: u16,
+        pub unit_parking_sensors: u16,
+        pub unit_bt: u16,
+        pub topic_units_status: u16,
+        pub topic_proxi: u16,
+        pub topic_steering_wheel: u16,
+        pub topic_datetime: u16,
+        pub topic_display: u16,
+        pub topic_bt: u16,
+        pub topic_radio_station: u16,
+        pub topic_radio_source: u16,
+        pub topic_radio_volume: u16,
+        pub topic_speed: u16,
+        pub topic_diag_status: u16,
+        pub topic_doors: u16,
+        pub topic_battery: u16,
+    }
+
+    impl MessageMap {
+        pub const GRANDE_PUNTO: Self = Self {
+            unit_body_computer: 0x4000,
+            unit_instrument_panel: 0x4003,
+            unit_radio: 0x4005,
+            unit_parking_sensors: 0x4018,
+            unit_bt: 0x4021,
+            topic_units_status: 0xe09,
+            topic_proxi: 0x1e11,
+            topic_steering_wheel: 0x0635,
+            topic_datetime: 0xc21,
+            topic_display: 0xa39,
+            topic_bt: 0x631,
+            topic_radio_station: 0xa19,
+            topic_radio_source: 0xa11,
+            topic_radio_volume: 0xa29,
+            topic_speed: 0xa18,
+            topic_diag_status: 0x1e39,
+            // NOTE: Not verified in a car yet, to be confirmed with the sniffer
+            topic_doors: 0x621,
+            // NOTE: Not verified in a car yet, to be confirmed with the sniffer
+            topic_battery: 0xe19,
+        };
+
+        // NOTE: The other Blue&Me-era platforms are not verified in a car yet, and are assumed
+        // to share the ids of the Grande Punto until the sniffer shows otherwise
+        pub const FIAT_500: Self = Self::GRANDE_PUNTO;
+        pub const PANDA: Self = Self::GRANDE_PUNTO;
+        pub const MITO: Self = Self::GRANDE_PUNTO;
+    }
+
+    /// The map of the platform the firmware is built for
+    const MAP: MessageMap = if cfg!(feature = "model-500") {
+        MessageMap::FIAT_500
+    } else if cfg!(feature = "model-panda") {
+        MessageMap::PANDA
+    } else if cfg!(feature = "model-mito") {
+        MessageMap::MITO
+    } else {
+        MessageMap::GRANDE_PUNTO
+    };
+
+    const UNIT_BODY_COMPUTER: u16 = MAP.unit_body_computer;
+    const UNIT_INSTRUMENT_PANEL: u16 = MAP.unit_instrument_panel;
+    const UNIT_RADIO: u16 = MAP.unit_radio;
+    const UNIT_PARKING_SENSORS: u16 = MAP.unit_parking_sensors;
+    const UNIT_BT: u16 = MAP.unit_bt;
+
+    const TOPIC_UNITS_STATUS: u16 = MAP.topic_units_status;
+    const TOPIC_PROXI: u16 = MAP.topic_proxi;
+    const TOPIC_STEERING_WHEEL: u16 = MAP.topic_steering_wheel;
+    const TOPIC_DATETIME: u16 = MAP.topic_datetime;
+    const TOPIC_DISPLAY: u16 = MAP.topic_display;
+    const TOPIC_BT: u16 = MAP.topic_bt;
+    const TOPIC_RADIO_STATION: u16 = MAP.topic_radio_station;
+    const TOPIC_RADIO_SOURCE: u16 = MAP.topic_radio_source;
+    const TOPIC_RADIO_VOLUME: u16 = MAP.topic_radio_volume;
+    const TOPIC_SPEED: u16 = MAP.topic_speed;
+    const TOPIC_DIAG_STATUS: u16 = MAP.topic_diag_status;
+    const TOPIC_DOORS: u16 = MAP.topic_doors;
+    const TOPIC_BATTERY: u16 = MAP.topic_battery;
+
+    pub const MAX_RADIO_VOLUME: u8 = 30;
 
     const CHAR_MAP: &str = "0123456789.ABCDEFGHIJKLMNOPQRSTUVWXYZ%% %ij%%%%%%_%%?@!+-:/#*%;";
 
+    /// The 6-bit code of each ASCII character, i.e. its first position in `CHAR_MAP` plus one,
+    /// and the code of the space for the characters not in the map
+    const CHAR_CODES: [u8; 128] = {
+        let map = CHAR_MAP.as_bytes();
+
+        let mut space = 0;
+        while map[space] != b' ' {
+            space += 1;
+        }
+
+        let mut codes = [space as u8 + 1; 128];
+
+        // Backwards, so that the first of the duplicates wins
+        let mut index = map.len();
+        while index > 0 {
+            index -= 1;
+            codes[map[index] as usize] = index as u8 + 1;
+        }
+
+        codes
+    };
+
     pub type FramePayload = heapless::Vec<u8, 8>;
 
     #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -110,6 +319,11 @@ pub mod message {
         Bt(Bt<'a>),
         RadioStation(RadioStation<'a>),
         RadioSource(RadioSource<'a>),
+        RadioVolume(RadioVolume<'a>),
+        Speed(Speed<'a>),
+        DiagStatus(DiagStatus<'a>),
+        Doors(Doors<'a>),
+        Battery(Battery<'a>),
         Unknown { topic: u16, payload: &'a [u8] },
     }
 
@@ -124,6 +338,11 @@ pub mod message {
                 TOPIC_DISPLAY => Topic::Display((payload, str_buf).into()),
                 TOPIC_RADIO_STATION => Topic::RadioStation((payload, str_buf).into()),
                 TOPIC_RADIO_SOURCE => Topic::RadioSource(payload.into()),
+                TOPIC_RADIO_VOLUME => Topic::RadioVolume(payload.into()),
+                TOPIC_SPEED => Topic::Speed(payload.into()),
+                TOPIC_DIAG_STATUS => Topic::DiagStatus(payload.into()),
+                TOPIC_DOORS => Topic::Doors(payload.into()),
+                TOPIC_BATTERY => Topic::Battery(payload.into()),
                 other => Topic::Unknown {
                     topic: other,
                     payload,
@@ -143,6 +362,11 @@ pub mod message {
                 Topic::Display(payload) => (TOPIC_DISPLAY, payload.into()),
                 Topic::RadioStation(payload) => (TOPIC_RADIO_STATION, payload.into()),
                 Topic::RadioSource(payload) => (TOPIC_RADIO_SOURCE, payload.into()),
+                Topic::RadioVolume(payload) => (TOPIC_RADIO_VOLUME, payload.into()),
+                Topic::Speed(payload) => (TOPIC_SPEED, payload.into()),
+                Topic::DiagStatus(payload) => (TOPIC_DIAG_STATUS, payload.into()),
+                Topic::Doors(payload) => (TOPIC_DOORS, payload.into()),
+                Topic::Battery(payload) => (TOPIC_BATTERY, payload.into()),
                 Topic::Unknown { topic, payload } => {
                     (topic, FramePayload::from_slice(payload).unwrap())
                 }
@@ -196,6 +420,22 @@ pub mod message {
         }
     }
 
+    impl<'a> BodyComputer<'a> {
+        /// NOTE: Inferred from the second byte, which all the frames of the topic share. The key
+        /// in the crank position and whether the engine runs are not known to be reported
+        pub fn ignition(&self) -> Option<Ignition> {
+            match self {
+                Self::ShutDownRequest | Self::AboutToSleep => Some(Ignition::Off),
+                Self::WakeupRequest | Self::PoweringOn => Some(Ignition::Accessory),
+                Self::StatusRequest | Self::Active => Some(Ignition::On),
+                Self::Unknown(&[0x00, 0x1a, ..]) => Some(Ignition::Off),
+                Self::Unknown(&[0x00, 0x1c, ..]) => Some(Ignition::Accessory),
+                Self::Unknown(&[0x00, 0x1e, ..]) => Some(Ignition::On),
+                Self::Unknown(_) => None,
+            }
+        }
+    }
+
     impl<'a> From<BodyComputer<'a>> for FramePayload {
         fn from(value: BodyComputer<'a>) -> Self {
             let slice: &[u8] = match value {
@@ -241,6 +481,9 @@ pub mod message {
         }
     }
 
+    // NOTE: One fixed bit layout is assumed for all wheels. Trim levels without some of the
+    // buttons (e.g. Windows) simply never report them. Adapting the layout to the PROXI
+    // configuration needs the PROXI bytes which encode the wheel variant, and these are unknown
     #[derive(Debug, EnumSetType)]
     #[enumset(repr = "u16")]
     #[repr(u16)]
@@ -299,24 +542,66 @@ pub mod message {
 
     impl<'a> From<&'a [u8]> for DateTime<'a> {
         fn from(value: &'a [u8]) -> Self {
-            match value {
-                value if value.len() == 6 => panic!(), // TODO
-                other => Self::Unknown(other),
-            }
+            Self::decode(value).unwrap_or(Self::Unknown(value))
         }
     }
 
     impl<'a> From<DateTime<'a>> for FramePayload {
         fn from(value: DateTime<'a>) -> Self {
-            let slice: &[u8] = match value {
-                DateTime::Current { .. } => &[], // TODO
-                DateTime::Unknown(other) => other,
+            match value {
+                DateTime::Current {
+                    year,
+                    month,
+                    day,
+                    hour,
+                    minute,
+                } => FramePayload::from_slice(&[
+                    to_bcd(hour),
+                    to_bcd(minute),
+                    to_bcd(day),
+                    to_bcd(month),
+                    to_bcd((year / 100) as u8),
+                    to_bcd((year % 100) as u8),
+                ])
+                .unwrap(),
+                DateTime::Unknown(other) => FramePayload::from_slice(other).unwrap(),
+            }
+        }
+    }
+
+    impl<'a> DateTime<'a> {
+        /// All fields are BCD: hour, minute, day, month, and the year as two bytes (e.g. 0x20 0x24)
+        fn decode(value: &[u8]) -> Option<Self> {
+            let &[hour, minute, day, month, century, year] = value else {
+                return None;
             };
 
-            FramePayload::from_slice(slice).unwrap()
+            let (hour, minute) = (from_bcd(hour)?, from_bcd(minute)?);
+            let (day, month) = (from_bcd(day)?, from_bcd(month)?);
+
+            let valid =
+                hour < 24 && minute < 60 && (1..=31).contains(&day) && (1..=12).contains(&month);
+
+            valid.then_some(Self::Current {
+                year: from_bcd(century)? as u16 * 100 + from_bcd(year)? as u16,
+                month,
+                day,
+                hour,
+                minute,
+            })
         }
     }
 
+    fn from_bcd(value: u8) -> Option<u8> {
+        let (tens, ones) = (value >> 4, value & 0x0f);
+
+        (tens < 10 && ones < 10).then_some(tens * 10 + ones)
+    }
+
+    fn to_bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+
     #[derive(Debug)]
     pub enum Bt<'a> {
         Mute,
@@ -473,6 +758,223 @@ pub mod message {
         }
     }
 
+    /// The volume of the radio, reported on change (e.g. by the wheel volume keys) and periodically
+    #[derive(Debug)]
+    pub enum RadioVolume<'a> {
+        Level(u8),
+        Unknown(&'a [u8]),
+    }
+
+    impl<'a> From<&'a [u8]> for RadioVolume<'a> {
+        fn from(value: &'a [u8]) -> Self {
+            match value {
+                &[level, _] if level <= MAX_RADIO_VOLUME => Self::Level(level),
+                other => Self::Unknown(other),
+            }
+        }
+    }
+
+    impl<'a> From<RadioVolume<'a>> for FramePayload {
+        fn from(value: RadioVolume<'a>) -> Self {
+            match value {
+                RadioVolume::Level(level) => FramePayload::from_slice(&[level, 0x00]),
+                RadioVolume::Unknown(other) => FramePayload::from_slice(other),
+            }
+            .unwrap()
+        }
+    }
+
+    /// The vehicle speed, in km/h
+    ///
+    /// NOTE: Only the first two bytes are decoded, the rest (e.g. the odometer) is unknown
+    #[derive(Debug)]
+    pub enum Speed<'a> {
+        Kmh(u16),
+        Unknown(&'a [u8]),
+    }
+
+    impl<'a> From<&'a [u8]> for Speed<'a> {
+        fn from(value: &'a [u8]) -> Self {
+            match value {
+                &[hi, lo, ..] => Self::Kmh(u16::from_be_bytes([hi, lo])),
+                other => Self::Unknown(other),
+            }
+        }
+    }
+
+    impl<'a> From<Speed<'a>> for FramePayload {
+        fn from(value: Speed<'a>) -> Self {
+            match value {
+                Speed::Kmh(kmh) => FramePayload::from_slice(&kmh.to_be_bytes()),
+                Speed::Unknown(other) => FramePayload::from_slice(other),
+            }
+            .unwrap()
+        }
+    }
+
+    #[derive(Debug, EnumSetType)]
+    #[enumset(repr = "u8")]
+    pub enum Door {
+        Driver,
+        Passenger,
+        RearLeft,
+        RearRight,
+        Tailgate,
+    }
+
+    /// The open doors, one bit each in the first byte
+    #[derive(Debug)]
+    pub enum Doors<'a> {
+        Open(EnumSet<Door>),
+        Unknown(&'a [u8]),
+    }
+
+    impl<'a> From<&'a [u8]> for Doors<'a> {
+        fn from(value: &'a [u8]) -> Self {
+            match value {
+                &[open, ..] => Self::Open(EnumSet::from_repr_truncated(open)),
+                other => Self::Unknown(other),
+            }
+        }
+    }
+
+    impl<'a> From<Doors<'a>> for FramePayload {
+        fn from(value: Doors<'a>) -> Self {
+            match value {
+                Doors::Open(open) => FramePayload::from_slice(&[open.as_repr()]),
+                Doors::Unknown(other) => FramePayload::from_slice(other),
+            }
+            .unwrap()
+        }
+    }
+
+    /// The battery voltage, in 100mV steps in the first byte
+    #[derive(Debug)]
+    pub enum Battery<'a> {
+        Millivolts(u16),
+        Unknown(&'a [u8]),
+    }
+
+    impl<'a> From<&'a [u8]> for Battery<'a> {
+        fn from(value: &'a [u8]) -> Self {
+            match value {
+                &[decivolts, ..] if decivolts > 0 => Self::Millivolts(decivolts as u16 * 100),
+                other => Self::Unknown(other),
+            }
+        }
+    }
+
+    impl<'a> From<Battery<'a>> for FramePayload {
+        fn from(value: Battery<'a>) -> Self {
+            match value {
+                Battery::Millivolts(millivolts) => {
+                    FramePayload::from_slice(&[(millivolts / 100).min(255) as u8])
+                }
+                Battery::Unknown(other) => FramePayload::from_slice(other),
+            }
+            .unwrap()
+        }
+    }
+
+    /// Our own status frame, allowing to read the firmware version off the bus
+    #[derive(Debug)]
+    pub enum DiagStatus<'a> {
+        Version {
+            major: u8,
+            minor: u8,
+            patch: u8,
+            build: u32,
+        },
+        Unknown(&'a [u8]),
+    }
+
+    impl<'a> From<&'a [u8]> for DiagStatus<'a> {
+        fn from(value: &'a [u8]) -> Self {
+            match value {
+                &[major, minor, patch, 0x00, b0, b1, b2, b3] => Self::Version {
+                    major,
+                    minor,
+                    patch,
+                    build: u32::from_be_bytes([b0, b1, b2, b3]),
+                },
+                other => Self::Unknown(other),
+            }
+        }
+    }
+
+    impl<'a> From<DiagStatus<'a>> for FramePayload {
+        fn from(value: DiagStatus<'a>) -> Self {
+            match value {
+                DiagStatus::Version {
+                    major,
+                    minor,
+                    patch,
+                    build,
+                } => {
+                    let [b0, b1, b2, b3] = build.to_be_bytes();
+
+                    FramePayload::from_slice(&[major, minor, patch, 0x00, b0, b1, b2, b3])
+                }
+                DiagStatus::Unknown(other) => FramePayload::from_slice(other),
+            }
+            .unwrap()
+        }
+    }
+
+    /// Characters of a display text carried by a single frame
+    pub const DISPLAY_CHUNK_LEN: usize = 8;
+    /// The frames of a display text are numbered with four bits
+    pub const MAX_DISPLAY_CHUNKS: usize = 16;
+
+    /// The number of frames a display text is sent in, at least one even if empty
+    pub fn display_chunks(text: &str) -> usize {
+        core::cmp::max(
+            (text.chars().count() + DISPLAY_CHUNK_LEN - 1) / DISPLAY_CHUNK_LEN,
+            1,
+        )
+    }
+
+    /// The text of the `chunk`-th frame. Split by characters rather than bytes,
+    /// as the track metadata of the phones is UTF-8
+    pub fn display_chunk(text: &str, chunk: usize) -> &str {
+        let offset = |chars| {
+            text.char_indices()
+                .nth(chars)
+                .map(|(offset, _)| offset)
+                .unwrap_or(text.len())
+        };
+
+        &text[offset(chunk * DISPLAY_CHUNK_LEN)..offset((chunk + 1) * DISPLAY_CHUNK_LEN)]
+    }
+
+    /// Acceptance filter (ID and mask) letting through only the body computer
+    /// units status topic, which carries the wakeup requests
+    pub fn wake_filter() -> (u32, u32) {
+        (get_id(TOPIC_UNITS_STATUS, 0), get_id(0x1fff, 0))
+    }
+
+    /// Acceptance filter (ID and mask) letting through the topics the adapter consumes.
+    /// A single filter cannot match a set of IDs, so it only fixes the bits all topics agree on
+    pub fn consumed_filter() -> (u32, u32) {
+        const CONSUMED: &[u16] = &[
+            TOPIC_UNITS_STATUS,
+            TOPIC_PROXI,
+            TOPIC_STEERING_WHEEL,
+            TOPIC_DISPLAY,
+            TOPIC_RADIO_STATION,
+            TOPIC_RADIO_SOURCE,
+            TOPIC_RADIO_VOLUME,
+            TOPIC_SPEED,
+            TOPIC_DOORS,
+            TOPIC_BATTERY,
+        ];
+
+        let ones = CONSUMED.iter().fold(0x1fff, |acc, topic| acc & topic);
+        let zeros = CONSUMED.iter().fold(0x1fff, |acc, topic| acc & !topic);
+
+        (get_id(ones, 0), get_id(ones | zeros, 0))
+    }
+
     fn get_id(topic: u16, publisher: u16) -> u32 {
         ((topic as u32) << 16) | (publisher as u32)
     }
@@ -545,11 +1047,11 @@ pub mod message {
         }
 
         for ch in text.chars() {
-            let index = CHAR_MAP
-                .chars()
-                .position(|chm| chm == ch)
-                .unwrap_or(CHAR_MAP.chars().position(|chm| chm == ' ').unwrap())
-                + 1;
+            let index = CHAR_CODES[if ch.is_ascii() {
+                ch as usize
+            } else {
+                b' ' as usize
+            }] as usize;
 
             let char_start = offset >> 3;
             let char_end = (offset + 6) >> 3;
@@ -577,7 +1079,7 @@ pub mod message {
 
     #[test]
     fn test() {
-        let mut str_buf = heapless::String::<32>::new();
+        let mut str_buf = heapless::String::<12>::new();
 
         assert_eq!(
             decode_display_text(&0x101A8177D4610A0E_u64.to_be_bytes(), &mut str_buf),
@@ -598,6 +1100,91 @@ pub mod message {
             ),
             0x00008177d4610a00
         );
+        let (filter, mask) = consumed_filter();
+
+        assert_eq!(get_id(TOPIC_PROXI, UNIT_BODY_COMPUTER) & mask, filter);
+        assert_eq!(get_id(TOPIC_RADIO_VOLUME, UNIT_RADIO) & mask, filter);
+        assert_ne!(get_id(TOPIC_DATETIME, UNIT_BODY_COMPUTER) & mask, filter);
+
+        assert!(matches!(
+            DateTime::from(&[0x09, 0x41, 0x28, 0x02, 0x20, 0x24][..]),
+            DateTime::Current {
+                year: 2024,
+                month: 2,
+                day: 28,
+                hour: 9,
+                minute: 41,
+            }
+        ));
+        assert!(matches!(
+            DateTime::from(&[0x25, 0x00, 0x01, 0x01, 0x20, 0x24][..]),
+            DateTime::Unknown(_)
+        ));
+        assert!(matches!(
+            DateTime::from(&[0x0a, 0x00, 0x01, 0x01, 0x20, 0x24][..]),
+            DateTime::Unknown(_)
+        ));
+        assert_eq!(
+            FramePayload::from(DateTime::Current {
+                year: 2024,
+                month: 12,
+                day: 31,
+                hour: 23,
+                minute: 59,
+            }),
+            FramePayload::from_slice(&[0x23, 0x59, 0x31, 0x12, 0x20, 0x24]).unwrap()
+        );
+
+        assert!(matches!(
+            RadioVolume::from(&[0x0c, 0x00][..]),
+            RadioVolume::Level(12)
+        ));
+        assert!(matches!(
+            RadioVolume::from(&[0xff, 0x00][..]),
+            RadioVolume::Unknown(_)
+        ));
+        assert!(matches!(
+            Speed::from(&[0x00, 0x5a, 0x12, 0x34][..]),
+            Speed::Kmh(90)
+        ));
+        assert!(matches!(Speed::from(&[0x00][..]), Speed::Unknown(_)));
+        assert!(matches!(
+            Doors::from(&[0x11, 0x00][..]),
+            Doors::Open(open) if open == Door::Driver | Door::Tailgate
+        ));
+        assert!(matches!(Doors::from(&[][..]), Doors::Unknown(_)));
+        assert!(matches!(
+            Battery::from(&[0x7b, 0x00][..]),
+            Battery::Millivolts(12300)
+        ));
+        assert!(matches!(Battery::from(&[0x00][..]), Battery::Unknown(_)));
+        assert_eq!(
+            BodyComputer::from(&[0x00, 0x1c, 0x00, 0x00, 0x00, 0x01][..]).ignition(),
+            Some(Ignition::Accessory)
+        );
+        assert_eq!(
+            BodyComputer::from(&[0x00, 0x1e, 0x08][..]).ignition(),
+            Some(Ignition::On)
+        );
+        assert_eq!(
+            BodyComputer::from(&[0x00, 0x1a][..]).ignition(),
+            Some(Ignition::Off)
+        );
+        assert_eq!(BodyComputer::from(&[0x01][..]).ignition(), None);
+        for (index, ch) in CHAR_MAP.chars().enumerate() {
+            assert_eq!(
+                CHAR_MAP.as_bytes()[CHAR_CODES[ch as usize] as usize - 1],
+                CHAR_MAP.as_bytes()[index]
+            );
+        }
+        assert_eq!(CHAR_CODES[b'a' as usize], CHAR_CODES[b' ' as usize]);
+        assert_eq!(display_chunks(""), 1);
+        assert_eq!(display_chunks("ABCDEFGH"), 1);
+        assert_eq!(display_chunks("ABCDEFGHI"), 2);
+        assert_eq!(display_chunk("ABCDEFGHI", 0), "ABCDEFGH");
+        assert_eq!(display_chunk("ABCDEFGHI", 1), "I");
+        assert_eq!(display_chunk("ABCDEFGHI", 2), "");
+        assert_eq!(display_chunk("BEYONCÉ - HALO", 1), "- HALO");
         assert_eq!(
             u64::from_be_bytes(encode_display_text("0").into_array().unwrap()),
             0x0000040000000000
@@ -610,41 +1197,130 @@ pub mod message {
             ),
             "BLAH "
         );
+
+        // Captured from the instrument panel: a plain cockpit text, i.e. not a menu
+        let captured = 0x101A8177D4610A0E_u64.to_be_bytes();
+
+        assert!(matches!(
+            Display::from((&captured[..], &mut str_buf)),
+            Display::Text {
+                for_radio: false,
+                menu: false,
+                chunk: 0,
+                ..
+            }
+        ));
+
+        let layout = |for_radio, menu| {
+            let payload = FramePayload::from(Display::Text {
+                for_radio,
+                menu,
+                text: "ULTIME",
+                chunk: 0,
+                total_chunks: 2.try_into().unwrap(),
+            });
+
+            [payload[0], payload[1]]
+        };
+
+        assert_eq!(layout(false, false), [captured[0], captured[1]]);
+        assert_eq!(layout(false, true), [0x10, 0x16]);
+        assert_eq!(layout(true, true), [0x10, 0x2a]);
+
+        let mut frame = [0; 8];
+        frame[..2].copy_from_slice(&layout(false, true));
+
+        assert!(matches!(
+            Display::from((&frame[..], &mut str_buf)),
+            Display::Text {
+                for_radio: false,
+                menu: true,
+                ..
+            }
+        ));
     }
 }
 
 #[allow(clippy::too_many_arguments)]
-pub async fn process<const N: usize>(
-    bus: BusSubscription<'_>,
+pub async fn process<const N: usize, const R: usize, const C: usize>(
+    bus: BusSubscription<'_, R, C>,
     mut can: impl Peripheral<P = CAN>,
     mut tx: impl Peripheral<P = impl OutputPin>,
     mut rx: impl Peripheral<P = impl InputPin>,
     str_buf: &mut heapless::String<N>,
     radio: Sender<'_, impl RawMutex, RadioState>,
+    radio_volume: Sender<'_, impl RawMutex, u8>,
     buttons: Sender<'_, impl RawMutex, EnumSet<SteeringWheelButton>>,
+    speed: Sender<'_, impl RawMutex, u16>,
+    parking: Sender<'_, impl RawMutex, bool>,
     radio_commands: Sender<'_, impl RawMutex, BtCommand>,
+    vehicle: StatefulSender<'_, impl RawMutex, Vehicle>,
+    car_clock: StatefulSender<'_, impl RawMutex, Option<CarClock>>,
+    fm_station: StatefulSender<'_, impl RawMutex, FmStation>,
+    diagnostics: QueueSender<'_, impl RawMutex, Diagnostic>,
+    storage: &Storage,
 ) -> Result<(), Error> {
     loop {
         bus.service.wait_enabled().await?;
 
-        {
-            bus.service.starting();
+        bus.service.starting();
+
+        let mut started = None;
 
-            let mut driver = create(&mut can, &mut tx, &mut rx)?;
+        let filtered = &Cell::new(false);
+        let bench = &Cell::new(false);
+        // Outlives the driver, which is re-created with the wake filter once stopped
+        let low_voltage = &Cell::new(false);
+
+        loop {
+            // While the car is parked B-CAN stays chatty, so only wake frames are received
+            let sleeping = bus.service.get_sys_state() == SystemState::Stopped;
+
+            info!(
+                "Creating CAN driver, sleeping: {}, filtered: {}",
+                sleeping,
+                filtered.get()
+            );
+
+            let mut driver = create(&mut can, &mut tx, &mut rx, sleeping, filtered.get())?;
 
             let raw_buttons = &Signal::<NoopRawMutex, _>::new();
+            let parking_frames = &Signal::<NoopRawMutex, _>::new();
 
             let send_radio_switch = &Signal::<NoopRawMutex, _>::new();
             let send_radio_display = &Signal::<NoopRawMutex, _>::new();
             let send_cockpit_display = &Signal::<NoopRawMutex, _>::new();
             let send_proxi = &Signal::<NoopRawMutex, _>::new();
             let send_status = &Signal::<NoopRawMutex, _>::new();
+            let send_version = &Signal::<NoopRawMutex, _>::new();
+            let send_raw = &Signal::<NoopRawMutex, _>::new();
+
+            let factory_menu_until = &Cell::new(None);
+            let received = &Cell::new(false);
 
             driver.start()?;
 
-            let _started = bus.service.started();
+            if STANDALONE {
+                radio.send(RadioState::BtActive);
+            }
+
+            send_version.signal(as_frame(Topic::DiagStatus(DiagStatus::Version {
+                major: version::major(),
+                minor: version::minor(),
+                patch: version::patch(),
+                build: version::build(),
+            })));
 
-            SelectSpawn::run(&mut pin!(bus.service.wait_disabled()))
+            // Re-creating the driver with another filter should not flip the service state
+            if started.is_none() {
+                started = Some(bus.service.started());
+            }
+
+            let sleeping_changed = bus
+                .service
+                .wait_disabled_or_sys_state(|state| (state == SystemState::Stopped) != sleeping);
+
+            SelectSpawn::run(&mut pin!(sleeping_changed))
                 .chain(&mut pin!(process_radio_mux(
                     &bus.audio,
                     &bus.phone,
@@ -655,24 +1331,51 @@ pub async fn process<const N: usize>(
                 .chain(&mut pin!(process_display(
                     &bus.radio_display,
                     true,
+                    RADIO_DISPLAY_INTERVAL,
+                    factory_menu_until,
                     send_radio_display,
                 )))
                 .chain(&mut pin!(process_display(
                     &bus.cockpit_display,
                     false,
+                    COCKPIT_DISPLAY_INTERVAL,
+                    factory_menu_until,
                     send_cockpit_display,
                 )))
                 .chain(&mut pin!(process_send(
                     &driver,
+                    bench,
                     &[
                         send_radio_switch,
                         send_radio_display,
                         send_cockpit_display,
                         send_proxi,
                         send_status,
+                        send_version,
+                        send_raw,
                     ],
+                    &diagnostics,
+                )))
+                .chain(&mut pin!(process_raw(&bus.can_frames, send_raw)))
+                .chain(&mut pin!(process_overflows(
+                    &driver,
+                    filtered,
+                    &diagnostics
+                )))
+                .chain(&mut pin!(process_error_active(bench, &diagnostics)))
+                .chain(&mut pin!(process_bench(
+                    sleeping,
+                    received,
+                    bench,
+                    &radio,
+                    &diagnostics
+                )))
+                .chain(&mut pin!(process_debounce_buttons(
+                    raw_buttons,
+                    &buttons,
+                    storage
                 )))
-                .chain(&mut pin!(process_debounce_buttons(raw_buttons, &buttons)))
+                .chain(&mut pin!(process_parking(parking_frames, &parking)))
                 .chain(&mut pin!(process_recv(
                     &driver,
                     str_buf,
@@ -680,11 +1383,27 @@ pub async fn process<const N: usize>(
                     send_status,
                     send_proxi,
                     &radio,
+                    &radio_volume,
+                    &speed,
                     raw_buttons,
+                    parking_frames,
+                    &vehicle,
+                    &car_clock,
+                    &fm_station,
+                    &radio_commands,
+                    storage,
+                    factory_menu_until,
+                    received,
+                    low_voltage,
+                    &diagnostics,
                 )))
                 .await?;
 
             driver.stop()?;
+
+            if !bus.service.is_enabled() {
+                break;
+            }
         }
     }
 }
@@ -693,8 +1412,26 @@ fn create<'d>(
     can: impl Peripheral<P = CAN> + 'd,
     tx: impl Peripheral<P = impl OutputPin> + 'd,
     rx: impl Peripheral<P = impl InputPin> + 'd,
+    sleeping: bool,
+    filtered: bool,
 ) -> Result<OwnedAsyncCanDriver<'d>, Error> {
-    Ok(AsyncCanDriver::new(can, tx, rx, &CanConfig::new())?)
+    let config = if sleeping {
+        let (filter, mask) = message::wake_filter();
+
+        CanConfig::new().filter(Filter::Extended { filter, mask })
+    } else if filtered {
+        let (filter, mask) = message::consumed_filter();
+
+        CanConfig::new().filter(Filter::Extended { filter, mask })
+    } else {
+        CanConfig::new()
+    };
+
+    let config = config
+        .timing(HAL_TIMING)
+        .alerts(Alert::RxQueueFull | Alert::RxFifoOverflow);
+
+    Ok(AsyncCanDriver::new(can, tx, rx, &config)?)
 }
 
 async fn process_radio_mux(
@@ -704,101 +1441,257 @@ async fn process_radio_mux(
     radio_commands: &Sender<'_, impl RawMutex, BtCommand>,
     radio_switch_out: &Signal<impl RawMutex, Frame>,
 ) -> Result<(), Error> {
-    let mut sradio = RadioState::Unknown;
-    let mut sphone = AudioState::Uninitialized;
-    let mut saudio = AudioState::Uninitialized;
+    let mut mux = RadioMux::new();
 
     loop {
-        let ret = select3(radio.recv(), phone.recv(), audio.recv()).await;
+        let deadline = mux.deadline();
 
-        match ret {
-            Either3::First(new) => {
-                sradio = new;
-
-                if saudio.is_active() && !sphone.is_active() {
-                    match new {
-                        RadioState::BtActive => radio_commands.send(BtCommand::Resume),
-                        _ => radio_commands.send(BtCommand::Pause),
-                    }
-                }
+        let stop = async {
+            match deadline {
+                Some(deadline) => Timer::at(deadline).await,
+                None => core::future::pending().await,
             }
-            Either3::Second(new) => {
-                sphone = new;
+        };
+
+        let ret = select4(radio.recv(), phone.recv(), audio.recv(), stop).await;
 
-                if sphone.is_active() && !sradio.is_bt_active() {
-                    radio_switch_out.signal(as_frame(Topic::Bt(Bt::Phone)));
+        let now = Instant::now();
+
+        let action = match ret {
+            Either4::First(new) => mux.update(MuxEvent::Radio(new), now),
+            Either4::Second(new) => mux.update(MuxEvent::Phone(new), now),
+            Either4::Third(new) => mux.update(MuxEvent::Audio(new), now),
+            Either4::Fourth(_) => mux.poll(now),
+        };
+
+        match action {
+            Some(MuxAction::Command(command)) => {
+                if command == BtCommand::Stop {
+                    info!("Radio away from BT for long, stopping the phone");
                 }
 
-                // TODO: Switch back on phone disconnect
+                radio_commands.send(command);
+            }
+            Some(MuxAction::SwitchToPhone) => {
+                radio_switch_out.signal(as_frame(Topic::Bt(Bt::Phone)))
             }
-            Either3::Third(new) => saudio = new,
+            None => (),
         }
     }
 }
 
+/// The pacing of the frames of a multi-chunk text, so that the radio keeps up with them
+const DISPLAY_CHUNK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Texts changing faster than `min_interval` (e.g. the track metadata arriving piece by piece)
+/// are collapsed to the latest one, rather than each being sent in full
 async fn process_display<const N: usize>(
     text: &StatefulReceiver<'_, impl RawMutex, DisplayText<N>>,
     for_radio: bool,
+    min_interval: Duration,
+    factory_menu_until: &Cell<Option<Instant>>,
     display_out: &Signal<impl RawMutex, Frame>,
 ) -> Result<(), Error> {
     let mut version = None;
-    let mut offset = 0;
-    let mut processing = false;
+    // The frames of the current text still to be sent, the last chunk first
+    let mut frames = heapless::Vec::<_, { message::MAX_DISPLAY_CHUNKS }>::new();
+    let mut clearing = false;
+    let mut resend = false;
+    let mut started_at: Option<Instant> = None;
 
     loop {
-        select(text.recv(), Timer::after(Duration::from_millis(10))).await;
+        select(text.recv(), Timer::after(DISPLAY_CHUNK_INTERVAL)).await;
+
+        let now = Instant::now();
 
         text.state(|text| {
             if Some(text.version) != version {
-                version = Some(text.version);
-                offset = 0;
-                processing = true;
-            }
+                if started_at
+                    .map(|at| now.duration_since(at) < min_interval)
+                    .unwrap_or(false)
+                {
+                    // Neither start the new text yet, nor send the rest of the old one,
+                    // as its chunks would be mixed with the new text
+                    return;
+                }
 
-            if !display_out.signaled() && processing {
-                let menu = text.menu && !for_radio;
-                let text = &text.text;
+                started_at = Some(now);
 
-                let chunk_payload = &text[offset..min(offset + 8, text.len())];
-                let chunk = offset / 8;
-                let total_chunks =
-                    core::cmp::max(text.len() / 8 + (if text.len() % 8 > 0 { 1 } else { 0 }), 1);
+                // Chunks of the previous text might be on the display already,
+                // so clear it first rather than mixing old and new chunks
+                clearing = !frames.is_empty();
 
-                let topic = Topic::Display(Display::Text {
-                    for_radio,
-                    menu,
-                    text: chunk_payload,
-                    chunk,
-                    total_chunks: total_chunks.try_into().unwrap(),
-                });
+                version = Some(text.version);
+                resend = true;
+            }
 
-                // println!("{topic:?}");
+            if display_out.signaled() {
+                return;
+            }
 
-                display_out.signal(as_frame(topic));
+            // Writing to the cockpit while the factory menu is open would interleave the texts,
+            // so the current text is rather sent anew once the menu is closed
+            if !for_radio
+                && factory_menu_until
+                    .get()
+                    .map(|until| until > Instant::now())
+                    .unwrap_or(false)
+            {
+                resend = true;
+                clearing = false;
+
+                return;
+            }
 
-                offset += 8;
+            if resend {
+                frames = display_frames(&text.text, for_radio, text.menu && !for_radio);
+                resend = false;
+            }
 
-                if text.len() <= offset {
-                    processing = false;
-                }
+            if clearing {
+                display_out.signal(as_frame(Topic::Display(Display::Text {
+                    for_radio,
+                    menu: text.menu && !for_radio,
+                    text: "",
+                    chunk: 0,
+                    total_chunks: 1.try_into().unwrap(),
+                })));
+
+                clearing = false;
+            } else if let Some(frame) = frames.pop() {
+                display_out.signal(frame);
             }
         });
     }
 }
 
+/// Encodes all the chunks of a text once, rather than on every send. The last chunk comes first
+fn display_frames(
+    text: &str,
+    for_radio: bool,
+    menu: bool,
+) -> heapless::Vec<Frame, { message::MAX_DISPLAY_CHUNKS }> {
+    let total_chunks = min(message::display_chunks(text), message::MAX_DISPLAY_CHUNKS);
+
+    (0..total_chunks)
+        .rev()
+        .map(|chunk| {
+            as_frame(Topic::Display(Display::Text {
+                for_radio,
+                menu,
+                text: message::display_chunk(text, chunk),
+                chunk,
+                total_chunks: total_chunks.try_into().unwrap(),
+            }))
+        })
+        .collect()
+}
+
+/// Transient TX errors (a full TX queue or a timeout, e.g. while the bus is busy) are retried
+/// with a backoff, and a frame failing still is dropped. Only a run of dropped frames, or any other
+/// error (e.g. bus-off), ends the service. The retries and drops are reported once per window
+///
+/// NOTE: Lost arbitrations are no errors here, as the controller retransmits on its own
 async fn process_send<'d, const N: usize>(
     driver: &OwnedAsyncCanDriver<'d>,
+    bench: &Cell<bool>,
     frames: &[&Signal<impl RawMutex, Frame>; N],
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
 ) -> Result<(), Error> {
+    let mut window_end = Instant::now() + OVERFLOW_WINDOW;
+    let mut retries: u16 = 0;
+    let mut dropped: u16 = 0;
+    let mut dropped_in_row = 0;
+
     loop {
         let mut array = heapless::Vec::<_, N>::from_iter(frames.iter().map(|signal| signal.wait()));
 
         let (frame, _) = select_slice(&mut array).await;
 
-        driver.transmit(&frame).await?;
+        // On the bench the frames are dropped, as there is no display to show them anyway
+        if bench.get() {
+            continue;
+        }
+
+        let mut backoff = TX_RETRY_MIN;
+        let mut attempts = 1;
+
+        loop {
+            match driver.transmit(&frame).await {
+                Ok(()) => {
+                    dropped_in_row = 0;
+                    break;
+                }
+                Err(err) if is_transient(&err) && attempts < TX_ATTEMPTS => {
+                    attempts += 1;
+                    retries = retries.saturating_add(1);
+
+                    Timer::after(backoff).await;
+                    backoff = min(backoff * 2, TX_RETRY_MAX);
+                }
+                Err(err) if is_transient(&err) && dropped_in_row + 1 < TX_DROPPED_PERSISTENT => {
+                    warn!("Dropping CAN frame {:08x}: {}", frame.identifier(), err);
+
+                    dropped = dropped.saturating_add(1);
+                    dropped_in_row += 1;
+                    break;
+                }
+                Err(err) => {
+                    report_bus_off(diagnostics);
+
+                    return Err(err.into());
+                }
+            }
+        }
+
+        let now = Instant::now();
+
+        if now >= window_end {
+            if retries > 0 || dropped > 0 {
+                diagnostics.send(Diagnostic::CanTxErrors { retries, dropped });
+            }
+
+            window_end = now + OVERFLOW_WINDOW;
+            retries = 0;
+            dropped = 0;
+        }
     }
 }
 
+fn report_bus_off(diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>) {
+    let mut status: twai_status_info_t = Default::default();
+
+    if esp!(unsafe { twai_get_status_info(&mut status) }).is_ok()
+        && status.state == twai_state_t_TWAI_STATE_BUS_OFF
+    {
+        diagnostics.send(Diagnostic::CanBusOff {
+            tx_errors: status.tx_error_counter,
+        });
+    }
+}
+
+fn is_transient(err: &EspError) -> bool {
+    err.code() == ESP_FAIL || err.code() == ESP_ERR_TIMEOUT as i32
+}
+
+async fn process_raw(
+    frames: &Receiver<'_, impl RawMutex, RawFrame>,
+    send: &Signal<impl RawMutex, Frame>,
+) -> Result<(), Error> {
+    loop {
+        let raw = frames.recv().await;
+
+        match Frame::new(raw.id, true, &raw.data) {
+            Some(frame) => {
+                info!("Sending raw frame {:08x}: {:02x?}", raw.id, raw.data);
+                send.signal(frame);
+            }
+            None => warn!("Invalid raw frame {:08x}", raw.id),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_recv<'d, const N: usize>(
     driver: &OwnedAsyncCanDriver<'d>,
     str_buf: &mut heapless::String<N>,
@@ -806,39 +1699,352 @@ async fn process_recv<'d, const N: usize>(
     status_out: &Signal<impl RawMutex, Frame>,
     proxi_out: &Signal<impl RawMutex, Frame>,
     radio: &Sender<'_, impl RawMutex, RadioState>,
+    radio_volume: &Sender<'_, impl RawMutex, u8>,
+    speed: &Sender<'_, impl RawMutex, u16>,
     raw_buttons: &Signal<impl RawMutex, EnumSet<SteeringWheelButton>>,
+    parking_frames: &Signal<impl RawMutex, ()>,
+    vehicle: &StatefulSender<'_, impl RawMutex, Vehicle>,
+    car_clock: &StatefulSender<'_, impl RawMutex, Option<CarClock>>,
+    fm_station: &StatefulSender<'_, impl RawMutex, FmStation>,
+    radio_commands: &Sender<'_, impl RawMutex, BtCommand>,
+    storage: &Storage,
+    factory_menu_until: &Cell<Option<Instant>>,
+    received: &Cell<bool>,
+    low_voltage: &Cell<bool>,
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
 ) -> Result<(), Error> {
     let mut pending_proxi_request = false;
-    let mut pending_proxi_value = None;
+    // Served right away after a cold boot, rather than only once another unit answered a request,
+    // as the real Blue&Me does
+    let mut pending_proxi_value = storage
+        .get(VEHICLE_KEY)
+        .await?
+        .and_then(|value| <[u8; 6]>::try_from(value.as_slice()).ok());
+
+    // Known from the last drive, so the per-car settings apply before the PROXI is captured again
+    if let Some(proxi) = pending_proxi_value {
+        vehicle.modify(|vehicle| {
+            let changed = vehicle.proxi != Some(proxi);
+            vehicle.proxi = Some(proxi);
+            changed
+        });
+    }
+
+    let mut radio_state = None;
+    let mut volume = None;
+    let mut kmh = None;
+    let mut about_to_sleep = false;
+    let mut driver_door_open = false;
+    let mut sniffer = Sniffer::new();
 
     loop {
         let frame = driver.receive().await?;
 
+        received.set(true);
+
         let message: Message<'_> = (&frame, &mut *str_buf).into();
 
+        // NOTE: While the driver is filtered, the unknown topics are not received in the first place
+        let sniff = service.is_service_mode()
+            && (matches!(message.topic, Topic::Unknown { .. })
+                || matches!(message.publisher, Publisher::Unknown(_)));
+
+        if message.publisher == Publisher::ParkingSensors {
+            parking_frames.signal(());
+        }
+
         match message.topic {
             Topic::BodyComputer(payload) => {
-                process_recv_body_computer(payload, service, status_out)
+                if let Some(ignition) = payload.ignition() {
+                    if service.ignition() != ignition {
+                        info!("Ignition: {:?}", ignition);
+
+                        service.sys_set_ignition(ignition);
+                    }
+                }
+
+                match payload {
+                    BodyComputer::AboutToSleep if !about_to_sleep => {
+                        // Otherwise the phone keeps on playing to a sink which is about to go dead
+                        info!("Car about to sleep, pausing the phone");
+
+                        radio_commands.send(BtCommand::PrepareSleep);
+                        about_to_sleep = true;
+                    }
+                    BodyComputer::WakeupRequest
+                    | BodyComputer::PoweringOn
+                    | BodyComputer::Active => about_to_sleep = false,
+                    _ => (),
+                }
+
+                if low_voltage.get() && payload.ignition() == Some(Ignition::On) {
+                    info!("Key on, leaving the low voltage protection");
+
+                    low_voltage.set(false);
+                    service.sys_start();
+                }
+
+                process_recv_body_computer(payload, low_voltage.get(), service, status_out)
+            }
+            Topic::Battery(Battery::Millivolts(millivolts))
+                if millivolts < LOW_VOLTAGE_MV
+                    && !low_voltage.get()
+                    && service.ignition() != Ignition::On =>
+            {
+                // Cranking dips are excluded, as the key is on meanwhile
+                warn!("Battery at {}mV, stopping", millivolts);
+
+                diagnostics.send(Diagnostic::LowVoltage { millivolts });
+
+                radio_commands.send(BtCommand::PrepareSleep);
+                about_to_sleep = true;
+                low_voltage.set(true);
+
+                service.sys_stop();
+
+                status_out.signal(as_frame(Topic::BodyComputer(BodyComputer::AboutToSleep)));
+            }
+            Topic::Doors(Doors::Open(open)) => {
+                let opened = open.contains(Door::Driver) && !driver_door_open;
+
+                driver_door_open = open.contains(Door::Driver);
+
+                // The driver leaving the car, which otherwise keeps us (and the phone connected)
+                // powered until the body computer cuts the supply minutes later. Also while a
+                // service is still (re)starting, as only an already stopping system is left alone
+                if opened
+                    && service.ignition() == Ignition::Off
+                    && matches!(
+                        service.get_sys_state(),
+                        SystemState::Starting | SystemState::Started
+                    )
+                {
+                    info!("Driver door opened after key-off, stopping");
+
+                    radio_commands.send(BtCommand::PrepareSleep);
+                    about_to_sleep = true;
+
+                    service.sys_stop();
+
+                    status_out.signal(as_frame(Topic::BodyComputer(BodyComputer::AboutToSleep)));
+                }
+            }
+            Topic::Proxi(payload) => {
+                let captured = process_recv_proxi(
+                    payload,
+                    &mut pending_proxi_request,
+                    &mut pending_proxi_value,
+                    proxi_out,
+                );
+
+                if let Some(proxi) = captured {
+                    process_vehicle(proxi, vehicle, storage).await?;
+                }
             }
-            Topic::Proxi(payload) => process_recv_proxi(
-                payload,
-                &mut pending_proxi_request,
-                &mut pending_proxi_value,
-                proxi_out,
-            ),
             Topic::SteeringWheel(payload) => process_recv_steering_wheel(payload, raw_buttons),
-            Topic::RadioSource(payload) => process_recv_radio_source(payload, radio),
+            Topic::DateTime(payload) => process_recv_datetime(payload, car_clock),
+            Topic::Display(Display::Text {
+                for_radio: false,
+                menu: true,
+                ..
+            }) if message.publisher == Publisher::InstrumentPanel => {
+                factory_menu_until.set(Some(Instant::now() + FACTORY_MENU_HOLD))
+            }
+            Topic::RadioSource(payload) if !STANDALONE => {
+                process_recv_radio_source(payload, &mut radio_state, radio, fm_station)
+            }
+            Topic::RadioStation(RadioStation::Station(name)) if !STANDALONE => {
+                fm_station.modify(|station| {
+                    if station.name != name {
+                        set_text(&mut station.name, name);
+                        true
+                    } else {
+                        false
+                    }
+                });
+            }
+            Topic::RadioVolume(RadioVolume::Level(level)) if volume != Some(level) => {
+                volume = Some(level);
+                radio_volume.send(level);
+            }
+            Topic::Speed(Speed::Kmh(new)) if kmh != Some(new) => {
+                kmh = Some(new);
+                speed.send(new);
+
+                service.sys_set_moving(new > 0);
+            }
             _ => (),
         }
+
+        if sniff {
+            sniffer.record(&frame, storage).await?;
+        }
+    }
+}
+
+/// Body computer storms overflow the RX queue faster than `process_recv` drains it.
+/// Overflows are reported, and once sustained the driver is re-created filtered
+async fn process_overflows<'d>(
+    driver: &OwnedAsyncCanDriver<'d>,
+    filtered: &Cell<bool>,
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
+) -> Result<(), Error> {
+    let mut window_end = Instant::now() + OVERFLOW_WINDOW;
+    let mut overflows: u16 = 0;
+    let mut sustained = 0;
+
+    loop {
+        if let Either::First(alerts) = select(driver.read_alerts(), Timer::at(window_end)).await {
+            if alerts?.intersects(Alert::RxQueueFull | Alert::RxFifoOverflow) {
+                overflows = overflows.saturating_add(1);
+            }
+        }
+
+        let now = Instant::now();
+
+        if now < window_end {
+            continue;
+        }
+
+        window_end = now + OVERFLOW_WINDOW;
+
+        if overflows > 0 {
+            diagnostics.send(Diagnostic::CanOverflow { count: overflows });
+            sustained += 1;
+        } else {
+            sustained = 0;
+        }
+
+        overflows = 0;
+
+        if sustained >= OVERFLOW_SUSTAINED && !filtered.get() {
+            warn!("Sustained CAN RX overflows, re-creating the driver filtered");
+
+            filtered.set(true);
+
+            return Ok(());
+        }
+    }
+}
+
+/// Reports the driver once, if it does not become error-active on the bus
+async fn process_error_active(
+    bench: &Cell<bool>,
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
+) -> Result<(), Error> {
+    const TICK: Duration = Duration::from_secs(1);
+
+    let deadline = Instant::now() + ERROR_ACTIVE_WITHIN;
+
+    loop {
+        Timer::after(TICK).await;
+
+        let mut status: twai_status_info_t = Default::default();
+
+        esp!(unsafe { twai_get_status_info(&mut status) })?;
+
+        if status.state == twai_state_t_TWAI_STATE_RUNNING
+            && status.tx_error_counter < ERROR_PASSIVE_COUNT
+            && status.rx_error_counter < ERROR_PASSIVE_COUNT
+        {
+            break;
+        }
+
+        // Without a bus no frame is acknowledged, so the driver is error-passive on the bench
+        if bench.get() {
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            warn!(
+                "CAN not error-active at {} bit/s: {:?}",
+                BUS_TIMING.bitrate, status
+            );
+
+            diagnostics.send(Diagnostic::CanNotErrorActive {
+                tx_errors: status.tx_error_counter,
+                rx_errors: status.rx_error_counter,
+            });
+
+            break;
+        }
+    }
+
+    core::future::pending().await
+}
+
+/// Switches to the bench mode once no frame was received for `BENCH_AFTER` after the start,
+/// i.e. the radio is assumed on BT so that the audio plays, and no display frames are sent.
+/// A sleeping car is silent as well, so that is only detected while awake
+async fn process_bench(
+    sleeping: bool,
+    received: &Cell<bool>,
+    bench: &Cell<bool>,
+    radio: &Sender<'_, impl RawMutex, RadioState>,
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
+) -> Result<(), Error> {
+    const TICK: Duration = Duration::from_secs(1);
+
+    if !bench.get() && !sleeping {
+        Timer::after(BENCH_AFTER).await;
+
+        if !received.get() {
+            bench.set(true);
+
+            radio.send(RadioState::BtActive);
+            diagnostics.send(Diagnostic::BenchMode);
+        }
+    }
+
+    while bench.get() {
+        Timer::after(TICK).await;
+
+        if received.get() {
+            info!("CAN traffic, leaving the bench mode");
+
+            bench.set(false);
+        }
+    }
+
+    core::future::pending().await
+}
+
+/// Publishes whether the parking sensors are active (i.e. beeping, in reverse gear)
+///
+/// NOTE: Their payload is not decoded, rather their unit is assumed to only publish while the
+/// sensors are active. While the driver is filtered, their frames are not received at all
+async fn process_parking(
+    parking_frames: &Signal<impl RawMutex, ()>,
+    parking: &Sender<'_, impl RawMutex, bool>,
+) -> Result<(), Error> {
+    loop {
+        parking_frames.wait().await;
+
+        parking.send(true);
+
+        while let Either::First(_) = select(parking_frames.wait(), Timer::after(PARKING_HOLD)).await
+        {
+        }
+
+        parking.send(false);
     }
 }
 
 async fn process_debounce_buttons(
     raw_buttons: &Signal<impl RawMutex, EnumSet<SteeringWheelButton>>,
     buttons: &Sender<'_, impl RawMutex, EnumSet<SteeringWheelButton>>,
+    storage: &Storage,
 ) -> Result<(), Error> {
     const TICK: Duration = Duration::from_millis(10);
 
+    let debounce = Duration::from_millis(
+        storage
+            .get_u32(DEBOUNCE_KEY)
+            .await?
+            .unwrap_or(DEFAULT_DEBOUNCE_MS)
+            .min(MAX_DEBOUNCE_MS) as _,
+    );
+
     let mut debouncing = [None; 16];
     let mut debounced_state = EnumSet::EMPTY;
     let mut latest_state = EnumSet::EMPTY;
@@ -849,8 +2055,8 @@ async fn process_debounce_buttons(
                 for button in EnumSet::ALL {
                     if latest_state.contains(button) != new.contains(button) {
                         let debouncing = &mut debouncing[button as usize];
-                        if !debouncing.is_some() {
-                            *debouncing = Some(Duration::from_millis(100));
+                        if debouncing.is_none() {
+                            *debouncing = Some(debounce);
                         }
                     }
                 }
@@ -868,7 +2074,7 @@ async fn process_debounce_buttons(
                             if latest_state.contains(button) {
                                 debounced_state |= button;
                             } else {
-                                debounced_state &= button;
+                                debounced_state -= button;
                             }
 
                             send_buttons = true;
@@ -896,12 +2102,15 @@ fn process_recv_steering_wheel(
     }
 }
 
+/// Returns the PROXI value, once it is captured for the first time
 fn process_recv_proxi(
     payload: Proxi<'_>,
     pending_proxi_request: &mut bool,
-    proxi_value: &mut Option<[u8; 8]>,
+    proxi_value: &mut Option<[u8; 6]>,
     proxi_out: &Signal<impl RawMutex, Frame>,
-) {
+) -> Option<[u8; 6]> {
+    let mut captured = None;
+
     match payload {
         Proxi::Request => {
             if !*pending_proxi_request {
@@ -909,11 +2118,13 @@ fn process_recv_proxi(
             }
         }
         Proxi::Response(pvr) => {
-            if proxi_value.is_none() {
-                let mut pv = [0; 8];
-                pv.copy_from_slice(pvr);
+            let mut pv = [0; 6];
+            pv.copy_from_slice(pvr);
 
+            // The stored value is replaced, should the adapter have moved to another car
+            if *proxi_value != Some(pv) {
                 *proxi_value = Some(pv);
+                captured = Some(pv);
             }
         }
         _ => (),
@@ -925,15 +2136,45 @@ fn process_recv_proxi(
             *pending_proxi_request = false;
         }
     }
+
+    captured
+}
+
+/// Publishes the car the adapter is installed in, so that per-car settings can be applied,
+/// and remembers it, so that moving the adapter to another car is detected
+async fn process_vehicle(
+    proxi: [u8; 6],
+    vehicle: &StatefulSender<'_, impl RawMutex, Vehicle>,
+    storage: &Storage,
+) -> Result<(), Error> {
+    vehicle.modify(|vehicle| {
+        if vehicle.proxi != Some(proxi) {
+            vehicle.proxi = Some(proxi);
+            true
+        } else {
+            false
+        }
+    });
+
+    let known = storage.get(VEHICLE_KEY).await?;
+
+    if known.as_deref() != Some(&proxi[..]) {
+        info!("Installed in a new car, PROXI: {:02x?}", proxi);
+
+        storage.set(VEHICLE_KEY, &proxi).await?;
+    }
+
+    Ok(())
 }
 
 fn process_recv_body_computer(
     payload: BodyComputer<'_>,
+    low_voltage: bool,
     service: &ServiceLifecycle<'_, impl RawMutex>,
     status_out: &Signal<impl RawMutex, Frame>,
 ) {
     match payload {
-        BodyComputer::WakeupRequest => service.sys_start(),
+        BodyComputer::WakeupRequest if !low_voltage => service.sys_start(),
         BodyComputer::ShutDownRequest => service.sys_stop(),
         BodyComputer::StatusRequest => {
             let state = match service.get_sys_state() {
@@ -949,10 +2190,65 @@ fn process_recv_body_computer(
     }
 }
 
+/// Only published when changed, i.e. once a minute
+///
+/// NOTE: The car clock is only read, not set from the phone time. The Bluedroid HF client
+/// has no means to send arbitrary AT commands (e.g. AT+CCLK, which is not part of HFP anyway),
+/// and no other profile carries the time of the phone
+fn process_recv_datetime(
+    payload: DateTime<'_>,
+    car_clock: &StatefulSender<'_, impl RawMutex, Option<CarClock>>,
+) {
+    if let DateTime::Current {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+    } = payload
+    {
+        let clock = Some(CarClock {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+        });
+
+        car_clock.modify(|car_clock| {
+            if *car_clock != clock {
+                *car_clock = clock;
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
 fn process_recv_radio_source(
     payload: RadioSource<'_>,
+    radio_state: &mut Option<RadioState>,
     radio: &Sender<'_, impl RawMutex, RadioState>,
+    fm_station: &StatefulSender<'_, impl RawMutex, FmStation>,
 ) {
+    let frequency = if let RadioSource::Fm(frequency) = &payload {
+        Some(*frequency)
+    } else {
+        None
+    };
+
+    // The name of the previous station is stale once retuned
+    fm_station.modify(|station| {
+        if station.frequency != frequency {
+            station.frequency = frequency;
+            station.name.clear();
+            true
+        } else {
+            false
+        }
+    });
+
     let state = match payload {
         RadioSource::Fm(_) => RadioState::Fm,
         RadioSource::BtPlaying => RadioState::BtActive,
@@ -960,7 +2256,11 @@ fn process_recv_radio_source(
         RadioSource::Unknown(_) => RadioState::Unknown,
     };
 
-    radio.send(state);
+    // The radio repeats the frame periodically, only publish actual transitions
+    if *radio_state != Some(state) {
+        *radio_state = Some(state);
+        radio.send(state);
+    }
 }
 
 fn as_frame(topic: Topic<'_>) -> Frame {
@@ -0,0 +1,201 @@
+use embassy_time::{Duration, Instant};
+
+use enumset::{enum_set, EnumSet};
+
+use crate::can::message::SteeringWheelButton;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ChordAction {
+    UsbOverride,
+    ServiceMode,
+    FactoryReset,
+    /// Makes the adapter discoverable for another pairing window
+    Pairing,
+    /// Restarts without BT and audio, honoured only right after boot
+    SafeMode,
+}
+
+pub struct Chord {
+    pub buttons: EnumSet<SteeringWheelButton>,
+    pub hold: Duration,
+    pub action: ChordAction,
+}
+
+/// A chord fires once its buttons (and only those) were held for at least the chord hold time
+pub const CHORDS: &[Chord] = &[
+    Chord {
+        buttons: enum_set!(SteeringWheelButton::Mute | SteeringWheelButton::Windows),
+        hold: Duration::from_ticks(0),
+        action: ChordAction::UsbOverride,
+    },
+    Chord {
+        buttons: enum_set!(
+            SteeringWheelButton::Mute
+                | SteeringWheelButton::Windows
+                | SteeringWheelButton::VolumeUp
+        ),
+        hold: Duration::from_ticks(0),
+        action: ChordAction::ServiceMode,
+    },
+    Chord {
+        buttons: enum_set!(
+            SteeringWheelButton::Mute
+                | SteeringWheelButton::Windows
+                | SteeringWheelButton::VolumeDown
+        ),
+        hold: Duration::from_secs(5),
+        action: ChordAction::FactoryReset,
+    },
+    Chord {
+        buttons: enum_set!(
+            SteeringWheelButton::Mute | SteeringWheelButton::Windows | SteeringWheelButton::Down
+        ),
+        hold: Duration::from_secs(2),
+        action: ChordAction::Pairing,
+    },
+    Chord {
+        buttons: enum_set!(SteeringWheelButton::Mute),
+        hold: Duration::from_secs(3),
+        action: ChordAction::SafeMode,
+    },
+];
+
+pub struct ChordDetector<'a> {
+    chords: &'a [Chord],
+    pressed: EnumSet<SteeringWheelButton>,
+    since: Instant,
+    fired: bool,
+}
+
+impl<'a> ChordDetector<'a> {
+    pub const fn new(chords: &'a [Chord]) -> Self {
+        Self {
+            chords,
+            pressed: EnumSet::EMPTY,
+            since: Instant::from_ticks(0),
+            fired: false,
+        }
+    }
+
+    pub fn update(
+        &mut self,
+        pressed: EnumSet<SteeringWheelButton>,
+        now: Instant,
+    ) -> Option<ChordAction> {
+        if self.pressed != pressed {
+            self.pressed = pressed;
+            self.since = now;
+            self.fired = false;
+        }
+
+        self.poll(now)
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<ChordAction> {
+        if self.fired {
+            return None;
+        }
+
+        let held = now.duration_since(self.since);
+
+        let chord = self
+            .chords
+            .iter()
+            .find(|chord| chord.buttons == self.pressed && chord.hold <= held)?;
+
+        self.fired = true;
+
+        Some(chord.action)
+    }
+
+    /// Whether a chord is being held which did not fire yet, i.e. whether `poll` needs to be called
+    pub fn is_holding(&self) -> bool {
+        !self.fired
+            && self
+                .chords
+                .iter()
+                .any(|chord| chord.buttons == self.pressed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use SteeringWheelButton::*;
+
+    fn at(millis: u64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    #[test]
+    fn immediate_chords() {
+        let mut detector = ChordDetector::new(CHORDS);
+
+        assert_eq!(detector.update(EnumSet::only(Mute), at(0)), None);
+        assert_eq!(
+            detector.update(Mute | Windows, at(10)),
+            Some(ChordAction::UsbOverride)
+        );
+        assert_eq!(detector.update(Mute | Windows, at(20)), None);
+        assert_eq!(
+            detector.update(Mute | Windows | VolumeUp, at(30)),
+            Some(ChordAction::ServiceMode)
+        );
+        assert_eq!(detector.update(EnumSet::EMPTY, at(40)), None);
+        assert!(!detector.is_holding());
+    }
+
+    #[test]
+    fn held_chords() {
+        let mut detector = ChordDetector::new(CHORDS);
+
+        assert_eq!(detector.update(Mute | Windows | VolumeDown, at(0)), None);
+        assert!(detector.is_holding());
+        assert_eq!(detector.poll(at(4999)), None);
+        assert_eq!(detector.poll(at(5000)), Some(ChordAction::FactoryReset));
+        assert_eq!(detector.poll(at(6000)), None);
+        assert!(!detector.is_holding());
+    }
+
+    #[test]
+    fn pairing() {
+        let mut detector = ChordDetector::new(CHORDS);
+
+        assert_eq!(detector.update(Mute | Windows | Down, at(0)), None);
+        assert_eq!(detector.poll(at(1999)), None);
+        assert_eq!(detector.poll(at(2000)), Some(ChordAction::Pairing));
+    }
+
+    #[test]
+    fn safe_mode() {
+        let mut detector = ChordDetector::new(CHORDS);
+
+        assert_eq!(detector.update(EnumSet::only(Mute), at(0)), None);
+        assert_eq!(detector.poll(at(2999)), None);
+        assert_eq!(detector.poll(at(3000)), Some(ChordAction::SafeMode));
+    }
+
+    #[test]
+    fn released_early() {
+        let mut detector = ChordDetector::new(CHORDS);
+
+        assert_eq!(detector.update(Mute | Windows | VolumeDown, at(0)), None);
+        assert_eq!(
+            detector.update(Mute | Windows, at(1000)),
+            Some(ChordAction::UsbOverride)
+        );
+        assert_eq!(detector.update(Mute | Windows | VolumeDown, at(2000)), None);
+        assert_eq!(detector.poll(at(6000)), None);
+        assert_eq!(detector.poll(at(7000)), Some(ChordAction::FactoryReset));
+    }
+
+    #[test]
+    fn extra_buttons() {
+        let mut detector = ChordDetector::new(CHORDS);
+
+        assert_eq!(detector.update(Mute | Windows | Menu, at(0)), None);
+        assert!(!detector.is_holding());
+        assert_eq!(detector.poll(at(10000)), None);
+    }
+}
@@ -3,32 +3,57 @@ use core::{
     pin::pin,
 };
 
-use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
 use embassy_sync::blocking_mutex::raw::RawMutex;
 
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use enumset::EnumSet;
 
 use crate::{
+    audio::{Gains, SharedAudioBuffers},
     bus::{
-        bt::{AudioState, AudioTrackState, BtCommand, PhoneCallInfo, PhoneCallState, TrackInfo},
-        can::RadioState,
+        bt::{
+            AudioState, AudioTrackState, Bonded, BtCommand, Identity, PhoneCallInfo,
+            PhoneCallState, PlayerSettings, Takeover, TrackInfo,
+        },
+        can::{DisplayText, RadioState, Vehicle},
+        diag::BootReason,
         BusSubscription,
     },
     can::message::SteeringWheelButton,
+    chord::{ChordAction, ChordDetector, CHORDS},
+    diag,
     error::Error,
+    menu::{DtmfEntry, Menu, MenuPage},
     select_spawn::SelectSpawn,
     service::ServiceLifecycle,
-    signal::{Receiver, Sender, StatefulReceiver},
+    signal::{Receiver, Sender, StatefulReceiver, StatefulSender},
+    storage::Storage,
     usb_cutoff::UsbCutoff,
 };
 
+const CHORD_TICK: Duration = Duration::from_millis(100);
+
+/// Holding Mute requests the safe mode only that early, i.e. when held at ignition
+const SAFE_MODE_WINDOW: Duration = Duration::from_secs(10);
+
+/// With hysteresis, so that the volume does not pump around the threshold
+const SPEED_BOOST_ON_KMH: u16 = 90;
+const SPEED_BOOST_OFF_KMH: u16 = 80;
+
 struct Status {
     audio: AudioState,
     track: AudioTrackState,
     phone: AudioState,
     call: PhoneCallState,
     radio: RadioState,
+    takeover: Takeover,
+    paired: usize,
+    identity: Identity,
+    gains: Gains,
+    /// The car the gains are tuned for
+    proxi: Option<[u8; 6]>,
+    settings: PlayerSettings,
 }
 
 impl Status {
@@ -39,16 +64,29 @@ impl Status {
             phone: AudioState::Uninitialized,
             call: PhoneCallState::Idle,
             radio: RadioState::Unknown,
+            takeover: Takeover::new(),
+            paired: 0,
+            identity: Identity::new(),
+            gains: Gains::new(),
+            proxi: None,
+            settings: PlayerSettings::new(),
         }
     }
 }
 
-pub async fn process(
-    bus: BusSubscription<'_>,
+#[allow(clippy::too_many_arguments)]
+pub async fn process<const R: usize, const N: usize>(
+    bus: BusSubscription<'_, R, N>,
     mut usb_cutoff: UsbCutoff<'_>,
     button_commands: Sender<'_, impl RawMutex, BtCommand>,
+    cockpit_display: StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    storage: &Storage,
+    boot: BootReason,
 ) -> Result<(), Error> {
-    let usb_cutoff_disable_period = Cell::new(true);
+    // After a crank-induced reset the user is not at the wheel pressing buttons,
+    // so there is no point in waiting for the override chord
+    let usb_cutoff_disable_period = Cell::new(!boot.is_unclean());
     let usb_cutoff_disable = Cell::new(false);
     let service_mode = Cell::new(false);
 
@@ -56,6 +94,7 @@ pub async fn process(
         let _started = bus.service.started_when_enabled().await?;
 
         let status = RefCell::new(Status::new());
+        let menu = RefCell::new(Menu::new());
 
         SelectSpawn::run(&mut pin!(bus.service.wait_disabled()))
             .chain(&mut pin!(process_usb_cutoff(
@@ -68,18 +107,37 @@ pub async fn process(
             .chain(&mut pin!(process_buttons(
                 &bus.buttons,
                 &status,
+                &menu,
                 &usb_cutoff_disable_period,
                 &usb_cutoff_disable,
                 &service_mode,
                 &button_commands,
+                &cockpit_display,
+                audio_buffers,
+                storage,
             )))
+            .chain(&mut pin!(process_vehicle(
+                &bus.vehicle,
+                &status,
+                &menu,
+                &cockpit_display,
+                audio_buffers,
+                storage,
+            )))
+            .chain(&mut pin!(process_speed(&bus.speed, audio_buffers)))
+            .chain(&mut pin!(process_parking(&bus.parking, audio_buffers)))
             .chain(&mut pin!(process_status(
                 &bus.audio,
                 &bus.audio_track,
                 &bus.phone,
                 &bus.phone_call,
                 &bus.radio,
+                &bus.takeover,
+                &bus.bonded,
+                &bus.identity,
                 &status,
+                &menu,
+                &cockpit_display,
             )))
             .await?;
     }
@@ -99,105 +157,411 @@ async fn process_usb_cutoff(
 
     if !usb_cutoff_disable.get() {
         usb_cutoff.cutoff()?;
-    } else if !service_mode.get() {
+    } else if service_mode.get() {
+        service.sys_set_service_mode();
+    } else {
         service.sys_set_normal_mode();
     }
 
     core::future::pending().await
 }
 
-async fn process_buttons(
+/// The gains are tuned per car, so they are reloaded whenever the car is (re-)identified
+async fn process_vehicle<const N: usize>(
+    vehicle: &StatefulReceiver<'_, impl RawMutex, Vehicle>,
+    status: &RefCell<Status>,
+    menu: &RefCell<Menu>,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    storage: &Storage,
+) -> Result<(), Error> {
+    loop {
+        let proxi = vehicle.state(|vehicle| vehicle.proxi);
+        let gains = Gains::load(storage, proxi).await?;
+
+        let mut status = status.borrow_mut();
+        status.proxi = proxi;
+
+        if status.gains != gains {
+            status.gains = gains;
+
+            audio_buffers.lock(|buffers| buffers.borrow_mut().set_gains(gains));
+
+            let menu = menu.borrow();
+
+            if menu.is_open() {
+                render_menu(&menu, &status, cockpit_display);
+            }
+        }
+
+        drop(status);
+
+        vehicle.recv().await;
+    }
+}
+
+async fn process_speed(
+    speed: &Receiver<'_, impl RawMutex, u16>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+) -> Result<(), Error> {
+    let mut boost = false;
+
+    loop {
+        let kmh = speed.recv().await;
+
+        let new = if boost {
+            kmh > SPEED_BOOST_OFF_KMH
+        } else {
+            kmh >= SPEED_BOOST_ON_KMH
+        };
+
+        if new != boost {
+            boost = new;
+
+            audio_buffers.lock(|buffers| buffers.borrow_mut().set_speed_boost(boost));
+        }
+    }
+}
+
+async fn process_parking(
+    parking: &Receiver<'_, impl RawMutex, bool>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+) -> Result<(), Error> {
+    loop {
+        let active = parking.recv().await;
+
+        audio_buffers.lock(|buffers| buffers.borrow_mut().set_parking(active));
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_buttons<const N: usize>(
     buttons: &Receiver<'_, impl RawMutex, EnumSet<SteeringWheelButton>>,
     status: &RefCell<Status>,
+    menu: &RefCell<Menu>,
     usb_cutoff_disable_period: &Cell<bool>,
     usb_cutoff_disable: &Cell<bool>,
     service_mode: &Cell<bool>,
     button_commands: &Sender<'_, impl RawMutex, BtCommand>,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+    audio_buffers: &SharedAudioBuffers<'_>,
+    storage: &Storage,
 ) -> Result<(), Error> {
     let mut sbuttons = EnumSet::EMPTY;
-    let mut conf = false;
-    let mut menu = false;
+    let mut dtmf = DtmfEntry::new();
+    let mut chords = ChordDetector::new(CHORDS);
 
     loop {
-        let buttons = buttons.recv().await;
-        let just_pressed = sbuttons.intersection(buttons);
+        let new = if chords.is_holding() {
+            select(buttons.recv(), Timer::after(CHORD_TICK)).await
+        } else {
+            Either::First(buttons.recv().await)
+        };
+
+        let now = Instant::now();
+
+        let (chord, buttons) = match new {
+            Either::First(buttons) => (chords.update(buttons, now), buttons),
+            Either::Second(_) => (chords.poll(now), sbuttons),
+        };
+
+        if let Some(chord) = chord {
+            handle_chord(
+                chord,
+                usb_cutoff_disable_period,
+                usb_cutoff_disable,
+                service_mode,
+                button_commands,
+                storage,
+            )
+            .await?;
+        }
+
+        if matches!(new, Either::Second(_)) {
+            continue;
+        }
+
+        let just_pressed = just_pressed(sbuttons, buttons);
 
         sbuttons = buttons;
 
-        let status = status.borrow();
+        let mut gains = status.borrow().gains;
 
-        if status.phone.is_active() {
-            conf = false;
-        } else if usb_cutoff_disable_period.get()
-            && sbuttons.contains(SteeringWheelButton::Mute)
-            && sbuttons.contains(SteeringWheelButton::Windows)
         {
-            usb_cutoff_disable.set(true);
+            let status = status.borrow();
+
+            let mut menu = menu.borrow_mut();
+            let page = menu.page();
+            let key = dtmf.key();
 
-            if sbuttons.contains(SteeringWheelButton::VolumeUp) {
-                service_mode.set(true);
+            handle_run(
+                just_pressed,
+                &mut menu,
+                &mut dtmf,
+                &mut gains,
+                &status,
+                button_commands,
+            );
+
+            if dtmf.key() != key {
+                render_dtmf(&dtmf, cockpit_display);
+            }
+
+            // While the driver is asked about a second phone, the prompt owns the display
+            if menu.page() != page && !status.takeover.pending {
+                render_menu(&menu, &status, cockpit_display);
             }
-        } else {
-            conf = !conf;
         }
 
-        if conf {
-            handle_conf(just_pressed, &status, button_commands);
-        } else {
-            handle_run(just_pressed, &mut menu, &status, button_commands);
+        if gains != status.borrow().gains {
+            let mut status = status.borrow_mut();
+            status.gains = gains;
+
+            audio_buffers.lock(|buffers| buffers.borrow_mut().set_gains(gains));
+
+            let menu = menu.borrow();
+
+            if menu.is_open() {
+                render_menu(&menu, &status, cockpit_display);
+            }
+
+            let proxi = status.proxi;
+
+            drop(status);
+
+            gains.save(storage, proxi).await?;
         }
     }
 }
 
-fn handle_conf(
-    _just_pressed: EnumSet<SteeringWheelButton>,
-    _status: &Status,
-    _button_commands: &Sender<'_, impl RawMutex, BtCommand>,
-) {
-    // TODO
+async fn handle_chord(
+    chord: ChordAction,
+    usb_cutoff_disable_period: &Cell<bool>,
+    usb_cutoff_disable: &Cell<bool>,
+    service_mode: &Cell<bool>,
+    button_commands: &Sender<'_, impl RawMutex, BtCommand>,
+    storage: &Storage,
+) -> Result<(), Error> {
+    match chord {
+        ChordAction::UsbOverride if usb_cutoff_disable_period.get() => {
+            usb_cutoff_disable.set(true);
+        }
+        ChordAction::ServiceMode if usb_cutoff_disable_period.get() => {
+            usb_cutoff_disable.set(true);
+            service_mode.set(true);
+        }
+        ChordAction::FactoryReset => storage.factory_reset().await?,
+        ChordAction::Pairing => button_commands.send(BtCommand::OpenPairing),
+        ChordAction::SafeMode if Instant::now() < Instant::from_ticks(0) + SAFE_MODE_WINDOW => {
+            storage.set_u32(diag::SAFE_MODE_KEY, 1).await?;
+            storage.restart().await?;
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// The buttons pressed since the previous state, as the held and the released ones do nothing
+fn just_pressed(
+    previous: EnumSet<SteeringWheelButton>,
+    buttons: EnumSet<SteeringWheelButton>,
+) -> EnumSet<SteeringWheelButton> {
+    buttons.difference(previous)
 }
 
 fn handle_run(
     just_pressed: EnumSet<SteeringWheelButton>,
-    menu: &mut bool,
+    menu: &mut Menu,
+    dtmf: &mut DtmfEntry,
+    gains: &mut Gains,
     status: &Status,
     button_commands: &Sender<'_, impl RawMutex, BtCommand>,
 ) {
-    if status.phone.is_active() {
-        *menu = false;
+    if !matches!(status.call, PhoneCallState::CallActive) {
+        dtmf.close();
     }
 
-    if *menu {
-        handle_phone_menu(just_pressed, menu, status, button_commands);
+    if status.phone.is_active() || status.takeover.pending {
+        menu.close();
+    }
+
+    if status.takeover.pending {
+        handle_takeover(just_pressed, button_commands);
+    } else if menu.is_open() {
+        handle_menu(just_pressed, menu, gains, status, button_commands);
     } else {
-        handle_shortcuts(just_pressed, menu, status, button_commands);
+        handle_shortcuts(just_pressed, menu, dtmf, gains, status, button_commands);
+    }
+}
+
+fn handle_takeover(
+    just_pressed: EnumSet<SteeringWheelButton>,
+    button_commands: &Sender<'_, impl RawMutex, BtCommand>,
+) {
+    if just_pressed.contains(SteeringWheelButton::Menu) {
+        button_commands.send(BtCommand::AcceptTakeover);
+    } else if just_pressed.contains(SteeringWheelButton::Down) {
+        button_commands.send(BtCommand::RejectTakeover);
     }
 }
 
-fn handle_phone_menu(
+fn handle_menu(
     just_pressed: EnumSet<SteeringWheelButton>,
-    menu: &mut bool,
-    _status: &Status,
-    _button_commands: &Sender<'_, impl RawMutex, BtCommand>,
+    menu: &mut Menu,
+    gains: &mut Gains,
+    status: &Status,
+    button_commands: &Sender<'_, impl RawMutex, BtCommand>,
 ) {
-    // TODO
     if just_pressed.contains(SteeringWheelButton::Up) {
-        *menu = false;
+        menu.close();
+    } else if just_pressed.contains(SteeringWheelButton::Down) {
+        menu.next();
+    } else if just_pressed.contains(SteeringWheelButton::Menu) {
+        match menu.page() {
+            Some(MenuPage::LowLatency) => button_commands.send(BtCommand::ToggleLowLatency),
+            Some(MenuPage::Shuffle) => button_commands.send(BtCommand::ToggleShuffle),
+            Some(MenuPage::Repeat) => button_commands.send(BtCommand::ToggleRepeat),
+            Some(MenuPage::Volume) => button_commands.send(BtCommand::CycleVolumeControl),
+            Some(MenuPage::BlockDevice) => {
+                button_commands.send(BtCommand::BlockDevice);
+                menu.close();
+            }
+            Some(MenuPage::UnblockAll) => button_commands.send(BtCommand::UnblockAll),
+            Some(MenuPage::NewPhone) => {
+                button_commands.send(BtCommand::SetArbitration(status.takeover.policy.next()))
+            }
+            Some(MenuPage::PairNew) => button_commands.send(BtCommand::OpenPairing),
+            Some(MenuPage::Paired) => button_commands.send(BtCommand::UnpairAll),
+            Some(MenuPage::Name) => {
+                let mut identity = status.identity;
+                identity.suffix = (identity.suffix + 1) % (Identity::MAX_SUFFIX + 1);
+
+                button_commands.send(BtCommand::SetIdentity(identity));
+            }
+            Some(MenuPage::MusicGain) => gains.music = Gains::next(gains.music),
+            Some(MenuPage::CallGain) => gains.call = Gains::next(gains.call),
+            Some(MenuPage::VolumeKeys) => gains.volume_keys = !gains.volume_keys,
+            Some(MenuPage::SpeedVolume) => gains.speed_volume = !gains.speed_volume,
+            Some(MenuPage::ParkingDuck) => gains.parking_duck = !gains.parking_duck,
+            Some(MenuPage::BtTest) => button_commands.send(BtCommand::TestMode),
+            _ => (),
+        }
     }
 }
 
+fn render_menu<const N: usize>(
+    menu: &Menu,
+    status: &Status,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+) {
+    cockpit_display.modify(|display| {
+        if let Some(page) = menu.page() {
+            display.version += 1;
+            display.menu = true;
+            page.render(
+                status.takeover.policy,
+                status.paired,
+                &status.identity,
+                &status.gains,
+                &status.settings,
+                &mut display.text,
+            );
+        } else {
+            display.reset();
+        }
+
+        true
+    });
+}
+
+fn render_dtmf<const N: usize>(
+    dtmf: &DtmfEntry,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+) {
+    cockpit_display.modify(|display| {
+        if dtmf.is_open() {
+            display.version += 1;
+            display.menu = true;
+            dtmf.render(&mut display.text);
+        } else {
+            display.reset();
+        }
+
+        true
+    });
+}
+
+fn render_takeover<const N: usize>(
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+) {
+    cockpit_display.modify(|display| {
+        display.update_text("NEW PHONE?");
+        display.menu = true;
+
+        true
+    });
+}
+
 fn handle_shortcuts(
     just_pressed: EnumSet<SteeringWheelButton>,
-    menu: &mut bool,
+    menu: &mut Menu,
+    dtmf: &mut DtmfEntry,
+    gains: &mut Gains,
     status: &Status,
     button_commands: &Sender<'_, impl RawMutex, BtCommand>,
 ) {
     match status.call {
+        // The radio adjusts its own volume as well, the call gain only adds to it
+        call if call.is_in_call()
+            && gains.volume_keys
+            && (just_pressed.contains(SteeringWheelButton::VolumeUp)
+                || just_pressed.contains(SteeringWheelButton::VolumeDown)) =>
+        {
+            if just_pressed.contains(SteeringWheelButton::VolumeUp) {
+                gains.call = (gains.call + 1).min(Gains::MAX);
+            } else if just_pressed.contains(SteeringWheelButton::VolumeDown) {
+                gains.call = (gains.call - 1).max(Gains::MIN);
+            }
+        }
+        PhoneCallState::CallActive if dtmf.is_open() => {
+            if just_pressed.contains(SteeringWheelButton::Up) {
+                dtmf.prev();
+            } else if just_pressed.contains(SteeringWheelButton::Down) {
+                dtmf.next();
+            } else if just_pressed.contains(SteeringWheelButton::Menu) {
+                button_commands.send(BtCommand::Dtmf(dtmf.key().unwrap()));
+            } else if just_pressed.contains(SteeringWheelButton::Mute) {
+                dtmf.close();
+            }
+        }
+        PhoneCallState::CallActive
+            if just_pressed.contains(SteeringWheelButton::Up)
+                || just_pressed.contains(SteeringWheelButton::Down) =>
+        {
+            dtmf.open();
+        }
         PhoneCallState::Dialing | PhoneCallState::DialingAlerting | PhoneCallState::CallActive => {
             if just_pressed.contains(SteeringWheelButton::Menu) {
                 button_commands.send(BtCommand::Hangup);
             }
         }
+        PhoneCallState::CallWaiting => {
+            if just_pressed.contains(SteeringWheelButton::Menu) {
+                button_commands.send(BtCommand::Hold);
+            } else if just_pressed.contains(SteeringWheelButton::Down) {
+                button_commands.send(BtCommand::RejectWaiting);
+            }
+        }
+        PhoneCallState::OnHold => {
+            if just_pressed.contains(SteeringWheelButton::Menu) {
+                button_commands.send(BtCommand::Hangup);
+            } else if just_pressed.contains(SteeringWheelButton::Up) {
+                button_commands.send(BtCommand::Swap);
+            } else if just_pressed.contains(SteeringWheelButton::Down) {
+                button_commands.send(BtCommand::Merge);
+            }
+        }
         PhoneCallState::Ringing => {
             if just_pressed.contains(SteeringWheelButton::Menu) {
                 button_commands.send(BtCommand::Answer);
@@ -207,9 +571,17 @@ fn handle_shortcuts(
         }
         PhoneCallState::Idle => {
             if just_pressed.contains(SteeringWheelButton::Menu) {
-                *menu = true;
+                menu.open();
+            } else if just_pressed.contains(SteeringWheelButton::Windows)
+                && status.phone.is_connected()
+            {
+                button_commands.send(BtCommand::VoiceAssistant);
             } else if status.radio.is_bt_active() && status.audio.is_connected() {
-                if just_pressed.contains(SteeringWheelButton::Mute) {
+                if just_pressed.contains(SteeringWheelButton::VolumeUp) {
+                    button_commands.send(BtCommand::VolumeUp);
+                } else if just_pressed.contains(SteeringWheelButton::VolumeDown) {
+                    button_commands.send(BtCommand::VolumeDown);
+                } else if just_pressed.contains(SteeringWheelButton::Mute) {
                     if matches!(status.audio, AudioState::Streaming) {
                         button_commands.send(BtCommand::Pause);
                     } else if matches!(status.audio, AudioState::Connected | AudioState::Suspended)
@@ -230,17 +602,24 @@ fn handle_shortcuts(
     }
 }
 
-async fn process_status(
+#[allow(clippy::too_many_arguments)]
+async fn process_status<const N: usize>(
     audio: &Receiver<'_, impl RawMutex, AudioState>,
     audio_track: &StatefulReceiver<'_, impl RawMutex, TrackInfo>,
     phone: &Receiver<'_, impl RawMutex, AudioState>,
     phone_call: &StatefulReceiver<'_, impl RawMutex, PhoneCallInfo>,
     radio: &Receiver<'_, impl RawMutex, RadioState>,
+    takeover: &StatefulReceiver<'_, impl RawMutex, Takeover>,
+    bonded: &StatefulReceiver<'_, impl RawMutex, Bonded>,
+    identity: &StatefulReceiver<'_, impl RawMutex, Identity>,
     status: &RefCell<Status>,
+    menu: &RefCell<Menu>,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
 ) -> Result<(), Error> {
     loop {
-        match select(
+        match select3(
             radio.recv(),
+            select3(takeover.recv(), bonded.recv(), identity.recv()),
             select4(
                 audio.recv(),
                 audio_track.recv(),
@@ -250,15 +629,166 @@ async fn process_status(
         )
         .await
         {
-            Either::First(new) => status.borrow_mut().radio = new,
-            Either::Second(Either4::First(new)) => status.borrow_mut().audio = new,
-            Either::Second(Either4::Second(_)) => {
-                status.borrow_mut().track = audio_track.state(|track| track.state)
+            Either3::First(new) => status.borrow_mut().radio = new,
+            Either3::Second(Either3::First(_)) => {
+                let new = takeover.state(|takeover| *takeover);
+
+                let mut status = status.borrow_mut();
+                let old = core::mem::replace(&mut status.takeover, new);
+
+                let mut menu = menu.borrow_mut();
+
+                if new.pending != old.pending {
+                    menu.close();
+
+                    if new.pending {
+                        render_takeover(cockpit_display);
+                    } else {
+                        render_menu(&menu, &status, cockpit_display);
+                    }
+                } else if new.policy != old.policy && menu.is_open() {
+                    render_menu(&menu, &status, cockpit_display);
+                }
+            }
+            Either3::Second(Either3::Second(_)) => {
+                let mut status = status.borrow_mut();
+                status.paired = bonded.state(|bonded| bonded.devices.len());
+
+                let menu = menu.borrow();
+
+                if menu.is_open() && !status.takeover.pending {
+                    render_menu(&menu, &status, cockpit_display);
+                }
+            }
+            Either3::Second(Either3::Third(_)) => {
+                let mut status = status.borrow_mut();
+                status.identity = identity.state(|identity| *identity);
+
+                let menu = menu.borrow();
+
+                if menu.is_open() && !status.takeover.pending {
+                    render_menu(&menu, &status, cockpit_display);
+                }
+            }
+            Either3::Third(Either4::First(new)) => status.borrow_mut().audio = new,
+            Either3::Third(Either4::Second(_)) => {
+                let mut status = status.borrow_mut();
+
+                let (track, settings) = audio_track.state(|track| (track.state, track.settings));
+                status.track = track;
+
+                if status.settings != settings {
+                    status.settings = settings;
+
+                    let menu = menu.borrow();
+
+                    if menu.is_open() && !status.takeover.pending {
+                        render_menu(&menu, &status, cockpit_display);
+                    }
+                }
             }
-            Either::Second(Either4::Third(new)) => status.borrow_mut().phone = new,
-            Either::Second(Either4::Fourth(_)) => {
+            Either3::Third(Either4::Third(new)) => status.borrow_mut().phone = new,
+            Either3::Third(Either4::Fourth(_)) => {
                 status.borrow_mut().call = phone_call.state(|call| call.state)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::task::Poll;
+
+    use embassy_futures::poll_once;
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use crate::bus::Service;
+    use crate::signal::BroadcastSignal;
+
+    use super::*;
+
+    /// Replays the button states through the button handling, returning the commands sent
+    fn replay(
+        status: &Status,
+        menu: &mut Menu,
+        states: &[EnumSet<SteeringWheelButton>],
+    ) -> Vec<BtCommand> {
+        let commands = BroadcastSignal::<NoopRawMutex, _, 1>::subscribed([Service::Commands]);
+        let receiver = commands.receiver(Service::Commands);
+
+        let mut dtmf = DtmfEntry::new();
+        let mut gains = status.gains;
+        let mut previous = EnumSet::EMPTY;
+
+        states
+            .iter()
+            .filter_map(|buttons| {
+                handle_run(
+                    just_pressed(previous, *buttons),
+                    menu,
+                    &mut dtmf,
+                    &mut gains,
+                    status,
+                    &commands.sender(),
+                );
+
+                previous = *buttons;
+
+                match poll_once(receiver.recv()) {
+                    Poll::Ready(command) => Some(command),
+                    Poll::Pending => None,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn press_release() {
+        let mut status = Status::new();
+        status.takeover.pending = true;
+
+        let menu = SteeringWheelButton::Menu.into();
+
+        let commands = replay(
+            &status,
+            &mut Menu::new(),
+            &[menu, EnumSet::EMPTY, menu, EnumSet::EMPTY],
+        );
+
+        assert_eq!(
+            commands,
+            [BtCommand::AcceptTakeover, BtCommand::AcceptTakeover]
+        );
+    }
+
+    #[test]
+    fn held() {
+        let mut status = Status::new();
+        status.call = PhoneCallState::Ringing;
+
+        let menu = EnumSet::only(SteeringWheelButton::Menu);
+        let chord = menu | SteeringWheelButton::Down;
+
+        let commands = replay(
+            &status,
+            &mut Menu::new(),
+            &[menu, menu, chord, EnumSet::EMPTY],
+        );
+
+        assert_eq!(commands, [BtCommand::Answer, BtCommand::Reject]);
+    }
+
+    #[test]
+    fn menu_opened() {
+        let mut menu = Menu::new();
+
+        let commands = replay(
+            &Status::new(),
+            &mut menu,
+            &[SteeringWheelButton::Menu.into(), EnumSet::EMPTY],
+        );
+
+        assert!(commands.is_empty());
+        assert!(menu.is_open());
+    }
+}
@@ -0,0 +1,434 @@
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_futures::select::{select3, Either3};
+
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use esp_idf_svc::log::EspLogger;
+use esp_idf_svc::sys::{
+    esp, esp_spp_cb_event_t, esp_spp_cb_event_t_ESP_SPP_CLOSE_EVT,
+    esp_spp_cb_event_t_ESP_SPP_DATA_IND_EVT, esp_spp_cb_event_t_ESP_SPP_INIT_EVT,
+    esp_spp_cb_event_t_ESP_SPP_SRV_OPEN_EVT, esp_spp_cb_param_t, esp_spp_cfg_t, esp_spp_deinit,
+    esp_spp_disconnect, esp_spp_enhanced_init, esp_spp_mode_t_ESP_SPP_MODE_CB,
+    esp_spp_register_callback, esp_spp_role_t_ESP_SPP_ROLE_SLAVE, esp_spp_start_srv, esp_spp_write,
+    ESP_SPP_SEC_AUTHENTICATE,
+};
+
+use log::{info, warn, Log, Metadata, Record};
+
+use crate::arena::Arena;
+use crate::bus::{
+    can::{Notification, RawFrame},
+    diag::Diagnostic,
+    BusSubscription, UpdateRequest,
+};
+use crate::can;
+use crate::devices::{BtAddr, SharedDevices};
+use crate::error::Error;
+use crate::signal::{QueueSender, Sender, StatefulSender};
+use crate::sniffer;
+use crate::storage::Storage;
+use crate::trace::{self, Profile};
+
+const SERVER_NAME: &[u8] = b"FIAT CONSOLE\0";
+
+const LINE_LEN: usize = 64;
+const LOG_LINE_LEN: usize = 256;
+
+/// The log lines not yet written to the client
+const OUTPUT_LEN: usize = 2048;
+
+/// The chunks the output is written in, from a buffer borrowed from the arena
+pub const WRITE_BUF_SIZE: usize = 256;
+
+const REFUSED_DURATION: core::time::Duration = core::time::Duration::from_secs(5);
+
+type Line = heapless::String<LINE_LEN>;
+
+/// The connected SPP client, 0 if none
+static HANDLE: Mutex<CriticalSectionRawMutex, RefCell<u32>> = Mutex::new(RefCell::new(0));
+
+/// A client which opened the console, and is only let in once found admissible
+static OPENED: Signal<CriticalSectionRawMutex, (u32, BtAddr)> = Signal::new();
+
+static INPUT: Mutex<CriticalSectionRawMutex, RefCell<Line>> =
+    Mutex::new(RefCell::new(heapless::String::new()));
+static LINES: Signal<CriticalSectionRawMutex, Line> = Signal::new();
+
+type Output = heapless::Deque<u8, OUTPUT_LEN>;
+
+static OUTPUT: Mutex<CriticalSectionRawMutex, RefCell<Output>> =
+    Mutex::new(RefCell::new(heapless::Deque::new()));
+static OUTPUT_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+static LOGGER: Logger = Logger(EspLogger::new());
+
+/// Logs to the UART as usual, and also to the console client, if one is connected. The lines
+/// for the client are only buffered here, as the logging task might be a Bluedroid callback,
+/// and are written to the client from the console task
+pub struct Logger(EspLogger);
+
+impl Logger {
+    pub fn initialize() {
+        log::set_logger(&LOGGER).unwrap();
+        LOGGER.0.initialize();
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.log(record);
+
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let handle = HANDLE.lock(|handle| *handle.borrow());
+
+        if handle != 0 {
+            let mut line = heapless::String::<LOG_LINE_LEN>::new();
+
+            // Long lines are rather cut than dropped
+            let _ = write!(
+                &mut line,
+                "{} {}: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            let _ = line.push_str("\r\n");
+
+            OUTPUT.lock(|output| push_line(&mut output.borrow_mut(), &line));
+            OUTPUT_READY.signal(());
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+/// The Serial Port Profile server of the console, for as long as it is not dropped
+pub struct Spp(());
+
+impl Spp {
+    /// Needs the Bluetooth driver to be initialized
+    pub fn new() -> Result<Self, Error> {
+        esp!(unsafe { esp_spp_register_callback(Some(handle_spp)) })?;
+
+        let config = esp_spp_cfg_t {
+            mode: esp_spp_mode_t_ESP_SPP_MODE_CB,
+            enable_l2cap_ertm: true,
+            tx_buffer_size: 0,
+        };
+
+        esp!(unsafe { esp_spp_enhanced_init(&config) })?;
+
+        Ok(Self(()))
+    }
+}
+
+impl Drop for Spp {
+    fn drop(&mut self) {
+        HANDLE.lock(|handle| *handle.borrow_mut() = 0);
+        OUTPUT.lock(|output| output.borrow_mut().clear());
+
+        unsafe {
+            esp_spp_deinit();
+        }
+    }
+}
+
+/// Runs the commands typed on the console:
+/// - `state`: dumps the Bluetooth state
+/// - `can <id> <data>`: sends a frame on the CAN bus, both in hex (e.g. `can 0a294000 0c00`).
+///   Refused unless in service mode, so that no phone writes to the bus while driving
+/// - `trace <a2dp|avrcp|hfp> <on|off>`: traces the events of a Bluetooth profile
+/// - `trace`: dumps and clears the traced events
+/// - `sniff [clear]`: dumps (or clears) the unknown CAN frames recorded in service mode
+/// - `debounce <ms>`: sets the debounce time of the steering wheel buttons
+/// - `update [check]`: switches to the update mode (which closes the console), and with `check`
+///   only reports whether a newer firmware is available on the display. Refused while driving
+pub async fn process(
+    bus: &BusSubscription<'_>,
+    update: &Sender<'_, impl RawMutex, UpdateRequest>,
+    notification: &StatefulSender<'_, impl RawMutex, Notification>,
+    can_frames: &Sender<'_, impl RawMutex, RawFrame>,
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
+    arena: &Arena<'_>,
+    devices: &SharedDevices,
+    storage: &Storage,
+) -> Result<(), Error> {
+    let mut chunk = arena.borrow(WRITE_BUF_SIZE);
+
+    if chunk.is_none() {
+        diagnostics.send(Diagnostic::ArenaExhausted {
+            len: WRITE_BUF_SIZE,
+        });
+    }
+
+    loop {
+        let line = match select3(LINES.wait(), OUTPUT_READY.wait(), OPENED.wait()).await {
+            Either3::First(line) => line,
+            Either3::Second(_) => {
+                match chunk.as_deref_mut() {
+                    Some(chunk) => write_output(chunk),
+                    // The client only gets the replies on the UART then
+                    None => OUTPUT.lock(|output| output.borrow_mut().clear()),
+                }
+
+                continue;
+            }
+            Either3::Third((handle, addr)) => {
+                admit(handle, &addr, devices);
+                continue;
+            }
+        };
+
+        let mut args = line.split_whitespace();
+
+        match args.next() {
+            Some("state") => {
+                info!("System: {:?}", bus.service.get_sys_state());
+                info!("Ignition: {:?}", bus.service.ignition());
+                bus.audio_track.state(|track| info!("Track: {:?}", track));
+                bus.phone_call.state(|call| info!("Call: {:?}", call));
+                bus.hfp_indicators
+                    .state(|indicators| info!("Indicators: {:?}", indicators));
+                bus.link.state(|link| info!("Link: {:?}", link));
+                bus.car_clock.state(|clock| info!("Car clock: {:?}", clock));
+            }
+            Some("can") if !bus.service.is_service_mode() => {
+                warn!("Sending CAN frames is only available in service mode")
+            }
+            Some("can") => match parse_frame(args.next(), args.next()) {
+                Some(frame) => can_frames.send(frame),
+                None => warn!("Usage: can <id> <data>"),
+            },
+            Some("trace") => match (args.next(), args.next()) {
+                (None, _) => trace::dump(),
+                (Some(profile), Some(state @ ("on" | "off"))) => match Profile::parse(profile) {
+                    Some(profile) => {
+                        trace::enable(profile, state == "on");
+                        info!("Tracing: {:?}", trace::enabled_profiles());
+                    }
+                    None => warn!("Unknown profile: {}", profile),
+                },
+                _ => warn!("Usage: trace [<a2dp|avrcp|hfp> <on|off>]"),
+            },
+            Some("sniff") => match args.next() {
+                None => sniffer::dump(storage).await?,
+                Some("clear") => sniffer::clear(storage).await?,
+                _ => warn!("Usage: sniff [clear]"),
+            },
+            Some("debounce") => match args.next().and_then(|ms| ms.parse::<u32>().ok()) {
+                Some(ms) if ms <= can::MAX_DEBOUNCE_MS => {
+                    storage.set_u32(can::DEBOUNCE_KEY, ms).await?;
+                    info!("Debounce: {}ms, from the next CAN driver start", ms);
+                }
+                _ => warn!("Usage: debounce <0-{}>", can::MAX_DEBOUNCE_MS),
+            },
+            Some("update") => {
+                let request = match args.next() {
+                    None => UpdateRequest::Install,
+                    Some("check") => UpdateRequest::Check,
+                    Some(_) => {
+                        warn!("Usage: update [check]");
+                        continue;
+                    }
+                };
+
+                if bus.service.sys_set_update_mode() {
+                    info!("Entering update mode: {:?}", request);
+
+                    update.send(request);
+                } else {
+                    warn!("Not entering update mode while the car may be driven");
+
+                    notification.modify(|notification| {
+                        notification.post("NO UPDATE WHILE DRIVING", REFUSED_DURATION);
+                        true
+                    });
+                }
+            }
+            Some(other) => warn!("Unknown command: {}", other),
+            None => (),
+        }
+    }
+}
+
+/// Lines not fitting are dropped whole, as the client is lagging behind anyway
+fn push_line(output: &mut Output, line: &str) -> bool {
+    if output.capacity() - output.len() < line.len() {
+        return false;
+    }
+
+    for byte in line.bytes() {
+        let _ = output.push_back(byte);
+    }
+
+    true
+}
+
+/// Lossy while the link is congested, which is fine for a debug console
+fn write_output(chunk: &mut [u8]) {
+    let handle = HANDLE.lock(|handle| *handle.borrow());
+
+    loop {
+        let len = OUTPUT.lock(|output| {
+            let mut output = output.borrow_mut();
+
+            chunk
+                .iter_mut()
+                .map_while(|byte| output.pop_front().map(|popped| *byte = popped))
+                .count()
+        });
+
+        if len == 0 {
+            break;
+        }
+
+        if handle != 0 {
+            unsafe {
+                esp_spp_write(handle, len as _, chunk.as_mut_ptr());
+            }
+        }
+    }
+}
+
+/// The SPP server only asks for authentication, which any bonded phone passes, blocked or not
+fn admit(handle: u32, addr: &BtAddr, devices: &SharedDevices) {
+    if devices.lock(|devices| devices.borrow().is_admissible(addr)) {
+        HANDLE.lock(|current| *current.borrow_mut() = handle);
+
+        info!("Console connected");
+    } else {
+        warn!("Console refused to {:?}", addr);
+
+        if let Err(err) = esp!(unsafe { esp_spp_disconnect(handle) }) {
+            warn!("Disconnecting the console failed: {}", err);
+        }
+    }
+}
+
+fn parse_frame(id: Option<&str>, data: Option<&str>) -> Option<RawFrame> {
+    let id = u32::from_str_radix(id?, 16).ok()?;
+    let data = data.unwrap_or("");
+
+    if data.len() % 2 != 0 || data.len() > 16 {
+        return None;
+    }
+
+    let mut frame = RawFrame {
+        id,
+        data: heapless::Vec::new(),
+    };
+
+    for offset in (0..data.len()).step_by(2) {
+        let byte = u8::from_str_radix(data.get(offset..offset + 2)?, 16).ok()?;
+
+        frame.data.push(byte).ok()?;
+    }
+
+    Some(frame)
+}
+
+/// Called on the Bluedroid task
+unsafe extern "C" fn handle_spp(event: esp_spp_cb_event_t, param: *mut esp_spp_cb_param_t) {
+    #[allow(non_upper_case_globals)]
+    match event {
+        esp_spp_cb_event_t_ESP_SPP_INIT_EVT => {
+            let result = esp!(esp_spp_start_srv(
+                ESP_SPP_SEC_AUTHENTICATE as _,
+                esp_spp_role_t_ESP_SPP_ROLE_SLAVE,
+                0,
+                SERVER_NAME.as_ptr() as _,
+            ));
+
+            if let Err(err) = result {
+                warn!("Starting the console server failed: {}", err);
+            }
+        }
+        esp_spp_cb_event_t_ESP_SPP_SRV_OPEN_EVT => {
+            OPENED.signal(((*param).srv_open.handle, (*param).srv_open.rem_bda));
+        }
+        esp_spp_cb_event_t_ESP_SPP_CLOSE_EVT => {
+            // Refused clients close as well
+            HANDLE.lock(|handle| {
+                let mut handle = handle.borrow_mut();
+
+                if *handle == (*param).close.handle {
+                    *handle = 0;
+                }
+            });
+        }
+        esp_spp_cb_event_t_ESP_SPP_DATA_IND_EVT
+            if HANDLE.lock(|handle| *handle.borrow()) == (*param).data_ind.handle =>
+        {
+            let data =
+                core::slice::from_raw_parts((*param).data_ind.data, (*param).data_ind.len as usize);
+
+            INPUT.lock(|input| {
+                let mut input = input.borrow_mut();
+
+                for byte in data {
+                    match byte {
+                        b'\r' | b'\n' if !input.is_empty() => {
+                            LINES.signal(input.clone());
+                            input.clear();
+                        }
+                        b'\r' | b'\n' => (),
+                        // Overlong lines are cut
+                        byte if byte.is_ascii() => {
+                            let _ = input.push(*byte as char);
+                        }
+                        _ => (),
+                    }
+                }
+            });
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_lines() {
+        let mut output = Output::new();
+
+        let line = "I bt: 0123456789012345678901234567890123456789012345678901234567\r\n";
+
+        let pushed = (0..OUTPUT_LEN)
+            .take_while(|_| push_line(&mut output, line))
+            .count();
+
+        assert_eq!(pushed, OUTPUT_LEN / line.len());
+        assert_eq!(output.len(), pushed * line.len());
+
+        assert!(push_line(&mut output, "\r\n"));
+    }
+
+    #[test]
+    fn frames() {
+        let frame = parse_frame(Some("0a294000"), Some("0c00")).unwrap();
+
+        assert_eq!(frame.id, 0x0a294000);
+        assert_eq!(&frame.data, &[0x0c, 0x00]);
+
+        assert_eq!(parse_frame(Some("10"), None).unwrap().data.len(), 0);
+        assert!(parse_frame(Some("10"), Some("0c0")).is_none());
+        assert!(parse_frame(Some("10"), Some("000000000000000000")).is_none());
+        assert!(parse_frame(Some("xyz"), Some("00")).is_none());
+        assert!(parse_frame(None, None).is_none());
+    }
+}
@@ -0,0 +1,383 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+
+use esp_idf_svc::hal::task::embassy_sync::EspRawMutex;
+
+use crate::bus::bt::{Arbitration, Identity, VolumeControl};
+use crate::error::Error;
+use crate::storage::{Storage, Value};
+
+pub type BtAddr = [u8; 6];
+
+const MAX_DEVICES: usize = 8;
+
+const LOW_LATENCY_KEY: &str = "low_latency";
+const BLOCKED_KEY: &str = "blocked";
+const ALLOWED_KEY: &str = "allowed";
+const ARBITRATION_KEY: &str = "arbitration";
+const LAST_KEY: &str = "last";
+const IDENTITY_KEY: &str = "identity";
+const PASSTHROUGH_VOLUME_KEY: &str = "vol_passthrough";
+const ABSOLUTE_VOLUME_KEY: &str = "vol_absolute";
+
+pub type AddrList = heapless::Vec<BtAddr, MAX_DEVICES>;
+
+/// Per-device settings and state of the currently connected devices
+pub struct Devices {
+    connected: Option<BtAddr>,
+    hfp: Option<BtAddr>,
+    pending: Option<BtAddr>,
+    low_latency: AddrList,
+    passthrough_volume: AddrList,
+    absolute_volume: AddrList,
+    blocked: AddrList,
+    allowed: AddrList,
+    pairing: bool,
+    arbitration: Arbitration,
+    last: Option<BtAddr>,
+    identity: Identity,
+}
+
+impl Devices {
+    pub async fn load(storage: &Storage) -> Result<Self, Error> {
+        Ok(Self {
+            connected: None,
+            hfp: None,
+            pending: None,
+            low_latency: load_list(storage, LOW_LATENCY_KEY).await?,
+            passthrough_volume: load_list(storage, PASSTHROUGH_VOLUME_KEY).await?,
+            absolute_volume: load_list(storage, ABSOLUTE_VOLUME_KEY).await?,
+            blocked: load_list(storage, BLOCKED_KEY).await?,
+            allowed: load_list(storage, ALLOWED_KEY).await?,
+            pairing: false,
+            arbitration: storage
+                .get_u32(ARBITRATION_KEY)
+                .await?
+                .map(Into::into)
+                .unwrap_or(Arbitration::Ask),
+            last: storage
+                .get(LAST_KEY)
+                .await?
+                .and_then(|value| value.as_slice().try_into().ok()),
+            identity: storage
+                .get(IDENTITY_KEY)
+                .await?
+                .and_then(|value| <[u8; 5]>::try_from(value.as_slice()).ok())
+                .map(|value| Identity {
+                    suffix: value[0].min(Identity::MAX_SUFFIX),
+                    pin: [value[1], value[2], value[3], value[4]],
+                })
+                .unwrap_or(Identity::new()),
+        })
+    }
+
+    pub fn connected(&self) -> Option<BtAddr> {
+        self.connected
+    }
+
+    pub fn set_connected(&mut self, addr: Option<BtAddr>) {
+        self.connected = addr;
+    }
+
+    /// With multipoint, the device connected over HFP might differ from the one streaming A2DP
+    pub fn hfp(&self) -> Option<BtAddr> {
+        self.hfp
+    }
+
+    pub fn set_hfp(&mut self, addr: Option<BtAddr>) {
+        self.hfp = addr;
+    }
+
+    /// A second device which connected while another one was connected already
+    pub fn pending(&self) -> Option<BtAddr> {
+        self.pending
+    }
+
+    pub fn set_pending(&mut self, addr: Option<BtAddr>) {
+        self.pending = addr;
+    }
+
+    /// The most recently connected device, persisted across restarts
+    pub fn last(&self) -> Option<BtAddr> {
+        self.last
+    }
+
+    pub fn set_last(&mut self, addr: BtAddr) {
+        self.last = Some(addr);
+    }
+
+    pub fn arbitration(&self) -> Arbitration {
+        self.arbitration
+    }
+
+    pub fn set_arbitration(&mut self, arbitration: Arbitration) {
+        self.arbitration = arbitration;
+    }
+
+    pub fn identity(&self) -> Identity {
+        self.identity
+    }
+
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = identity;
+    }
+
+    pub fn is_low_latency(&self, addr: &BtAddr) -> bool {
+        self.low_latency.contains(addr)
+    }
+
+    /// Returns the new low-latency state of the device
+    pub fn toggle_low_latency(&mut self, addr: &BtAddr) -> bool {
+        toggle(&mut self.low_latency, addr)
+    }
+
+    pub fn low_latency(&self) -> &AddrList {
+        &self.low_latency
+    }
+
+    /// Devices in neither volume list are controlled locally
+    pub fn volume_control(&self, addr: &BtAddr) -> VolumeControl {
+        if self.passthrough_volume.contains(addr) {
+            VolumeControl::Passthrough
+        } else if self.absolute_volume.contains(addr) {
+            VolumeControl::Absolute
+        } else {
+            VolumeControl::Local
+        }
+    }
+
+    /// Returns the new volume control of the device
+    pub fn cycle_volume_control(&mut self, addr: &BtAddr) -> VolumeControl {
+        match self.volume_control(addr).next() {
+            VolumeControl::Local => toggle(&mut self.absolute_volume, addr),
+            VolumeControl::Passthrough => toggle(&mut self.passthrough_volume, addr),
+            VolumeControl::Absolute => {
+                toggle(&mut self.passthrough_volume, addr);
+                toggle(&mut self.absolute_volume, addr)
+            }
+        };
+
+        self.volume_control(addr)
+    }
+
+    pub fn passthrough_volume(&self) -> &AddrList {
+        &self.passthrough_volume
+    }
+
+    pub fn absolute_volume(&self) -> &AddrList {
+        &self.absolute_volume
+    }
+
+    /// Blocked devices are refused when pairing or connecting
+    pub fn is_blocked(&self, addr: &BtAddr) -> bool {
+        self.blocked.contains(addr)
+    }
+
+    /// Returns `false` if the list is full, as evicting the oldest blocked device would let it in
+    pub fn block(&mut self, addr: &BtAddr) -> bool {
+        add(&mut self.blocked, addr).is_some()
+    }
+
+    pub fn unblock_all(&mut self) {
+        self.blocked.clear();
+    }
+
+    pub fn blocked(&self) -> &AddrList {
+        &self.blocked
+    }
+
+    /// Only allowed devices may connect, unless the pairing window is open
+    pub fn is_admissible(&self, addr: &BtAddr) -> bool {
+        !self.is_blocked(addr) && (self.pairing || self.allowed.contains(addr))
+    }
+
+    /// Returns whether the device was not allowed yet, or `None` if the list is full,
+    /// as an allowed device is only forgotten once unpaired
+    pub fn allow(&mut self, addr: &BtAddr) -> Option<bool> {
+        add(&mut self.allowed, addr)
+    }
+
+    /// Returns whether the device was allowed
+    pub fn disallow(&mut self, addr: &BtAddr) -> bool {
+        self.allowed.contains(addr) && !toggle(&mut self.allowed, addr)
+    }
+
+    pub fn allow_all(&mut self, list: AddrList) {
+        self.allowed = list;
+    }
+
+    pub fn allowed(&self) -> &AddrList {
+        &self.allowed
+    }
+
+    pub fn is_pairing(&self) -> bool {
+        self.pairing
+    }
+
+    pub fn set_pairing(&mut self, pairing: bool) {
+        self.pairing = pairing;
+    }
+}
+
+pub type SharedDevices = Mutex<EspRawMutex, RefCell<Devices>>;
+
+pub fn create_devices(devices: Devices) -> SharedDevices {
+    Mutex::new(RefCell::new(devices))
+}
+
+pub async fn save_low_latency(storage: &Storage, list: &AddrList) -> Result<(), Error> {
+    save_list(storage, LOW_LATENCY_KEY, list).await
+}
+
+pub async fn save_volume_control(
+    storage: &Storage,
+    passthrough: &AddrList,
+    absolute: &AddrList,
+) -> Result<(), Error> {
+    save_list(storage, PASSTHROUGH_VOLUME_KEY, passthrough).await?;
+    save_list(storage, ABSOLUTE_VOLUME_KEY, absolute).await
+}
+
+pub async fn save_blocked(storage: &Storage, list: &AddrList) -> Result<(), Error> {
+    save_list(storage, BLOCKED_KEY, list).await
+}
+
+pub async fn save_allowed(storage: &Storage, list: &AddrList) -> Result<(), Error> {
+    save_list(storage, ALLOWED_KEY, list).await
+}
+
+pub async fn save_arbitration(storage: &Storage, arbitration: Arbitration) -> Result<(), Error> {
+    storage.set_u32(ARBITRATION_KEY, arbitration.into()).await
+}
+
+pub async fn save_last(storage: &Storage, addr: &BtAddr) -> Result<(), Error> {
+    storage.set(LAST_KEY, addr).await
+}
+
+pub async fn save_identity(storage: &Storage, identity: &Identity) -> Result<(), Error> {
+    let pin = &identity.pin;
+
+    storage
+        .set(
+            IDENTITY_KEY,
+            &[identity.suffix, pin[0], pin[1], pin[2], pin[3]],
+        )
+        .await
+}
+
+async fn load_list(storage: &Storage, key: &'static str) -> Result<AddrList, Error> {
+    Ok(decode_list(&storage.get(key).await?.unwrap_or_default()))
+}
+
+async fn save_list(storage: &Storage, key: &'static str, list: &AddrList) -> Result<(), Error> {
+    storage.set(key, &encode_list(list)).await
+}
+
+/// A trailing partial address, e.g. of a list saved by a different firmware, is ignored
+fn decode_list(value: &[u8]) -> AddrList {
+    value
+        .chunks_exact(6)
+        .take(MAX_DEVICES)
+        .map(|addr| addr.try_into().unwrap())
+        .collect()
+}
+
+fn encode_list(list: &AddrList) -> Value {
+    let mut value = Value::new();
+
+    for addr in list {
+        value.extend_from_slice(addr).unwrap();
+    }
+
+    value
+}
+
+/// Returns whether the device was not in the list yet, or `None` if the list is full
+fn add(list: &mut AddrList, addr: &BtAddr) -> Option<bool> {
+    if list.contains(addr) {
+        Some(false)
+    } else {
+        list.push(*addr).ok().map(|_| true)
+    }
+}
+
+fn toggle(list: &mut AddrList, addr: &BtAddr) -> bool {
+    if let Some(index) = list.iter().position(|other| other == addr) {
+        list.remove(index);
+        false
+    } else {
+        if list.is_full() {
+            // Forget the oldest device
+            list.remove(0);
+        }
+
+        list.push(*addr).unwrap();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(n: u8) -> BtAddr {
+        [n, 0, 0, 0, 0, n]
+    }
+
+    #[test]
+    fn toggle_round_trip() {
+        let mut list = AddrList::new();
+
+        assert!(toggle(&mut list, &addr(1)));
+        assert!(toggle(&mut list, &addr(2)));
+        assert_eq!(list, [addr(1), addr(2)]);
+
+        assert!(!toggle(&mut list, &addr(1)));
+        assert_eq!(list, [addr(2)]);
+
+        assert!(toggle(&mut list, &addr(1)));
+        assert_eq!(list, [addr(2), addr(1)]);
+    }
+
+    #[test]
+    fn oldest_evicted() {
+        let mut list = (0..MAX_DEVICES as u8).map(addr).collect::<AddrList>();
+
+        assert!(toggle(&mut list, &addr(100)));
+
+        assert_eq!(list.len(), MAX_DEVICES);
+        assert!(!list.contains(&addr(0)));
+        assert_eq!(list.first(), Some(&addr(1)));
+        assert_eq!(list.last(), Some(&addr(100)));
+    }
+
+    #[test]
+    fn full_refused() {
+        let mut list = (0..MAX_DEVICES as u8).map(addr).collect::<AddrList>();
+
+        assert_eq!(add(&mut list, &addr(0)), Some(false));
+        assert_eq!(add(&mut list, &addr(100)), None);
+
+        assert_eq!(list.len(), MAX_DEVICES);
+        assert!(list.contains(&addr(0)));
+        assert!(!list.contains(&addr(100)));
+    }
+
+    #[test]
+    fn list_round_trip() {
+        let list = (0..MAX_DEVICES as u8).map(addr).collect::<AddrList>();
+
+        assert_eq!(decode_list(&encode_list(&list)), list);
+        assert!(decode_list(&encode_list(&AddrList::new())).is_empty());
+    }
+
+    #[test]
+    fn partial_addr_ignored() {
+        let mut value = encode_list(&[addr(1), addr(2)].into_iter().collect());
+
+        value.extend_from_slice(&[3, 3, 3]).unwrap();
+
+        assert_eq!(decode_list(&value), [addr(1), addr(2)]);
+    }
+}
@@ -0,0 +1,223 @@
+use core::fmt::{self, Display};
+
+use embassy_futures::select::{select3, Either3};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use embassy_time::{Duration, Instant, Timer};
+
+use enumset::EnumSet;
+
+use esp_idf_svc::hal::reset::ResetReason;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+
+use log::{error, info, warn};
+
+use crate::{
+    bus::{
+        can::Notification,
+        diag::{BootReason, Diagnostic, Severity},
+        BusSubscription, Service,
+    },
+    error::Error,
+    signal::StatefulSender,
+    storage::{self, Storage},
+    version,
+};
+
+const FAULT_DURATION: core::time::Duration = core::time::Duration::from_secs(10);
+
+pub const CRASHES_KEY: &str = "crashes";
+/// Set by holding Mute at ignition, for the next boot only
+pub const SAFE_MODE_KEY: &str = "safe_mode";
+
+/// Crashes in a row after which the unit boots in safe mode
+const SAFE_MODE_CRASHES: u32 = 3;
+/// Running for that long counts as a successful boot, resetting the crash count
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+pub fn boot_reason() -> BootReason {
+    match ResetReason::get() {
+        ResetReason::PowerOn => BootReason::PowerOn,
+        ResetReason::Brownout => BootReason::Brownout,
+        ResetReason::Watchdog | ResetReason::InterruptWatchdog | ResetReason::TaskWatchdog => {
+            BootReason::Watchdog
+        }
+        ResetReason::Panic => BootReason::Panic,
+        _ => BootReason::Other,
+    }
+}
+
+/// Whether to boot in the safe mode, i.e. only with CAN, the console and the updates,
+/// so that a unit crashing at boot can still be recovered in the car
+///
+/// Reads the NVS directly, as it is called before the storage service is spawned
+pub fn safe_mode(partition: EspDefaultNvsPartition, boot: BootReason) -> Result<bool, Error> {
+    let mut nvs = EspNvs::new(partition, storage::NAMESPACE, true)?;
+
+    let mut buf = [0; 4];
+
+    let crashes = nvs
+        .get_raw(CRASHES_KEY, &mut buf)?
+        .and_then(|value| value.try_into().ok())
+        .map(u32::from_le_bytes)
+        .unwrap_or(0);
+
+    let crashes = if boot.is_crash() { crashes + 1 } else { 0 };
+
+    nvs.set_raw(CRASHES_KEY, &crashes.to_le_bytes())?;
+
+    let requested = nvs.remove(SAFE_MODE_KEY)?;
+
+    if requested || crashes >= SAFE_MODE_CRASHES {
+        warn!(
+            "Safe mode (requested: {}, crashes in a row: {})",
+            requested, crashes
+        );
+
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+pub async fn process(
+    bus: BusSubscription<'_>,
+    notification: StatefulSender<'_, impl RawMutex, Notification>,
+    storage: &Storage,
+) -> Result<(), Error> {
+    let boots = storage.increment("boots").await?;
+
+    info!(
+        "Firmware V{} ({}, built {}), boot count: {}",
+        version::VERSION,
+        version::GIT_HASH,
+        version::BUILD_DATE,
+        boots
+    );
+
+    let mut stable = false;
+
+    loop {
+        let _started = bus.service.started_when_enabled().await?;
+
+        loop {
+            let stable_at = async {
+                if stable {
+                    core::future::pending().await
+                } else {
+                    Timer::at(Instant::from_ticks(0) + STABLE_AFTER).await
+                }
+            };
+
+            match select3(
+                bus.service.wait_disabled(),
+                bus.diagnostics.recv(),
+                stable_at,
+            )
+            .await
+            {
+                Either3::First(other) => break other?,
+                Either3::Second(diagnostic) => report(&diagnostic, &notification),
+                Either3::Third(_) => {
+                    storage.set_u32(CRASHES_KEY, 0).await?;
+                    stable = true;
+                }
+            }
+        }
+    }
+}
+
+/// Logs the diagnostic at its severity, critical faults also take over the radio display
+///
+/// NOTE: The board has no LED for a fault pattern
+fn report(diagnostic: &Diagnostic, notification: &StatefulSender<'_, impl RawMutex, Notification>) {
+    match diagnostic.severity() {
+        Severity::Info => info!("{}", Describe(diagnostic)),
+        Severity::Warn => warn!("{}", Describe(diagnostic)),
+        Severity::Critical => {
+            error!("{}", Describe(diagnostic));
+
+            notification.modify(|notification| {
+                notification.post(fault(diagnostic), FAULT_DURATION);
+                true
+            });
+        }
+    }
+}
+
+/// The text shown on the display for critical faults
+fn fault(diagnostic: &Diagnostic) -> &'static str {
+    match diagnostic {
+        Diagnostic::Overheat { .. } => "FAULT: OVERHEATING",
+        Diagnostic::UpdateFailed => "FAULT: UPDATE FAILED",
+        Diagnostic::CanNotErrorActive { .. } | Diagnostic::CanBusOff { .. } => "FAULT: CAN BUS",
+        Diagnostic::BtControllerFailed { .. } => "FAULT: BLUETOOTH",
+        Diagnostic::ServiceFailed { .. } => "FAULT: SERVICE FAILED",
+        _ => "FAULT",
+    }
+}
+
+struct Describe<'a>(&'a Diagnostic);
+
+impl<'a> Display for Describe<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Diagnostic::Boot(reason) if reason.is_unclean() => {
+                write!(f, "Unclean boot: {:?}", reason)
+            }
+            Diagnostic::Boot(reason) => write!(f, "Boot: {:?}", reason),
+            Diagnostic::SlowPoll { service, max_poll } => write!(
+                f,
+                "Service {:?} blocked the executor for {}ms",
+                service,
+                max_poll.as_millis()
+            ),
+            Diagnostic::Overheat { celsius } => write!(f, "Overheating: {}C", celsius),
+            Diagnostic::ArenaExhausted { len } => {
+                write!(f, "No arena buffer of {}B available", len)
+            }
+            Diagnostic::UpdateFailed => write!(f, "Firmware update failed"),
+            Diagnostic::CanOverflow { count } => write!(f, "CAN RX overflows: {}/s", count),
+            Diagnostic::CanTxErrors { retries, dropped } => write!(
+                f,
+                "CAN TX retries: {}/s, dropped frames: {}/s",
+                retries, dropped
+            ),
+            Diagnostic::CanNotErrorActive {
+                tx_errors,
+                rx_errors,
+            } => write!(
+                f,
+                "CAN not error-active, TX errors: {}, RX errors: {}",
+                tx_errors, rx_errors
+            ),
+            Diagnostic::BenchMode => write!(f, "No CAN traffic, bench mode"),
+            Diagnostic::LowVoltage { millivolts } => {
+                write!(f, "Low battery voltage: {}mV", millivolts)
+            }
+            Diagnostic::CanBusOff { tx_errors } => {
+                write!(f, "CAN bus-off, TX errors: {}", tx_errors)
+            }
+            Diagnostic::BtControllerFailed { code } => {
+                write!(f, "Bluetooth controller failed: {}", code)
+            }
+            Diagnostic::ServiceFailed { service, code } => {
+                write!(f, "Service {:?} failed: {}", service, code)
+            }
+            Diagnostic::CpuUsage(usage) => {
+                write!(f, "CPU:")?;
+
+                for service in EnumSet::<Service>::ALL {
+                    let permille = usage[service as usize];
+
+                    if permille > 0 {
+                        write!(f, " {:?} {}.{}%", service, permille / 10, permille % 10)?;
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
@@ -1,74 +1,493 @@
-use embassy_futures::select::{select4, Either4};
+use core::fmt::Write;
+
+use embassy_futures::select::{select3, select4, Either3, Either4};
 use embassy_sync::blocking_mutex::raw::RawMutex;
 
+use embassy_time::{Duration, Instant, Timer};
+
 use crate::{
+    bt,
     bus::{
-        bt::{AudioTrackState, PhoneCallState},
-        can::{DisplayText, RadioState},
-        BusSubscription,
+        bt::{AudioTrackState, BtState, PhoneCallState},
+        can::{DisplayText, FmStation, Notification, RadioState},
+        set_text, BusSubscription, DisplayString,
     },
     error::Error,
+    service::SystemState,
     signal::StatefulSender,
+    version,
 };
 
-// async fn process_cockpit(
-//     audio: Receiver<'_, impl RawMutex, AudioState>,
-//     audio_track: Receiver<'_, impl RawMutex, AudioTrackState>,
-//     track_info: &Mutex<impl RawMutex, RefCell<TrackInfo>>,
-//     phone: Receiver<'_, impl RawMutex, AudioState>,
-//     phone_call: Receiver<'_, impl RawMutex, PhoneCallState>,
-//     call_info: &Mutex<impl RawMutex, RefCell<PhoneCallInfo>>,
-//     radio: Receiver<'_, impl RawMutex, RadioState>,
-//     radio_display: &Mutex<impl RawMutex, RefCell<DisplayText>>,
-//     radio_display_out: &Signal<impl RawMutex, ()>,
-// ) -> Result<(), Error> {
-//     todo!()
-// }
-
-pub async fn process_radio<const N: usize>(
-    bus: BusSubscription<'_>,
+const TICK: Duration = Duration::from_millis(500);
+/// While shedding load the call duration is still shown to the second
+const SHEDDING_TICK: Duration = Duration::from_secs(1);
+
+const SPLASH_DURATION: core::time::Duration = core::time::Duration::from_secs(5);
+
+const VOLUME_DURATION: Duration = Duration::from_secs(2);
+
+const WEAK_LINK_DURATION: core::time::Duration = core::time::Duration::from_secs(3);
+
+const PAIRING_DURATION: core::time::Duration = core::time::Duration::from_secs(3);
+
+/// What both displays show, each in its own format
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DisplayContent {
+    /// A notification or the volume, written to the displays as is
+    Transient,
+    PhoneCall,
+    Track,
+    Blank,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DisplayEvent {
+    Radio(RadioState),
+    Phone(PhoneCallState),
+    Audio(AudioTrackState),
+    /// A transient text was posted, which is shown until then
+    Transient(Instant),
+    Tick,
+}
+
+/// Decides what the displays show: a transient text takes precedence over the call, which takes
+/// precedence over the track, the latter two only while the radio is on BT
+pub struct DisplayArbiter {
+    radio: RadioState,
+    phone: PhoneCallState,
+    audio: AudioTrackState,
+    transient_until: Option<Instant>,
+}
+
+impl DisplayArbiter {
+    pub const fn new() -> Self {
+        Self {
+            radio: RadioState::Unknown,
+            phone: PhoneCallState::Idle,
+            audio: AudioTrackState::Uninitialized,
+            transient_until: None,
+        }
+    }
+
+    pub fn radio(&self) -> RadioState {
+        self.radio
+    }
+
+    /// Returns the content to render, if the displays need an update. The call and the track
+    /// are rendered anew on each of their events, as their info (e.g. the duration) changes
+    pub fn update(&mut self, event: DisplayEvent, now: Instant) -> Option<DisplayContent> {
+        match event {
+            DisplayEvent::Radio(new) => self.radio = new,
+            DisplayEvent::Phone(new) => self.phone = new,
+            DisplayEvent::Audio(new) => self.audio = new,
+            DisplayEvent::Transient(until) => {
+                self.transient_until = Some(until);
+
+                return Some(DisplayContent::Transient);
+            }
+            DisplayEvent::Tick => match self.transient_until {
+                Some(until) if until <= now => (),
+                _ => return None,
+            },
+        }
+
+        match self.transient_until {
+            Some(until) if until > now => None,
+            _ => {
+                self.transient_until = None;
+
+                Some(self.content())
+            }
+        }
+    }
+
+    pub fn content(&self) -> DisplayContent {
+        if self.transient_until.is_some() {
+            DisplayContent::Transient
+        } else if self.radio.is_bt_active() && self.phone.is_active() {
+            DisplayContent::PhoneCall
+        } else if self.radio.is_bt_active() && self.audio.is_active() {
+            DisplayContent::Track
+        } else {
+            DisplayContent::Blank
+        }
+    }
+}
+
+/// The version and pairing hint, shown once the system is started for the first time after
+/// the boot, i.e. not when it is restarted after the key was off
+pub struct Splash {
+    shown: bool,
+}
+
+impl Splash {
+    pub const fn new() -> Self {
+        Self { shown: false }
+    }
+
+    /// Returns whether the splash is due
+    pub fn update(&mut self, state: SystemState) -> bool {
+        if !self.shown && state == SystemState::Started {
+            self.shown = true;
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Drives the radio and the cockpit display. The cockpit one is shared with the menu
+/// and the prompts of the commands, which take precedence
+pub async fn process<const N: usize, const C: usize>(
+    bus: BusSubscription<'_, N, C>,
     radio_display: StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+    cockpit_display: StatefulSender<'_, impl RawMutex, DisplayText<C>>,
+    notification: StatefulSender<'_, impl RawMutex, Notification>,
 ) -> Result<(), Error> {
+    let mut splash = Splash::new();
+
     loop {
         let _started = bus.service.started_when_enabled().await?;
 
-        let mut sradio = RadioState::Unknown;
-        let mut sphone = PhoneCallState::Idle;
-        let mut saudio = AudioTrackState::Uninitialized;
+        let mut arbiter = DisplayArbiter::new();
+
+        let mut sweak = false;
+
+        // Only a grown bond list means a new phone, as authentications also complete on reconnects
+        let mut sbonded = bus.bonded.state(|bonded| bonded.devices.len());
 
         loop {
-            let ret = select4(
-                bus.service.wait_disabled(),
-                bus.radio.recv(),
-                bus.phone_call.recv(),
-                bus.audio_track.recv(),
+            let ret = select3(
+                select4(
+                    bus.service.wait_disabled(),
+                    bus.radio.recv(),
+                    bus.phone_call.recv(),
+                    bus.audio_track.recv(),
+                ),
+                select4(
+                    bus.notification.recv(),
+                    bus.radio_volume.recv(),
+                    bus.link.recv(),
+                    Timer::after(if bus.service.is_shedding() {
+                        SHEDDING_TICK
+                    } else {
+                        TICK
+                    }),
+                ),
+                select3(bus.bt.recv(), bus.bonded.recv(), bus.fm_station.recv()),
             )
             .await;
 
-            match ret {
-                Either4::First(other) => break other?,
-                Either4::Second(new) => sradio = new,
-                Either4::Third(_) => sphone = bus.phone_call.state(|call| call.state),
-                Either4::Fourth(_) => saudio = bus.audio_track.state(|track| track.state),
+            let now = Instant::now();
+
+            // Not only on the ticks, which are postponed as long as other events keep coming
+            if splash.update(bus.service.get_sys_state()) {
+                post_splash(&bus, &notification);
             }
 
-            if sradio.is_bt_active() {
-                if sphone.is_active() {
-                    bus.phone_call.state(|call| {
-                        radio_display.modify(|display| {
-                            display.update_phone_info(call);
+            let event = match ret {
+                Either3::First(Either4::First(other)) => break other?,
+                Either3::First(Either4::Second(new)) => DisplayEvent::Radio(new),
+                Either3::First(Either4::Third(_)) => {
+                    DisplayEvent::Phone(bus.phone_call.state(|call| call.state))
+                }
+                Either3::First(Either4::Fourth(_)) => {
+                    DisplayEvent::Audio(bus.audio_track.state(|track| track.state))
+                }
+                Either3::Second(Either4::First(_)) => {
+                    DisplayEvent::Transient(bus.notification.state(|notification| {
+                        render_transient(&notification.text, &radio_display, &cockpit_display);
+
+                        now + Duration::from_millis(notification.duration.as_millis() as _)
+                    }))
+                }
+                Either3::Second(Either4::Second(volume)) => {
+                    // In BT mode the radio does not show its volume, as its display is ours
+                    if !arbiter.radio().is_bt_active() {
+                        continue;
+                    }
+
+                    let mut text = DisplayString::new();
+                    let _ = write!(&mut text, "VOL {:02}", volume);
+
+                    render_transient(&text, &radio_display, &cockpit_display);
+
+                    DisplayEvent::Transient(now + VOLUME_DURATION)
+                }
+                Either3::Second(Either4::Third(_)) => {
+                    let weak = bus.link.state(|link| link.is_weak());
+
+                    if weak && !sweak {
+                        notification.modify(|notification| {
+                            notification.post("WEAK BT SIGNAL", WEAK_LINK_DURATION);
                             true
                         });
+                    }
+
+                    sweak = weak;
+
+                    continue;
+                }
+                Either3::Second(Either4::Fourth(_)) => DisplayEvent::Tick,
+                Either3::Third(Either3::First(BtState::PairFailed)) => {
+                    notification.modify(|notification| {
+                        notification.post("PAIRING FAILED - TRY AGAIN", PAIRING_DURATION);
+                        true
                     });
-                } else if saudio.is_active() {
-                    bus.audio_track.state(|track| {
-                        radio_display.modify(|display| {
-                            display.update_track_info(track);
+
+                    continue;
+                }
+                Either3::Third(Either3::First(_)) => continue,
+                Either3::Third(Either3::Second(_)) => {
+                    let bonded = bus.bonded.state(|bonded| bonded.devices.len());
+
+                    if bonded > sbonded {
+                        notification.modify(|notification| {
+                            notification.post("NEW PHONE PAIRED", PAIRING_DURATION);
                             true
                         });
-                    });
+                    }
+
+                    sbonded = bonded;
+
+                    continue;
+                }
+                Either3::Third(Either3::Third(_)) => {
+                    render_cockpit(arbiter.content(), &bus, &cockpit_display);
+
+                    continue;
                 }
+            };
+
+            if let Some(content) = arbiter.update(event, now) {
+                render(content, &bus, &radio_display);
+            }
+
+            // Also on the ticks, so that the cockpit is restored once the menu closed
+            render_cockpit(arbiter.content(), &bus, &cockpit_display);
+        }
+    }
+}
+
+fn render_transient<const N: usize, const C: usize>(
+    text: &str,
+    radio_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<C>>,
+) {
+    radio_display.modify(|display| {
+        display.update_text(text);
+        true
+    });
+
+    cockpit_display.modify(|display| {
+        if display.menu {
+            false
+        } else {
+            display.update_text(text);
+            true
+        }
+    });
+}
+
+fn render<const N: usize, const C: usize>(
+    content: DisplayContent,
+    bus: &BusSubscription<'_, N, C>,
+    radio_display: &StatefulSender<'_, impl RawMutex, DisplayText<N>>,
+) {
+    match content {
+        DisplayContent::Transient => (),
+        DisplayContent::PhoneCall => bus.phone_call.state(|call| {
+            radio_display.modify(|display| {
+                display.update_phone_info(call);
+                true
+            });
+        }),
+        DisplayContent::Track => bus.audio_track.state(|track| {
+            radio_display.modify(|display| {
+                display.update_track_info(track);
+                true
+            });
+        }),
+        DisplayContent::Blank => radio_display.modify(|display| {
+            if !display.text.is_empty() {
+                display.reset();
+                true
+            } else {
+                false
             }
+        }),
+    }
+}
+
+/// The cockpit display is narrow, so it only shows the call duration, the song or the FM station,
+/// and is only written when its text changed
+fn render_cockpit<const N: usize, const C: usize>(
+    content: DisplayContent,
+    bus: &BusSubscription<'_, N, C>,
+    cockpit_display: &StatefulSender<'_, impl RawMutex, DisplayText<C>>,
+) {
+    let mut text = heapless::String::<C>::new();
+
+    match content {
+        DisplayContent::Transient => return,
+        DisplayContent::PhoneCall => bus.phone_call.state(|call| {
+            let secs = call.duration.as_secs();
+
+            let _ = write!(&mut text, "CALL {:02}:{:02}", secs / 60, secs % 60);
+        }),
+        DisplayContent::Track => bus
+            .audio_track
+            .state(|track| set_text(&mut text, &track.song)),
+        DisplayContent::Blank => bus
+            .fm_station
+            .state(|station| write_station(&mut text, station)),
+    }
+
+    cockpit_display.modify(|display| {
+        if display.menu || display.text == text {
+            false
+        } else {
+            display.update_text(&text);
+            true
         }
+    });
+}
+
+/// The RDS name, or else the frequency
+///
+/// NOTE: The frequency is assumed to be in 10kHz steps, which is not verified in a car yet
+fn write_station<const C: usize>(text: &mut heapless::String<C>, station: &FmStation) {
+    match station.frequency {
+        Some(_) if !station.name.is_empty() => set_text(text, station.name.trim_end()),
+        Some(frequency) => {
+            let _ = write!(text, "FM {}.{:02}", frequency / 100, frequency % 100);
+        }
+        None => (),
+    }
+}
+
+fn post_splash<const N: usize, const C: usize>(
+    bus: &BusSubscription<'_, N, C>,
+    notification: &StatefulSender<'_, impl RawMutex, Notification>,
+) {
+    let name = bt::device_name(&bus.identity.state(|identity| *identity));
+
+    let mut text = DisplayString::new();
+
+    let _ = write!(
+        &mut text,
+        "FIAT-BT V{}.{} - PAIR: {}",
+        version::major(),
+        version::minor(),
+        name
+    );
+
+    text.make_ascii_uppercase();
+
+    notification.modify(|notification| {
+        notification.post(&text, SPLASH_DURATION);
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use DisplayContent::*;
+
+    /// Replays timestamped events and returns the timestamped contents to render
+    fn replay(events: &[(u64, DisplayEvent)]) -> Vec<(u64, DisplayContent)> {
+        let mut arbiter = DisplayArbiter::new();
+
+        events
+            .iter()
+            .filter_map(|(secs, event)| {
+                arbiter
+                    .update(*event, Instant::from_secs(*secs))
+                    .map(|content| (*secs, content))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn call_over_track() {
+        let events = [
+            (0, DisplayEvent::Radio(RadioState::BtActive)),
+            (1, DisplayEvent::Audio(AudioTrackState::Playing)),
+            (10, DisplayEvent::Phone(PhoneCallState::CallActive)),
+            (11, DisplayEvent::Audio(AudioTrackState::Playing)),
+            (40, DisplayEvent::Phone(PhoneCallState::Idle)),
+            (50, DisplayEvent::Radio(RadioState::Fm)),
+        ];
+
+        assert_eq!(
+            replay(&events),
+            vec![
+                (0, Blank),
+                (1, Track),
+                (10, PhoneCall),
+                (11, PhoneCall),
+                (40, Track),
+                (50, Blank),
+            ]
+        );
+    }
+
+    #[test]
+    fn transient_over_all() {
+        let events = [
+            (0, DisplayEvent::Radio(RadioState::BtActive)),
+            (0, DisplayEvent::Audio(AudioTrackState::Playing)),
+            (10, DisplayEvent::Transient(Instant::from_secs(15))),
+            (12, DisplayEvent::Phone(PhoneCallState::CallActive)),
+            (13, DisplayEvent::Tick),
+            (15, DisplayEvent::Tick),
+            (16, DisplayEvent::Tick),
+        ];
+
+        assert_eq!(
+            replay(&events),
+            vec![(0, Blank), (0, Track), (10, Transient), (15, PhoneCall)]
+        );
+    }
+
+    #[test]
+    fn not_on_bt() {
+        let events = [
+            (0, DisplayEvent::Radio(RadioState::Fm)),
+            (1, DisplayEvent::Audio(AudioTrackState::Playing)),
+            (2, DisplayEvent::Phone(PhoneCallState::CallActive)),
+            (3, DisplayEvent::Tick),
+        ];
+
+        assert_eq!(replay(&events), vec![(0, Blank), (1, Blank), (2, Blank)]);
+    }
+
+    #[test]
+    fn splash_once() {
+        let mut splash = Splash::new();
+
+        let states = [
+            SystemState::Stopped,
+            SystemState::Starting,
+            SystemState::Started,
+            SystemState::Started,
+            SystemState::Stopping,
+            SystemState::Stopped,
+            SystemState::Starting,
+            SystemState::Started,
+        ];
+
+        let due = states
+            .iter()
+            .map(|state| splash.update(*state))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            due,
+            vec![false, false, true, false, false, false, false, false]
+        );
     }
 }
@@ -15,6 +15,15 @@ impl From<EspError> for Error {
     }
 }
 
+impl Error {
+    /// The ESP-IDF error code, as reported in the diagnostics
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::EspError(error) => error.code(),
+        }
+    }
+}
+
 // impl From<SpawnError> for Error {
 //     fn from(error: SpawnError) -> Self {
 //         Self::SpawnError(error)
@@ -0,0 +1,211 @@
+use core::cell::RefCell;
+use core::ffi::CStr;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use embassy_time::{Duration, Instant, Timer};
+
+use esp_idf_svc::sys::{uxTaskGetNumberOfTasks, uxTaskGetSystemState, TaskStatus_t};
+
+use log::warn;
+
+use crate::bus::{
+    diag::{Diagnostic, MAX_SERVICES},
+    Service,
+};
+use crate::error::Error;
+use crate::signal::QueueSender;
+
+const MAX_TASKS: usize = 16;
+
+const REPORT_PERIOD: Duration = Duration::from_secs(10);
+const SLOW_POLL: Duration = Duration::from_millis(5);
+
+/// FreeRTOS tasks of the Bluetooth stack, whose run time is accounted to `Service::Bt`
+const BT_TASKS: &[&str] = &["BTC_TASK", "BTU_TASK", "hciT", "btController", "BtA2dSinkT"];
+
+struct TaskStats {
+    service: Service,
+    max_poll: Duration,
+    busy: Duration,
+}
+
+/// Worst-case and total poll times of all instrumented tasks since the last report
+pub struct PollStats(RefCell<heapless::Vec<TaskStats, MAX_TASKS>>);
+
+impl PollStats {
+    pub const fn new() -> Self {
+        Self(RefCell::new(heapless::Vec::new()))
+    }
+
+    pub fn instrument<F>(&self, service: Service, fut: F) -> Instrumented<'_, F>
+    where
+        F: Future,
+    {
+        let mut tasks = self.0.borrow_mut();
+
+        let index = tasks.len();
+
+        let index = if tasks
+            .push(TaskStats {
+                service,
+                max_poll: Duration::from_ticks(0),
+                busy: Duration::from_ticks(0),
+            })
+            .is_ok()
+        {
+            Some(index)
+        } else {
+            warn!("Too many tasks, not instrumenting {:?}", service);
+            None
+        };
+
+        Instrumented {
+            stats: self,
+            index,
+            fut,
+        }
+    }
+
+    fn record(&self, index: usize, elapsed: Duration) {
+        let task = &mut self.0.borrow_mut()[index];
+
+        if task.max_poll < elapsed {
+            task.max_poll = elapsed;
+        }
+
+        task.busy += elapsed;
+    }
+
+    fn take_worst(&self) -> Option<(Service, Duration)> {
+        let mut tasks = self.0.borrow_mut();
+
+        let worst = tasks
+            .iter()
+            .max_by_key(|task| task.max_poll)
+            .map(|task| (task.service, task.max_poll));
+
+        for task in tasks.iter_mut() {
+            task.max_poll = Duration::from_ticks(0);
+        }
+
+        worst
+    }
+
+    /// The services' share of `period` spent in polling their tasks, in permille
+    fn take_usage(&self, period: Duration) -> [u16; MAX_SERVICES] {
+        let mut usage = [0; MAX_SERVICES];
+
+        for task in self.0.borrow_mut().iter_mut() {
+            usage[task.service as usize] += permille(task.busy.as_ticks(), period.as_ticks());
+            task.busy = Duration::from_ticks(0);
+        }
+
+        usage
+    }
+}
+
+pub struct Instrumented<'a, F> {
+    stats: &'a PollStats,
+    index: Option<usize>,
+    fut: F,
+}
+
+impl<'a, F> Future for Instrumented<'a, F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = unsafe { self.get_unchecked_mut() };
+
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+
+        let start = Instant::now();
+
+        let result = fut.poll(cx);
+
+        if let Some(index) = this.index {
+            this.stats.record(index, start.elapsed());
+        }
+
+        result
+    }
+}
+
+pub async fn report(
+    stats: &PollStats,
+    diagnostics: QueueSender<'_, impl RawMutex, Diagnostic>,
+) -> Result<(), Error> {
+    let mut rtos_tasks = Vec::new();
+    let mut last = bt_run_time(&mut rtos_tasks);
+
+    loop {
+        Timer::after(REPORT_PERIOD).await;
+
+        if let Some((service, max_poll)) = stats.take_worst() {
+            if max_poll >= SLOW_POLL {
+                diagnostics.send(Diagnostic::SlowPoll { service, max_poll });
+            }
+        }
+
+        let mut usage = stats.take_usage(REPORT_PERIOD);
+
+        // The Bluetooth stack runs in tasks of its own, rather than on the executor
+        if let Some((bt, total)) = bt_run_time(&mut rtos_tasks) {
+            if let Some((last_bt, last_total)) = last {
+                usage[Service::Bt as usize] += permille(
+                    bt.wrapping_sub(last_bt) as _,
+                    total.wrapping_sub(last_total) as _,
+                );
+            }
+
+            last = Some((bt, total));
+        }
+
+        diagnostics.send(Diagnostic::CpuUsage(usage));
+    }
+}
+
+/// The run-time counters of the Bluetooth stack tasks, and the total run time
+///
+/// `None` if the tasks did not fit, i.e. if one was created right after they were counted
+fn bt_run_time(tasks: &mut Vec<TaskStatus_t>) -> Option<(u32, u32)> {
+    let mut total = 0;
+
+    tasks.clear();
+    tasks.reserve(unsafe { uxTaskGetNumberOfTasks() } as _);
+
+    unsafe {
+        let count = uxTaskGetSystemState(tasks.as_mut_ptr(), tasks.capacity() as _, &mut total);
+
+        tasks.set_len(count as _);
+    }
+
+    if tasks.is_empty() {
+        warn!("The FreeRTOS tasks did not fit, skipping the Bluetooth run time");
+
+        return None;
+    }
+
+    let bt = tasks
+        .iter()
+        .filter(|task| {
+            let name = unsafe { CStr::from_ptr(task.pcTaskName) };
+
+            BT_TASKS.iter().any(|bt| name.to_bytes() == bt.as_bytes())
+        })
+        .fold(0_u32, |run_time, task| {
+            run_time.wrapping_add(task.ulRunTimeCounter)
+        });
+
+    Some((bt, total))
+}
+
+fn permille(part: u64, whole: u64) -> u16 {
+    (part * 1000).checked_div(whole).unwrap_or(0).min(1000) as _
+}
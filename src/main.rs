@@ -11,24 +11,44 @@ use esp_idf_svc::hal::peripherals::Peripherals;
 use esp_idf_svc::hal::task::thread::ThreadSpawnConfiguration;
 use esp_idf_svc::sys::{heap_caps_print_heap_info, MALLOC_CAP_DEFAULT};
 
+mod absolute_volume;
+mod app;
+mod arena;
 mod audio;
+mod board;
 mod bt;
 mod bus;
 mod can;
+mod chord;
 mod commands;
+mod console;
+mod devices;
+mod diag;
 mod displays;
 mod error;
+mod instrument;
+mod menu;
+mod metadata;
+mod presence;
+mod radio_mux;
 mod ringbuf;
 mod run;
 mod select_spawn;
 mod service;
 mod signal;
+mod sim;
+mod sniffer;
+mod spdif;
+mod storage;
+mod thermal;
+mod trace;
 mod updates;
 mod usb_cutoff;
+mod version;
 
 fn main() -> Result<(), Error> {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    console::Logger::initialize();
 
     unsafe {
         heap_caps_print_heap_info(MALLOC_CAP_DEFAULT);
@@ -0,0 +1,182 @@
+use core::fmt::Write;
+
+use crate::audio::Gains;
+use crate::bus::bt::{Arbitration, Identity, PlayerSettings, VolumeControl};
+use crate::version;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MenuPage {
+    LowLatency,
+    Shuffle,
+    Repeat,
+    Volume,
+    BlockDevice,
+    UnblockAll,
+    NewPhone,
+    PairNew,
+    Paired,
+    Name,
+    MusicGain,
+    CallGain,
+    VolumeKeys,
+    SpeedVolume,
+    ParkingDuck,
+    BtTest,
+    About,
+}
+
+impl MenuPage {
+    const ALL: &'static [MenuPage] = &[
+        MenuPage::LowLatency,
+        MenuPage::Shuffle,
+        MenuPage::Repeat,
+        MenuPage::Volume,
+        MenuPage::BlockDevice,
+        MenuPage::UnblockAll,
+        MenuPage::NewPhone,
+        MenuPage::PairNew,
+        MenuPage::Paired,
+        MenuPage::Name,
+        MenuPage::MusicGain,
+        MenuPage::CallGain,
+        MenuPage::VolumeKeys,
+        MenuPage::SpeedVolume,
+        MenuPage::ParkingDuck,
+        MenuPage::BtTest,
+        MenuPage::About,
+    ];
+
+    pub fn render<const N: usize>(
+        &self,
+        arbitration: Arbitration,
+        paired: usize,
+        identity: &Identity,
+        gains: &Gains,
+        settings: &PlayerSettings,
+        text: &mut heapless::String<N>,
+    ) {
+        text.clear();
+
+        let _ = match self {
+            Self::LowLatency => write!(text, "LOW LATENCY"),
+            Self::Shuffle if settings.shuffle => write!(text, "SHUFFLE: ON"),
+            Self::Shuffle => write!(text, "SHUFFLE: OFF"),
+            Self::Repeat if settings.repeat => write!(text, "REPEAT: ON"),
+            Self::Repeat => write!(text, "REPEAT: OFF"),
+            Self::Volume => match settings.volume {
+                VolumeControl::Local => write!(text, "VOLUME: LOCAL"),
+                VolumeControl::Passthrough => write!(text, "VOLUME: KEYS"),
+                VolumeControl::Absolute => write!(text, "VOLUME: ABS"),
+            },
+            Self::BlockDevice => write!(text, "BLOCK PHONE"),
+            Self::UnblockAll => write!(text, "UNBLOCK ALL"),
+            Self::NewPhone => match arbitration {
+                Arbitration::Reject => write!(text, "NEW: REJECT"),
+                Arbitration::Ask => write!(text, "NEW: ASK"),
+                Arbitration::Switch => write!(text, "NEW: SWITCH"),
+            },
+            Self::PairNew => write!(text, "PAIR NEW"),
+            Self::Paired => write!(text, "PAIRED: {}", paired),
+            Self::Name if identity.suffix > 0 => write!(text, "NAME: FIAT {}", identity.suffix),
+            Self::Name => write!(text, "NAME: FIAT"),
+            Self::MusicGain => write!(text, "MUSIC: {}%", Gains::percent(gains.music)),
+            Self::CallGain => write!(text, "CALL: {}%", Gains::percent(gains.call)),
+            Self::VolumeKeys if gains.volume_keys => write!(text, "VOL KEYS: ON"),
+            Self::VolumeKeys => write!(text, "VOL KEYS: OFF"),
+            Self::SpeedVolume if gains.speed_volume => write!(text, "SPD VOL: ON"),
+            Self::SpeedVolume => write!(text, "SPD VOL: OFF"),
+            Self::ParkingDuck if gains.parking_duck => write!(text, "PARK DUCK: ON"),
+            Self::ParkingDuck => write!(text, "PARK DUCK: OFF"),
+            Self::BtTest => write!(text, "BT TEST MODE"),
+            Self::About => write!(text, "V{} {}", version::VERSION, version::GIT_HASH),
+        };
+    }
+}
+
+/// The adapter's own menu, shown on the cockpit display
+pub struct Menu {
+    page: Option<usize>,
+}
+
+impl Menu {
+    pub const fn new() -> Self {
+        Self { page: None }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.page.is_some()
+    }
+
+    pub fn page(&self) -> Option<MenuPage> {
+        self.page.map(|page| MenuPage::ALL[page])
+    }
+
+    pub fn open(&mut self) {
+        self.page = Some(0);
+    }
+
+    pub fn close(&mut self) {
+        self.page = None;
+    }
+
+    pub fn next(&mut self) {
+        if let Some(page) = self.page.as_mut() {
+            *page = (*page + 1) % MenuPage::ALL.len();
+        }
+    }
+
+    pub fn prev(&mut self) {
+        if let Some(page) = self.page.as_mut() {
+            *page = (*page + MenuPage::ALL.len() - 1) % MenuPage::ALL.len();
+        }
+    }
+}
+
+const DTMF_KEYS: &[char] = &['0', '1', '2', '3', '4', '5', '6', '7', '8', '9', '*', '#'];
+
+/// Entry of DTMF digits during a call, for navigating IVR menus
+pub struct DtmfEntry {
+    key: Option<usize>,
+}
+
+impl DtmfEntry {
+    pub const fn new() -> Self {
+        Self { key: None }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn key(&self) -> Option<char> {
+        self.key.map(|key| DTMF_KEYS[key])
+    }
+
+    pub fn open(&mut self) {
+        self.key = Some(0);
+    }
+
+    pub fn close(&mut self) {
+        self.key = None;
+    }
+
+    pub fn next(&mut self) {
+        if let Some(key) = self.key.as_mut() {
+            *key = (*key + 1) % DTMF_KEYS.len();
+        }
+    }
+
+    pub fn prev(&mut self) {
+        if let Some(key) = self.key.as_mut() {
+            *key = (*key + DTMF_KEYS.len() - 1) % DTMF_KEYS.len();
+        }
+    }
+
+    pub fn render<const N: usize>(&self, text: &mut heapless::String<N>) {
+        text.clear();
+
+        if let Some(key) = self.key() {
+            let _ = write!(text, "DTMF: {}", key);
+        }
+    }
+}
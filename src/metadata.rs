@@ -0,0 +1,111 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::Mutex;
+
+use embassy_time::{Duration, Instant};
+
+use esp_idf_svc::hal::task::embassy_sync::EspRawMutex;
+
+/// The first retry comes after that long, the next ones after twice as long, up to `RETRY_MAX`
+pub const RETRY_MIN: Duration = Duration::from_secs(1);
+pub const RETRY_MAX: Duration = Duration::from_secs(8);
+
+/// Including the first request
+const MAX_ATTEMPTS: u8 = 5;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Pending {
+    attempts: u8,
+    retry_at: Instant,
+}
+
+/// The metadata request of a connection. Some phones reject the requests right after connecting,
+/// which would leave the display blank until the next track, so unanswered ones are retried
+pub struct MetadataRetry {
+    pending: Option<Pending>,
+}
+
+impl MetadataRetry {
+    pub const fn new() -> Self {
+        Self { pending: None }
+    }
+
+    /// The metadata was requested, e.g. on connect or once the playback started
+    pub fn requested(&mut self, now: Instant) {
+        if self.pending.is_none() {
+            self.pending = Some(Pending {
+                attempts: 1,
+                retry_at: now + RETRY_MIN,
+            });
+        }
+    }
+
+    /// The metadata arrived, or the connection is gone
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+
+    /// Whether the metadata should be requested again, which is given up after `MAX_ATTEMPTS`
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let Some(pending) = self.pending.as_mut() else {
+            return false;
+        };
+
+        if now < pending.retry_at {
+            return false;
+        }
+
+        if pending.attempts >= MAX_ATTEMPTS {
+            self.pending = None;
+
+            return false;
+        }
+
+        let backoff = RETRY_MIN.as_ticks() << pending.attempts;
+
+        pending.attempts += 1;
+        pending.retry_at = now + Duration::from_ticks(backoff.min(RETRY_MAX.as_ticks()));
+
+        true
+    }
+}
+
+pub type SharedMetadataRetry = Mutex<EspRawMutex, RefCell<MetadataRetry>>;
+
+pub fn create_metadata_retry() -> SharedMetadataRetry {
+    Mutex::new(RefCell::new(MetadataRetry::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Polls every second until then, returning the seconds of the retries
+    fn retries(retry: &mut MetadataRetry, until: u64) -> Vec<u64> {
+        (0..=until)
+            .filter(|secs| retry.poll(Instant::from_secs(*secs)))
+            .collect()
+    }
+
+    #[test]
+    fn backoff() {
+        let mut retry = MetadataRetry::new();
+
+        retry.requested(Instant::from_secs(0));
+
+        assert_eq!(retries(&mut retry, 60), vec![1, 3, 7, 15]);
+    }
+
+    #[test]
+    fn answered() {
+        let mut retry = MetadataRetry::new();
+
+        retry.requested(Instant::from_secs(0));
+
+        assert_eq!(retries(&mut retry, 2), vec![1]);
+
+        retry.reset();
+
+        assert!(retries(&mut retry, 60).is_empty());
+    }
+}
@@ -0,0 +1,52 @@
+//! NOTE: The presence is only announced in update mode, as only then is the WiFi up.
+//! Bluetooth is stopped meanwhile, so the announcements cannot tell whether a phone is connected,
+//! and only say that the car is awake. A BLE advertisement in normal mode would need the
+//! Bluetooth controller in dual mode, whereas it is built for BR/EDR only
+
+use std::net::{Ipv4Addr, UdpSocket};
+
+use embassy_futures::select::{select, Either};
+
+use embassy_time::{Duration, Timer};
+
+use log::warn;
+
+use crate::bus::BusSubscription;
+use crate::error::Error;
+
+/// Home automation setups listen on this port for the "car is here" announcements
+const PORT: u16 = 4210;
+
+const PERIOD: Duration = Duration::from_secs(5);
+
+const PAYLOAD: &str = "{\"awake\":true,\"mode\":\"update\"}";
+
+/// Announces the presence of the car over a WiFi UDP broadcast, while the WiFi is up
+pub async fn process(bus: BusSubscription<'_>) -> Result<(), Error> {
+    loop {
+        let _started = bus.service.started_when_enabled().await?;
+
+        loop {
+            match select(bus.service.wait_disabled(), Timer::after(PERIOD)).await {
+                Either::First(other) => break other?,
+                Either::Second(_) => announce(),
+            }
+        }
+    }
+}
+
+fn announce() {
+    // Fails for as long as the WiFi is not connected
+    if let Err(err) = broadcast(PAYLOAD.as_bytes()) {
+        warn!("Presence broadcast failed: {}", err);
+    }
+}
+
+fn broadcast(payload: &[u8]) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+
+    socket.set_broadcast(true)?;
+    socket.send_to(payload, (Ipv4Addr::BROADCAST, PORT))?;
+
+    Ok(())
+}
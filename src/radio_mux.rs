@@ -0,0 +1,227 @@
+use embassy_time::{Duration, Instant};
+
+use crate::bus::bt::{AudioState, BtCommand};
+use crate::bus::can::RadioState;
+
+/// A phone paused for that long because the radio switched away from BT gets stopped,
+/// so that it releases the audio focus
+pub const STOP_AFTER: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MuxEvent {
+    Radio(RadioState),
+    Phone(AudioState),
+    Audio(AudioState),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MuxAction {
+    Command(BtCommand),
+    /// Makes the radio switch to the BT source for the call
+    SwitchToPhone,
+}
+
+/// Decides when the playback is paused, resumed or stopped, and when the radio is switched to
+/// the phone, as the states of the radio, the call audio and the media audio change
+pub struct RadioMux {
+    radio: RadioState,
+    phone: AudioState,
+    audio: AudioState,
+    /// Set while the playback is paused because the radio switched away from BT
+    paused_since: Option<Instant>,
+    stopped: bool,
+}
+
+impl RadioMux {
+    pub const fn new() -> Self {
+        Self {
+            radio: RadioState::Unknown,
+            phone: AudioState::Uninitialized,
+            audio: AudioState::Uninitialized,
+            paused_since: None,
+            stopped: false,
+        }
+    }
+
+    pub fn update(&mut self, event: MuxEvent, now: Instant) -> Option<MuxAction> {
+        match event {
+            MuxEvent::Radio(new) => {
+                self.radio = new;
+
+                let action = if self.audio.is_active() && !self.phone.is_active() {
+                    if new.is_bt_active() {
+                        Some(BtCommand::Resume)
+                    } else {
+                        self.paused_since.get_or_insert(now);
+
+                        Some(BtCommand::Pause)
+                    }
+                } else if new.is_bt_active() && (self.paused_since.is_some() || self.stopped) {
+                    Some(BtCommand::Resume)
+                } else {
+                    None
+                };
+
+                if new.is_bt_active() {
+                    self.paused_since = None;
+                    self.stopped = false;
+                }
+
+                action.map(MuxAction::Command)
+            }
+            MuxEvent::Phone(new) => {
+                self.phone = new;
+
+                // TODO: Switch back on phone disconnect
+                (new.is_active() && !self.radio.is_bt_active()).then_some(MuxAction::SwitchToPhone)
+            }
+            MuxEvent::Audio(new) => {
+                self.audio = new;
+
+                None
+            }
+        }
+    }
+
+    /// Stops the phone once it was paused for `STOP_AFTER`
+    pub fn poll(&mut self, now: Instant) -> Option<MuxAction> {
+        if self
+            .deadline()
+            .map(|deadline| now < deadline)
+            .unwrap_or(true)
+        {
+            return None;
+        }
+
+        self.paused_since = None;
+        self.stopped = true;
+
+        Some(MuxAction::Command(BtCommand::Stop))
+    }
+
+    /// When `poll` needs to be called next, if at all
+    pub fn deadline(&self) -> Option<Instant> {
+        self.paused_since.map(|since| since + STOP_AFTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use BtCommand::*;
+    use MuxAction::*;
+
+    /// Replays timestamped events, polling in between as the timer would, and
+    /// returns the timestamped actions
+    fn replay(events: &[(u64, MuxEvent)]) -> Vec<(u64, MuxAction)> {
+        let mut mux = RadioMux::new();
+        let mut actions = Vec::new();
+
+        for (secs, event) in events {
+            let now = Instant::from_secs(*secs);
+
+            while let Some(deadline) = mux.deadline().filter(|deadline| *deadline <= now) {
+                if let Some(action) = mux.poll(deadline) {
+                    actions.push((deadline.as_secs(), action));
+                }
+            }
+
+            if let Some(action) = mux.update(*event, now) {
+                actions.push((*secs, action));
+            }
+        }
+
+        actions
+    }
+
+    fn playing() -> Vec<(u64, MuxEvent)> {
+        vec![
+            (0, MuxEvent::Radio(RadioState::BtActive)),
+            (0, MuxEvent::Phone(AudioState::Connected)),
+            (0, MuxEvent::Audio(AudioState::Streaming)),
+        ]
+    }
+
+    #[test]
+    fn call_during_song() {
+        let mut events = playing();
+
+        events.extend([
+            (10, MuxEvent::Audio(AudioState::Suspended)),
+            (10, MuxEvent::Phone(AudioState::Streaming)),
+            (40, MuxEvent::Phone(AudioState::Connected)),
+            (40, MuxEvent::Audio(AudioState::Streaming)),
+        ]);
+
+        // The radio stays on BT, so neither switching nor pausing is needed
+        assert!(replay(&events).is_empty());
+
+        let events = [
+            (0, MuxEvent::Radio(RadioState::Fm)),
+            (0, MuxEvent::Phone(AudioState::Connected)),
+            (10, MuxEvent::Phone(AudioState::Streaming)),
+            (10, MuxEvent::Radio(RadioState::BtActive)),
+            (40, MuxEvent::Phone(AudioState::Connected)),
+        ];
+
+        assert_eq!(replay(&events), vec![(10, SwitchToPhone)]);
+    }
+
+    #[test]
+    fn radio_source_flaps() {
+        let mut events = playing();
+
+        events.extend([
+            (10, MuxEvent::Radio(RadioState::Fm)),
+            (11, MuxEvent::Radio(RadioState::BtActive)),
+            (12, MuxEvent::Radio(RadioState::BtMuted)),
+            (13, MuxEvent::Radio(RadioState::BtActive)),
+        ]);
+
+        assert_eq!(
+            replay(&events),
+            vec![
+                (10, Command(Pause)),
+                (11, Command(Resume)),
+                (12, Command(Pause)),
+                (13, Command(Resume)),
+            ]
+        );
+    }
+
+    #[test]
+    fn stopped_when_away_for_long() {
+        let mut events = playing();
+
+        events.extend([
+            (10, MuxEvent::Radio(RadioState::Fm)),
+            (10, MuxEvent::Audio(AudioState::Suspended)),
+            (400, MuxEvent::Radio(RadioState::BtActive)),
+            (401, MuxEvent::Radio(RadioState::Fm)),
+        ]);
+
+        assert_eq!(
+            replay(&events),
+            vec![
+                (10, Command(Pause)),
+                (310, Command(Stop)),
+                (400, Command(Resume)),
+            ]
+        );
+    }
+
+    #[test]
+    fn phone_disconnect_mid_call() {
+        let events = [
+            (0, MuxEvent::Radio(RadioState::Fm)),
+            (0, MuxEvent::Phone(AudioState::Streaming)),
+            (1, MuxEvent::Radio(RadioState::BtActive)),
+            (20, MuxEvent::Phone(AudioState::Initialized)),
+            (20, MuxEvent::Audio(AudioState::Initialized)),
+        ];
+
+        // The radio is not switched back yet, see the TODO in `update`
+        assert_eq!(replay(&events), vec![(0, SwitchToPhone)]);
+    }
+}
@@ -1,10 +1,22 @@
-use core::cmp::min;
+use core::cmp::{max, min};
+
+/// Running counters of a ring buffer, so that the algorithms tuning the buffering
+/// do not have to track every push and pop themselves
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Metrics {
+    pub pushed: u64,
+    pub popped: u64,
+    /// The oldest data overwritten by pushes into a full buffer
+    pub dropped: u64,
+    pub max_fill: usize,
+}
 
 pub struct RingBuf<'a> {
     buf: &'a mut [u8],
     start: usize,
     end: usize,
     empty: bool,
+    metrics: Metrics,
 }
 
 impl<'a> RingBuf<'a> {
@@ -15,11 +27,17 @@ impl<'a> RingBuf<'a> {
             start: 0,
             end: 0,
             empty: true,
+            metrics: Metrics::default(),
         }
     }
 
     #[inline(always)]
     pub fn push(&mut self, data: &[u8]) -> usize {
+        let free = self.buf.len() - self.len();
+
+        self.metrics.pushed += data.len() as u64;
+        self.metrics.dropped += data.len().saturating_sub(free) as u64;
+
         let mut offset = 0;
 
         while offset < data.len() {
@@ -41,16 +59,19 @@ impl<'a> RingBuf<'a> {
             self.empty = false;
         }
 
-        self.len()
+        self.filled()
     }
 
     #[inline(always)]
     pub fn push_byte(&mut self, data: u8) -> usize {
         self.buf[self.end] = data;
 
+        self.metrics.pushed += 1;
+
         if !self.empty && self.start == self.end {
             // Dropping oldest data
             self.start = self.end + 1;
+            self.metrics.dropped += 1;
         }
 
         self.end += 1;
@@ -59,7 +80,7 @@ impl<'a> RingBuf<'a> {
 
         self.empty = false;
 
-        self.len()
+        self.filled()
     }
 
     #[inline(always)]
@@ -89,6 +110,8 @@ impl<'a> RingBuf<'a> {
             offset += len;
         }
 
+        self.metrics.popped += offset as u64;
+
         offset
     }
 
@@ -118,6 +141,16 @@ impl<'a> RingBuf<'a> {
         self.buf.len()
     }
 
+    #[inline(always)]
+    pub fn metrics(&self) -> Metrics {
+        self.metrics
+    }
+
+    #[inline(always)]
+    pub fn reset_metrics(&mut self) {
+        self.metrics = Default::default();
+    }
+
     #[inline(always)]
     pub fn clear(&mut self) {
         self.start = 0;
@@ -125,6 +158,15 @@ impl<'a> RingBuf<'a> {
         self.empty = true;
     }
 
+    #[inline(always)]
+    fn filled(&mut self) -> usize {
+        let len = self.len();
+
+        self.metrics.max_fill = max(self.metrics.max_fill, len);
+
+        len
+    }
+
     #[inline(always)]
     fn wrap(&mut self) {
         if self.start == self.buf.len() {
@@ -187,4 +229,30 @@ mod tests {
         assert!(rb.is_empty());
         assert!(!rb.is_full());
     }
+
+    #[test]
+    fn metrics() {
+        let mut buf = [0; 4];
+        let mut rb = RingBuf::new(&mut buf);
+
+        rb.push(&[0, 1, 2]);
+        rb.push_byte(3);
+        rb.push(&[4, 5]);
+
+        let mut buf = [0; 3];
+        rb.pop(&mut buf);
+
+        assert_eq!(
+            Metrics {
+                pushed: 6,
+                popped: 3,
+                dropped: 2,
+                max_fill: 4,
+            },
+            rb.metrics()
+        );
+
+        rb.reset_metrics();
+        assert_eq!(Metrics::default(), rb.metrics());
+    }
 }
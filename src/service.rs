@@ -18,46 +18,120 @@ pub enum SystemState {
     Stopping,
 }
 
-const ALWAYS_ON: EnumSet<Service> =
-    enum_set!(Service::Can | Service::CockpitDisplay | Service::RadioDisplay | Service::Commands);
+/// The key position, as reported by the body computer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ignition {
+    Unknown,
+    Off,
+    Accessory,
+    On,
+}
+
+const ALWAYS_ON: EnumSet<Service> = enum_set!(
+    Service::Can
+        | Service::RadioDisplay
+        | Service::Commands
+        | Service::Diagnostics
+        | Service::Storage
+        | Service::Thermal
+);
+
+/// Services only running in update mode, i.e. while the WiFi is in use
+///
+/// NOTE: Hence the presence announcements never see a connected phone, see `presence`
+///
+/// NOTE: As Bluetooth is stopped in update mode, the radio is never shared with the WiFi
+/// (no coexistence), so the A2DP quality (e.g. the SBC bitpool) needs no degrading meanwhile
+const UPDATE: EnumSet<Service> = enum_set!(Service::Wifi | Service::Presence);
 
 pub struct System {
+    spawned: EnumSet<Service>,
     enabled: EnumSet<Service>,
     always_on: EnumSet<Service>,
     started: EnumSet<Service>,
+    suspended: EnumSet<Service>,
     sys_enabled: bool,
+    shedding: bool,
+    service_mode: bool,
+    ignition: Ignition,
+    moving: bool,
 }
 
 impl System {
     pub const fn new() -> Self {
         Self {
+            spawned: EnumSet::EMPTY,
             enabled: EnumSet::EMPTY,
             always_on: ALWAYS_ON,
             started: EnumSet::EMPTY,
+            suspended: EnumSet::EMPTY,
             sys_enabled: true,
+            shedding: false,
+            service_mode: false,
+            ignition: Ignition::Unknown,
+            moving: false,
         }
     }
 
+    /// Only the spawned services are waited for, as some are left out, e.g. in safe mode
+    pub fn set_spawned(&mut self, service: Service) {
+        self.spawned |= service;
+    }
+
+    /// Bluetooth stays up for the console, i.e. for dumping the sniffed frames and injecting ones
     pub fn set_service_mode(&mut self) {
-        self.enabled = EnumSet::EMPTY;
+        self.enabled = enum_set!(Service::Bt);
+        self.service_mode = true;
     }
 
     pub fn set_update_mode(&mut self) {
-        self.enabled = enum_set!(Service::Wifi) & !ALWAYS_ON;
+        self.enabled = UPDATE & !ALWAYS_ON;
+        self.service_mode = false;
     }
 
     pub fn set_normal_mode(&mut self) {
-        self.enabled = EnumSet::ALL & !(Service::Wifi | ALWAYS_ON);
+        self.enabled = EnumSet::ALL & !(UPDATE | ALWAYS_ON);
+        self.service_mode = false;
+    }
+
+    pub fn is_service_mode(&self) -> bool {
+        self.service_mode
+    }
+
+    pub fn ignition(&self) -> Ignition {
+        self.ignition
+    }
+
+    /// The update mode stops Bluetooth and may reboot the adapter, so it is refused unless the car
+    /// stands with the key off or in the accessory position. Without CAN data (i.e. on the bench)
+    /// it is allowed
+    ///
+    /// NOTE: The handbrake is not decoded (its topic is unknown), hence the key position stands in
+    pub fn may_update(&self) -> bool {
+        !self.moving && self.ignition != Ignition::On
+    }
+
+    /// Suspended services are disabled regardless of the mode, e.g. while overheating
+    pub fn set_suspended(&mut self, suspended: EnumSet<Service>) {
+        self.suspended = suspended & !ALWAYS_ON;
+    }
+
+    /// A hint for the low-priority services (e.g. polling) to throttle themselves, set while
+    /// a call is active, as the SCO audio with the echo cancellation leaves little CPU to spare
+    pub fn is_shedding(&self) -> bool {
+        self.shedding
     }
 
     pub fn get_state(&self) -> SystemState {
+        let always_on = self.always_on & self.spawned;
+
         if self.sys_enabled {
-            if self.started == self.enabled | self.always_on {
+            if self.started == (self.enabled & !self.suspended & self.spawned) | always_on {
                 SystemState::Started
             } else {
                 SystemState::Starting
             }
-        } else if self.started == self.always_on {
+        } else if self.started == always_on {
             SystemState::Stopped
         } else {
             SystemState::Stopping
@@ -145,11 +219,21 @@ where
         });
     }
 
-    pub fn sys_set_update_mode(&self) {
+    /// Returns `false` if refused, see `System::may_update`
+    pub fn sys_set_update_mode(&self) -> bool {
+        let mut allowed = false;
+
         self.sender.modify(|sys| {
-            sys.set_update_mode();
-            true
+            allowed = sys.may_update();
+
+            if allowed {
+                sys.set_update_mode();
+            }
+
+            allowed
         });
+
+        allowed
     }
 
     pub fn sys_set_normal_mode(&self) {
@@ -159,6 +243,62 @@ where
         });
     }
 
+    pub fn sys_suspend(&self, services: EnumSet<Service>) {
+        self.sender.modify(|sys| {
+            sys.set_suspended(services);
+            true
+        });
+    }
+
+    pub fn sys_set_shedding(&self, shedding: bool) {
+        self.sender.modify(|sys| {
+            if sys.shedding != shedding {
+                sys.shedding = shedding;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn is_shedding(&self) -> bool {
+        self.receiver.state(|state| state.is_shedding())
+    }
+
+    pub fn is_service_mode(&self) -> bool {
+        self.receiver.state(|state| state.is_service_mode())
+    }
+
+    pub fn sys_set_ignition(&self, ignition: Ignition) {
+        self.sender.modify(|sys| {
+            if sys.ignition != ignition {
+                sys.ignition = ignition;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn ignition(&self) -> Ignition {
+        self.receiver.state(|state| state.ignition())
+    }
+
+    pub fn sys_set_moving(&self, moving: bool) {
+        self.sender.modify(|sys| {
+            if sys.moving != moving {
+                sys.moving = moving;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.receiver.state(|state| self.enabled(state))
+    }
+
     pub async fn wait_disabled(&self) -> Result<(), Error> {
         self.wait_enabled_disabled(false).await
     }
@@ -173,6 +313,35 @@ where
         Ok(self.started())
     }
 
+    /// Like `wait_disabled`, but also completes once the system state satisfies `f`
+    pub async fn wait_disabled_or_sys_state<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: Fn(SystemState) -> bool,
+    {
+        loop {
+            self.receiver.recv().await;
+
+            let (enabled, sys_state) = self
+                .receiver
+                .state(|state| (self.enabled(state), state.get_state()));
+
+            if !enabled || f(sys_state) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn enabled(&self, state: &System) -> bool {
+        if state.sys_enabled {
+            (state.enabled & !state.suspended).contains(self.service)
+                | state.always_on.contains(self.service)
+        } else {
+            state.always_on.contains(self.service)
+        }
+    }
+
     fn set_started(&self, started: bool) {
         self.sender.modify(|state| {
             let was_started = state.started.contains(self.service);
@@ -182,7 +351,7 @@ where
                     state.started |= self.service;
                     info!("Service {:?} started", self.service);
                 } else {
-                    state.started &= self.service;
+                    state.started &= !self.service;
                     info!("Service {:?} stopped", self.service);
                 }
 
@@ -197,13 +366,7 @@ where
         loop {
             self.receiver.recv().await;
 
-            let enabled = self.receiver.state(|state| {
-                if state.sys_enabled {
-                    state.enabled.contains(self.service) | state.always_on.contains(self.service)
-                } else {
-                    state.always_on.contains(self.service)
-                }
-            });
+            let enabled = self.receiver.state(|state| self.enabled(state));
 
             if enabled == wait_enabled {
                 break;
@@ -213,3 +376,89 @@ where
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+
+    fn spawned(services: EnumSet<Service>) -> System {
+        let mut system = System::new();
+
+        for service in services {
+            system.set_spawned(service);
+        }
+
+        system.set_normal_mode();
+
+        system
+    }
+
+    #[test]
+    fn started() {
+        let mut system = spawned(enum_set!(Service::Can | Service::Storage | Service::Bt));
+
+        system.started = enum_set!(Service::Can | Service::Storage);
+        assert_eq!(system.get_state(), SystemState::Starting);
+
+        system.started |= Service::Bt;
+        assert_eq!(system.get_state(), SystemState::Started);
+    }
+
+    #[test]
+    fn stopped() {
+        let mut system = spawned(enum_set!(Service::Can | Service::Storage | Service::Bt));
+
+        system.started = enum_set!(Service::Can | Service::Storage | Service::Bt);
+        system.sys_enabled = false;
+        assert_eq!(system.get_state(), SystemState::Stopping);
+
+        system.started &= !Service::Bt;
+        assert_eq!(system.get_state(), SystemState::Stopped);
+    }
+
+    #[test]
+    fn suspended() {
+        let mut system = spawned(enum_set!(Service::Can | Service::Bt | Service::Speakers));
+
+        system.set_suspended(enum_set!(Service::Speakers | Service::Can));
+        system.started = enum_set!(Service::Can | Service::Bt);
+        assert_eq!(system.get_state(), SystemState::Started);
+    }
+
+    #[test]
+    fn lifecycle() {
+        let system = StatefulBroadcastSignal::<NoopRawMutex, _>::new(spawned(enum_set!(
+            Service::Can | Service::Bt
+        )));
+
+        let can = ServiceLifecycle::new(Service::Can, &system);
+        let bt = ServiceLifecycle::new(Service::Bt, &system);
+
+        can.set_started(true);
+        bt.set_started(true);
+        assert_eq!(can.receiver.state(System::get_state), SystemState::Started);
+
+        can.sender.modify(|state| {
+            state.sys_enabled = false;
+            true
+        });
+        bt.set_started(false);
+        assert_eq!(can.receiver.state(System::get_state), SystemState::Stopped);
+    }
+
+    #[test]
+    fn presence_without_bt() {
+        let mut system = spawned(EnumSet::ALL);
+
+        assert!(!system.enabled.contains(Service::Presence));
+
+        system.set_service_mode();
+        assert!(!system.enabled.contains(Service::Presence));
+
+        system.set_update_mode();
+        assert!(system.enabled.contains(Service::Presence));
+        assert!(!system.enabled.contains(Service::Bt));
+    }
+}
@@ -2,39 +2,89 @@ use core::cell::RefCell;
 
 use embassy_sync::{
     blocking_mutex::{raw::RawMutex, Mutex},
+    channel::Channel,
     signal::Signal,
 };
 
 use crate::bus::Service;
 
-const MAX_RECEIVERS: usize = 9;
+/// One receiver per service, unless a topic is only subscribed by a few
+pub const MAX_RECEIVERS: usize = Service::COUNT;
 
-pub struct BroadcastSignal<M, T>([Signal<M, T>; MAX_RECEIVERS])
+/// Values a queue keeps until its subscriber catches up
+pub const QUEUE_LEN: usize = 8;
+
+/// `N` receivers, one for each of the subscribed services, in the order they were given
+pub struct BroadcastSignal<M, T, const N: usize = MAX_RECEIVERS>
 where
-    M: RawMutex;
+    M: RawMutex,
+{
+    subscribers: [Service; N],
+    signals: [Signal<M, T>; N],
+}
 
 impl<M, T> BroadcastSignal<M, T>
+where
+    M: RawMutex,
+{
+    pub const fn new() -> Self {
+        Self::subscribed(Service::ALL)
+    }
+}
+
+impl<M, T, const N: usize> BroadcastSignal<M, T, N>
 where
     M: RawMutex,
 {
     const INIT: Signal<M, T> = Signal::new();
 
-    pub const fn new() -> Self {
-        Self([Self::INIT; MAX_RECEIVERS])
+    /// `N` follows from the subscribers, so that a topic cannot run out of receivers.
+    /// The other services still get receivers, which never receive anything
+    pub const fn subscribed(subscribers: [Service; N]) -> Self {
+        assert!(distinct(&subscribers), "Services subscribed twice");
+
+        Self {
+            subscribers,
+            signals: [Self::INIT; N],
+        }
     }
 
     pub fn receiver(&self, service: Service) -> Receiver<'_, M, T> {
-        let index = service as usize;
+        let index = self
+            .subscribers
+            .iter()
+            .position(|subscriber| *subscriber == service);
 
-        Receiver(&self.0[index])
+        Receiver(index.map(|index| &self.signals[index]))
     }
 
     pub fn sender(&self) -> Sender<'_, M, T> {
-        Sender(&self.0)
+        Sender(&self.signals)
     }
 }
 
-pub struct Receiver<'a, M, T>(&'a Signal<M, T>)
+/// Whether no service is listed twice, for the const assertions on the subscribers
+pub const fn distinct(services: &[Service]) -> bool {
+    let mut index = 0;
+
+    while index < services.len() {
+        let mut other = index + 1;
+
+        while other < services.len() {
+            if services[index] as usize == services[other] as usize {
+                return false;
+            }
+
+            other += 1;
+        }
+
+        index += 1;
+    }
+
+    true
+}
+
+pub struct Receiver<'a, M, T>(Option<&'a Signal<M, T>>)
 where
     M: RawMutex;
 
@@ -44,7 +94,10 @@ where
     T: Send,
 {
     pub async fn recv(&self) -> T {
-        self.0.wait().await
+        match self.0 {
+            Some(signal) => signal.wait().await,
+            None => core::future::pending().await,
+        }
     }
 }
 
@@ -88,7 +141,7 @@ where
     }
 
     pub fn sender(&self) -> StatefulSender<'_, M, S> {
-        StatefulSender(&self.signal.0, &self.state)
+        StatefulSender(&self.signal.signals, &self.state)
     }
 }
 
@@ -127,3 +180,63 @@ where
         })
     }
 }
+
+/// Unlike the signals, keeps up to `N` values for a single subscriber, so that values sent back
+/// to back do not overwrite each other. Values sent while the queue is full are dropped
+pub struct Queue<M, T, const N: usize = QUEUE_LEN>
+where
+    M: RawMutex,
+{
+    subscriber: Service,
+    channel: Channel<M, T, N>,
+}
+
+impl<M, T, const N: usize> Queue<M, T, N>
+where
+    M: RawMutex,
+{
+    pub const fn new(subscriber: Service) -> Self {
+        Self {
+            subscriber,
+            channel: Channel::new(),
+        }
+    }
+
+    /// The other services still get receivers, which never receive anything
+    pub fn receiver(&self, service: Service) -> QueueReceiver<'_, M, T, N> {
+        QueueReceiver((service == self.subscriber).then_some(&self.channel))
+    }
+
+    pub fn sender(&self) -> QueueSender<'_, M, T, N> {
+        QueueSender(&self.channel)
+    }
+}
+
+pub struct QueueReceiver<'a, M, T, const N: usize = QUEUE_LEN>(Option<&'a Channel<M, T, N>>)
+where
+    M: RawMutex;
+
+impl<'a, M, T, const N: usize> QueueReceiver<'a, M, T, N>
+where
+    M: RawMutex,
+{
+    pub async fn recv(&self) -> T {
+        match self.0 {
+            Some(channel) => channel.receive().await,
+            None => core::future::pending().await,
+        }
+    }
+}
+
+pub struct QueueSender<'a, M, T, const N: usize = QUEUE_LEN>(&'a Channel<M, T, N>)
+where
+    M: RawMutex;
+
+impl<'a, M, T, const N: usize> QueueSender<'a, M, T, N>
+where
+    M: RawMutex,
+{
+    pub fn send(&self, value: T) {
+        let _ = self.0.try_send(value);
+    }
+}
@@ -0,0 +1,250 @@
+use embassy_futures::select::{select, Either};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use embassy_time::{Duration, Instant, Timer};
+
+use log::info;
+
+use crate::bus::{
+    bt::{
+        AudioState, AudioTrackState, BtCommand, BtState, PhoneCallInfo, PhoneCallState, TrackInfo,
+    },
+    set_text, BusSubscription,
+};
+use crate::error::Error;
+use crate::signal::{Sender, StatefulSender};
+
+const TICK: Duration = Duration::from_secs(1);
+
+const RING_TIMEOUT: Duration = Duration::from_secs(15);
+const CALL_DURATION: Duration = Duration::from_secs(30);
+
+enum Step {
+    Track {
+        artist: &'static str,
+        album: &'static str,
+        song: &'static str,
+        secs: u64,
+    },
+    Incoming(&'static str),
+}
+
+/// Long texts on purpose, so that the truncation and the scrolling get exercised too
+const SCENARIO: &[Step] = &[
+    Step::Track {
+        artist: "Paolo Conte",
+        album: "Azzurro",
+        song: "Azzurro",
+        secs: 20,
+    },
+    Step::Track {
+        artist: "Lucio Battisti",
+        album: "Emozioni - The Best Of Lucio Battisti Remastered",
+        song: "Emozioni",
+        secs: 20,
+    },
+    Step::Incoming("+39 02 1234567"),
+    Step::Track {
+        artist: "Mina",
+        album: "Studio Uno 66",
+        song: "Se telefonando",
+        secs: 20,
+    },
+];
+
+/// Plays scripted scenarios on the bus in place of a phone, so that the displays and the
+/// button handling can be iterated on in the car without any phone around.
+///
+/// Runs in the slot of the Bluetooth service and reacts to the commands sent to it.
+pub async fn process(
+    bus: BusSubscription<'_>,
+    bt: Sender<'_, impl RawMutex, BtState>,
+    audio: Sender<'_, impl RawMutex, AudioState>,
+    audio_track: StatefulSender<'_, impl RawMutex, TrackInfo>,
+    phone: Sender<'_, impl RawMutex, AudioState>,
+    phone_call: StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
+) -> Result<(), Error> {
+    loop {
+        let _started = bus.service.started_when_enabled().await?;
+
+        info!("Simulating a phone");
+
+        bt.send(BtState::Connected);
+        phone.send(AudioState::Connected);
+
+        match select(
+            bus.service.wait_disabled(),
+            run(&bus, &audio, &audio_track, &phone, &phone_call),
+        )
+        .await
+        {
+            Either::First(other) => other?,
+            Either::Second(_) => unreachable!(),
+        }
+
+        bt.send(BtState::Initialized);
+    }
+}
+
+async fn run(
+    bus: &BusSubscription<'_>,
+    audio: &Sender<'_, impl RawMutex, AudioState>,
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    phone: &Sender<'_, impl RawMutex, AudioState>,
+    phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
+) {
+    loop {
+        for step in SCENARIO {
+            match step {
+                Step::Track {
+                    artist,
+                    album,
+                    song,
+                    secs,
+                } => play(bus, audio, audio_track, artist, album, song, *secs).await,
+                Step::Incoming(number) => call(bus, audio, phone, phone_call, number).await,
+            }
+        }
+    }
+}
+
+async fn play(
+    bus: &BusSubscription<'_>,
+    audio: &Sender<'_, impl RawMutex, AudioState>,
+    audio_track: &StatefulSender<'_, impl RawMutex, TrackInfo>,
+    artist: &str,
+    album: &str,
+    song: &str,
+    secs: u64,
+) {
+    audio.send(AudioState::Streaming);
+
+    audio_track.modify(|track| {
+        track.reset();
+        track.state = AudioTrackState::Playing;
+        set_text(&mut track.artist, artist);
+        set_text(&mut track.album, album);
+        set_text(&mut track.song, song);
+        track.duration = core::time::Duration::from_secs(secs);
+        track.version += 1;
+        true
+    });
+
+    let mut paused = false;
+    let mut offset = 0;
+
+    while offset < secs {
+        match select(command(bus), Timer::after(TICK)).await {
+            Either::First(BtCommand::Pause | BtCommand::Stop) => paused = true,
+            Either::First(BtCommand::Resume) => paused = false,
+            Either::First(BtCommand::NextTrack) => break,
+            Either::First(BtCommand::PreviousTrack) => offset = 0,
+            Either::First(_) => continue,
+            Either::Second(_) if !paused => offset += 1,
+            Either::Second(_) => (),
+        }
+
+        audio.send(if paused {
+            AudioState::Suspended
+        } else {
+            AudioState::Streaming
+        });
+
+        audio_track.modify(|track| {
+            track.state = if paused {
+                AudioTrackState::Paused
+            } else {
+                AudioTrackState::Playing
+            };
+            track.paused = paused;
+            track.offset = core::time::Duration::from_secs(offset);
+            track.version += 1;
+            true
+        });
+    }
+}
+
+async fn call(
+    bus: &BusSubscription<'_>,
+    audio: &Sender<'_, impl RawMutex, AudioState>,
+    phone: &Sender<'_, impl RawMutex, AudioState>,
+    phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
+    number: &str,
+) {
+    audio.send(AudioState::Suspended);
+
+    set_call(phone_call, PhoneCallState::Ringing, Some(number), 0);
+
+    let ringing_since = Instant::now();
+
+    let answered = loop {
+        match select(command(bus), Timer::after(TICK)).await {
+            Either::First(BtCommand::Answer) => break true,
+            Either::First(BtCommand::Reject) => break false,
+            _ if ringing_since.elapsed() >= RING_TIMEOUT => break false,
+            _ => (),
+        }
+    };
+
+    if answered {
+        phone.send(AudioState::Streaming);
+
+        let active_since = Instant::now();
+
+        loop {
+            let elapsed = active_since.elapsed();
+
+            set_call(
+                phone_call,
+                PhoneCallState::CallActive,
+                None,
+                elapsed.as_secs(),
+            );
+
+            if elapsed >= CALL_DURATION {
+                break;
+            }
+
+            if let Either::First(BtCommand::Hangup) = select(command(bus), Timer::after(TICK)).await
+            {
+                break;
+            }
+        }
+
+        phone.send(AudioState::Connected);
+    }
+
+    phone_call.modify(|call| {
+        call.state = PhoneCallState::Idle;
+        call.reset();
+        call.version += 1;
+        true
+    });
+}
+
+fn set_call(
+    phone_call: &StatefulSender<'_, impl RawMutex, PhoneCallInfo>,
+    state: PhoneCallState,
+    number: Option<&str>,
+    secs: u64,
+) {
+    phone_call.modify(|call| {
+        call.state = state;
+
+        if let Some(number) = number {
+            set_text(&mut call.phone, number);
+        }
+
+        call.duration = core::time::Duration::from_secs(secs);
+        call.version += 1;
+        true
+    });
+}
+
+/// The commands from the steering wheel and from the radio, as `bt` would get them
+async fn command(bus: &BusSubscription<'_>) -> BtCommand {
+    match select(bus.button_commands.recv(), bus.radio_commands.recv()).await {
+        Either::First(command) | Either::Second(command) => command,
+    }
+}
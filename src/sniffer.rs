@@ -0,0 +1,160 @@
+use embassy_time::Instant;
+
+use esp_idf_svc::hal::can::Frame;
+
+use log::info;
+
+use crate::error::Error;
+use crate::storage::Storage;
+
+const RECORD_LEN: usize = 17;
+const RECORDS_PER_SLOT: usize = 3;
+const MAX_IDS: usize = 64;
+
+const HEAD_KEY: &str = "sniff_head";
+
+/// The flash-backed ring, each NVS value holding `RECORDS_PER_SLOT` records
+const SLOT_KEYS: &[&str] = &[
+    "sniff0", "sniff1", "sniff2", "sniff3", "sniff4", "sniff5", "sniff6", "sniff7", "sniff8",
+    "sniff9", "sniff10", "sniff11", "sniff12", "sniff13", "sniff14", "sniff15",
+];
+
+/// A received frame of a topic or a publisher which is not decoded (yet)
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Record {
+    millis: u32,
+    id: u32,
+    data: heapless::Vec<u8, 8>,
+}
+
+impl Record {
+    fn encode(&self, buf: &mut heapless::Vec<u8, 64>) {
+        let mut data = [0; 8];
+        data[..self.data.len()].copy_from_slice(&self.data);
+
+        let _ = buf.extend_from_slice(&self.millis.to_le_bytes());
+        let _ = buf.extend_from_slice(&self.id.to_le_bytes());
+        let _ = buf.push(self.data.len() as _);
+        let _ = buf.extend_from_slice(&data);
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let len = *buf.get(8)? as usize;
+
+        Some(Self {
+            millis: u32::from_le_bytes(buf.get(..4)?.try_into().ok()?),
+            id: u32::from_le_bytes(buf.get(4..8)?.try_into().ok()?),
+            data: heapless::Vec::from_slice(buf.get(9..9 + len.min(8))?).ok()?,
+        })
+    }
+}
+
+/// Records the unknown frames into NVS for reverse engineering more topics, but only when their
+/// payload changed, as the periodic ones would otherwise fill the ring (and wear the flash) in seconds
+pub struct Sniffer {
+    last: heapless::FnvIndexMap<u32, heapless::Vec<u8, 8>, MAX_IDS>,
+    pending: heapless::Vec<Record, RECORDS_PER_SLOT>,
+}
+
+impl Sniffer {
+    pub const fn new() -> Self {
+        Self {
+            last: heapless::FnvIndexMap::new(),
+            pending: heapless::Vec::new(),
+        }
+    }
+
+    pub async fn record(&mut self, frame: &Frame, storage: &Storage) -> Result<(), Error> {
+        let data = heapless::Vec::from_slice(frame.data()).unwrap();
+
+        if self.last.get(&frame.identifier()) == Some(&data) {
+            return Ok(());
+        }
+
+        // Once too many ids are tracked, the new ones are recorded on every frame
+        let _ = self.last.insert(frame.identifier(), data.clone());
+
+        let _ = self.pending.push(Record {
+            millis: Instant::now().as_millis() as _,
+            id: frame.identifier(),
+            data,
+        });
+
+        if self.pending.is_full() {
+            let head = storage.get_u32(HEAD_KEY).await?.unwrap_or(0);
+
+            let mut value = heapless::Vec::new();
+
+            for record in self.pending.iter() {
+                record.encode(&mut value);
+            }
+
+            storage
+                .set(SLOT_KEYS[head as usize % SLOT_KEYS.len()], &value)
+                .await?;
+            storage.set_u32(HEAD_KEY, head.wrapping_add(1)).await?;
+
+            self.pending.clear();
+        }
+
+        Ok(())
+    }
+}
+
+/// Logs the recorded frames, oldest first, i.e. over the console
+pub async fn dump(storage: &Storage) -> Result<(), Error> {
+    let head = storage.get_u32(HEAD_KEY).await?.unwrap_or(0) as usize;
+
+    for slot in 0..SLOT_KEYS.len() {
+        let key = SLOT_KEYS[(head + slot) % SLOT_KEYS.len()];
+
+        if let Some(value) = storage.get(key).await? {
+            for record in value.chunks(RECORD_LEN).filter_map(Record::decode) {
+                info!(
+                    "{:>8} {:08x}: {:02x?}",
+                    record.millis, record.id, record.data
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn clear(storage: &Storage) -> Result<(), Error> {
+    for key in SLOT_KEYS {
+        storage.remove(key).await?;
+    }
+
+    storage.remove(HEAD_KEY).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let record = Record {
+            millis: 123456,
+            id: 0x0a194000,
+            data: heapless::Vec::from_slice(&[1, 2, 3]).unwrap(),
+        };
+
+        let mut buf = heapless::Vec::new();
+
+        for _ in 0..RECORDS_PER_SLOT {
+            record.encode(&mut buf);
+        }
+
+        assert_eq!(buf.len(), RECORD_LEN * RECORDS_PER_SLOT);
+
+        let decoded = buf
+            .chunks(RECORD_LEN)
+            .filter_map(Record::decode)
+            .collect::<Vec<_>>();
+
+        assert_eq!(decoded, vec![record.clone(); RECORDS_PER_SLOT]);
+        assert!(Record::decode(&buf[..8]).is_none());
+    }
+}
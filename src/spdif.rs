@@ -0,0 +1,152 @@
+//! S/PDIF encoding of 16-bit stereo PCM, to be sent over the I2S data line.
+//!
+//! The I2S peripheral is clocked at twice the sample rate with 32-bit stereo slots, so that each
+//! S/PDIF subframe (32 biphase-mark encoded time slots, i.e. 64 cells) fits in two I2S words.
+//!
+//! Each subframe is laid out as VUCP of the previous subframe, preamble, aux bits and then the
+//! 16-bit sample, so that samples are aligned to I2S word boundaries. The polarity of each
+//! encoded byte is adjusted to the one following it, and the spare aux bit takes care of the
+//! parity, which allows for fixed VUCP and preamble patterns.
+
+const VUCP: u32 = 0xcc << 24;
+
+const PREAMBLE_B: u32 = 0xe8;
+const PREAMBLE_M: u32 = 0xe2;
+const PREAMBLE_W: u32 = 0xe4;
+
+const AUX: u32 = 0xb333;
+const AUX_FLIPPED: u32 = 0xcccc;
+
+const SUBFRAMES_PER_BLOCK: u16 = 384;
+
+/// Biphase-mark encoded bytes (LSB first), all ending with a low cell
+const BMC: [u16; 256] = bmc_table();
+
+pub struct SpdifEncoder<'a> {
+    buf: &'a mut [u32],
+    subframe: u16,
+}
+
+impl<'a> SpdifEncoder<'a> {
+    pub fn new(buf: &'a mut [u32]) -> Self {
+        Self { buf, subframe: 0 }
+    }
+
+    /// The max number of PCM bytes `encode` can take at once
+    pub fn chunk_len(&self, repeat: usize) -> usize {
+        self.buf.len() / (4 * repeat) * 4
+    }
+
+    /// Encodes 16-bit stereo PCM, repeating each frame `repeat` times so as to upsample
+    /// rates S/PDIF receivers cannot lock onto
+    pub fn encode(&mut self, pcm: &[u8], repeat: usize) -> &[u8] {
+        let mut offset = 0;
+
+        for frame in pcm.chunks_exact(4) {
+            for _ in 0..repeat {
+                for sample in frame.chunks_exact(2) {
+                    let (audio, header) = self.encode_sample(sample[0], sample[1]);
+
+                    // The ESP32 I2S peripheral sends the second slot of each frame first
+                    self.buf[offset] = audio;
+                    self.buf[offset + 1] = header;
+
+                    offset += 2;
+                }
+            }
+        }
+
+        as_u8_slice(&self.buf[..offset])
+    }
+
+    fn encode_sample(&mut self, lsb: u8, msb: u8) -> (u32, u32) {
+        let hi = BMC[msb as usize];
+        let mut lo = BMC[lsb as usize];
+
+        if hi & 0x8000 == 0 {
+            lo = !lo;
+        }
+
+        let aux = if lo & 0x8000 != 0 { AUX_FLIPPED } else { AUX };
+
+        let preamble = if self.subframe == 0 {
+            PREAMBLE_B
+        } else if self.subframe % 2 == 0 {
+            PREAMBLE_M
+        } else {
+            PREAMBLE_W
+        };
+
+        self.subframe = (self.subframe + 1) % SUBFRAMES_PER_BLOCK;
+
+        (
+            ((lo as u32) << 16) | hi as u32,
+            VUCP | (preamble << 16) | aux,
+        )
+    }
+}
+
+const fn bmc_table() -> [u16; 256] {
+    let mut table = [0; 256];
+
+    let mut value = 0;
+
+    while value < 256 {
+        let mut bmc = 0;
+        let mut end = 0;
+
+        // Going backwards, as the level of the last cell is the fixed one
+        let mut bit = 8;
+
+        while bit > 0 {
+            bit -= 1;
+
+            let first = if (value >> bit) & 1 == 1 {
+                end ^ 1
+            } else {
+                end
+            };
+
+            bmc |= ((first << 1) | end) << (14 - 2 * bit);
+            end = first ^ 1;
+        }
+
+        table[value] = bmc as u16;
+        value += 1;
+    }
+
+    table
+}
+
+fn as_u8_slice(slice: &[u32]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(slice.as_ptr() as *const _, slice.len() * 4) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bmc() {
+        assert_eq!(
+            BMC[..8],
+            [0xcccc, 0x4ccc, 0x2ccc, 0xaccc, 0x34cc, 0xb4cc, 0xd4cc, 0x54cc]
+        );
+
+        // Every encoded byte ends with a low cell
+        assert!(BMC.iter().all(|bmc| bmc & 1 == 0));
+    }
+
+    #[test]
+    fn preambles() {
+        let mut buf = [0; 8];
+        let mut encoder = SpdifEncoder::new(&mut buf);
+
+        encoder.encode(&[0; 8], 1);
+
+        assert_eq!((buf[1] >> 16) & 0xff, PREAMBLE_B);
+        assert_eq!((buf[3] >> 16) & 0xff, PREAMBLE_W);
+        assert_eq!((buf[5] >> 16) & 0xff, PREAMBLE_M);
+        assert_eq!((buf[7] >> 16) & 0xff, PREAMBLE_W);
+    }
+}
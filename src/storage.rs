@@ -0,0 +1,264 @@
+use core::cell::Cell;
+
+use embassy_futures::select::{select, Either};
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+
+use embassy_time::{Duration, Timer};
+
+use esp_idf_svc::hal::reset::restart;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{esp, nvs_flash_erase, EspError, ESP_ERR_NVS_VALUE_TOO_LONG};
+
+use log::{error, info, warn};
+
+use crate::bus::BusSubscription;
+use crate::error::Error;
+
+pub const NAMESPACE: &str = "fiat";
+
+/// Writes are coalesced in RAM and written to flash only once the storage
+/// was quiet for that long (or once the batch is full), so that repeated writes
+/// of a key reach the flash once
+///
+/// NOTE: `EspNvs` commits each key on its own, so a batch is not written atomically
+const COMMIT_DELAY: Duration = Duration::from_secs(2);
+const MAX_PENDING: usize = 8;
+
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(10);
+
+pub type Value = heapless::Vec<u8, 64>;
+
+enum Request {
+    Get(&'static str),
+    Set(&'static str, Value),
+    Remove(&'static str),
+    Erase,
+    Restart,
+}
+
+/// Asynchronous facade to the NVS partition.
+///
+/// All services access NVS through this type, while the NVS handle itself is
+/// owned by a single task (`process`), so there are no concurrent raw accesses.
+///
+/// Each request carries a token, which its response echoes, so that the late response
+/// to a cancelled request is not taken by the next caller
+pub struct Storage {
+    lock: Mutex<NoopRawMutex, ()>,
+    token: Cell<u32>,
+    request: Signal<NoopRawMutex, (u32, Request)>,
+    response: Signal<NoopRawMutex, (u32, Result<Option<Value>, Error>)>,
+}
+
+impl Storage {
+    pub const fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+            token: Cell::new(0),
+            request: Signal::new(),
+            response: Signal::new(),
+        }
+    }
+
+    pub async fn get(&self, key: &'static str) -> Result<Option<Value>, Error> {
+        self.request(Request::Get(key)).await
+    }
+
+    /// Values longer than a `Value` are refused with `ESP_ERR_NVS_VALUE_TOO_LONG`
+    pub async fn set(&self, key: &'static str, value: &[u8]) -> Result<(), Error> {
+        let value = Value::from_slice(value)
+            .map_err(|_| EspError::from_infallible::<{ ESP_ERR_NVS_VALUE_TOO_LONG as i32 }>())?;
+
+        self.request(Request::Set(key, value)).await?;
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, key: &'static str) -> Result<(), Error> {
+        self.request(Request::Remove(key)).await?;
+
+        Ok(())
+    }
+
+    /// Erases the whole NVS partition (including the Bluetooth bonds) and restarts
+    pub async fn factory_reset(&self) -> Result<(), Error> {
+        self.request(Request::Erase).await?;
+
+        Ok(())
+    }
+
+    /// Commits the pending writes and restarts
+    pub async fn restart(&self) -> Result<(), Error> {
+        self.request(Request::Restart).await?;
+
+        Ok(())
+    }
+
+    pub async fn get_u32(&self, key: &'static str) -> Result<Option<u32>, Error> {
+        Ok(self
+            .get(key)
+            .await?
+            .and_then(|value| value.as_slice().try_into().ok())
+            .map(u32::from_le_bytes))
+    }
+
+    pub async fn set_u32(&self, key: &'static str, value: u32) -> Result<(), Error> {
+        self.set(key, &value.to_le_bytes()).await
+    }
+
+    pub async fn increment(&self, key: &'static str) -> Result<u32, Error> {
+        let value = self.get_u32(key).await?.unwrap_or(0).wrapping_add(1);
+
+        self.set_u32(key, value).await?;
+
+        Ok(value)
+    }
+
+    async fn request(&self, request: Request) -> Result<Option<Value>, Error> {
+        let _guard = self.lock.lock().await;
+
+        let token = self.token.get().wrapping_add(1);
+        self.token.set(token);
+
+        self.response.reset();
+        self.request.signal((token, request));
+
+        loop {
+            let (rtoken, response) = self.response.wait().await;
+
+            if rtoken == token {
+                break response;
+            }
+        }
+    }
+}
+
+pub async fn process(
+    bus: BusSubscription<'_>,
+    storage: &Storage,
+    partition: EspDefaultNvsPartition,
+) -> Result<(), Error> {
+    let mut nvs = EspNvs::new(partition, NAMESPACE, true)?;
+    let mut pending = heapless::Vec::<(&'static str, Option<Value>), MAX_PENDING>::new();
+
+    loop {
+        let _started = bus.service.started_when_enabled().await?;
+
+        loop {
+            let request = if pending.is_empty() {
+                Either::First(storage.request.wait().await)
+            } else {
+                select(storage.request.wait(), Timer::after(COMMIT_DELAY)).await
+            };
+
+            let (token, request) = match request {
+                Either::First(request) => request,
+                Either::Second(_) => {
+                    commit(&mut nvs, &mut pending).await;
+                    continue;
+                }
+            };
+
+            let response = match request {
+                Request::Get(key) => {
+                    if let Some((_, value)) = pending.iter().find(|(pkey, _)| *pkey == key) {
+                        Ok(value.clone())
+                    } else {
+                        read(&nvs, key)
+                    }
+                }
+                Request::Set(key, value) => {
+                    stage(&mut nvs, &mut pending, key, Some(value)).await;
+                    Ok(None)
+                }
+                Request::Remove(key) => {
+                    stage(&mut nvs, &mut pending, key, None).await;
+                    Ok(None)
+                }
+                Request::Erase => {
+                    warn!("Factory reset");
+
+                    pending.clear();
+
+                    match esp!(unsafe { nvs_flash_erase() }) {
+                        Ok(()) => restart(),
+                        Err(err) => Err(err.into()),
+                    }
+                }
+                Request::Restart => {
+                    commit(&mut nvs, &mut pending).await;
+                    restart()
+                }
+            };
+
+            storage.response.signal((token, response));
+        }
+    }
+}
+
+fn read(nvs: &EspNvs<NvsDefault>, key: &str) -> Result<Option<Value>, Error> {
+    let mut buf = [0; 64];
+
+    Ok(nvs
+        .get_raw(key, &mut buf)?
+        .map(|value| Value::from_slice(value).unwrap()))
+}
+
+async fn stage(
+    nvs: &mut EspNvs<NvsDefault>,
+    pending: &mut heapless::Vec<(&'static str, Option<Value>), MAX_PENDING>,
+    key: &'static str,
+    value: Option<Value>,
+) {
+    if let Some((_, pvalue)) = pending.iter_mut().find(|(pkey, _)| *pkey == key) {
+        *pvalue = value;
+    } else {
+        if pending.is_full() {
+            commit(nvs, pending).await;
+        }
+
+        pending.push((key, value)).unwrap();
+    }
+}
+
+async fn commit(
+    nvs: &mut EspNvs<NvsDefault>,
+    pending: &mut heapless::Vec<(&'static str, Option<Value>), MAX_PENDING>,
+) {
+    info!("Writing {} NVS changes", pending.len());
+
+    for (key, value) in pending.iter() {
+        let mut delay = RETRY_DELAY;
+        let mut retries = 0;
+
+        while let Err(err) = write(nvs, key, value.as_deref()) {
+            if retries == MAX_RETRIES {
+                error!("Giving up writing NVS key {}: {}", key, err);
+                break;
+            }
+
+            warn!("Writing NVS key {} failed: {}, retrying", key, err);
+
+            Timer::after(delay).await;
+
+            delay = delay * 2;
+            retries += 1;
+        }
+    }
+
+    pending.clear();
+}
+
+fn write(nvs: &mut EspNvs<NvsDefault>, key: &str, value: Option<&[u8]>) -> Result<(), EspError> {
+    if let Some(value) = value {
+        nvs.set_raw(key, value)?;
+    } else {
+        nvs.remove(key)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use embassy_futures::select::{select, Either};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+
+use embassy_time::{Duration, Timer};
+
+use enumset::{enum_set, EnumSet};
+
+use log::{info, warn};
+
+use crate::bus::{
+    diag::{Diagnostic, Thermal},
+    BusSubscription, Service,
+};
+use crate::error::Error;
+use crate::signal::{QueueSender, StatefulSender};
+use crate::storage::Storage;
+
+const PERIOD: Duration = Duration::from_secs(10);
+
+/// With hysteresis, so that the nonessential services do not flip on and off
+const THROTTLE_ON_CELSIUS: i16 = 80;
+const THROTTLE_OFF_CELSIUS: i16 = 70;
+
+const PEAK_KEY: &str = "peak_temp";
+
+/// Services suspended while throttling
+const NONESSENTIAL: EnumSet<Service> = enum_set!(Service::Wifi | Service::Presence);
+
+extern "C" {
+    // Undocumented ROM function of the ESP32; uncalibrated, in Fahrenheit
+    fn temprature_sens_read() -> u8;
+}
+
+pub async fn process(
+    bus: BusSubscription<'_>,
+    thermal: StatefulSender<'_, impl RawMutex, Thermal>,
+    diagnostics: QueueSender<'_, impl RawMutex, Diagnostic>,
+    storage: &Storage,
+) -> Result<(), Error> {
+    let mut stored_peak = storage
+        .get_u32(PEAK_KEY)
+        .await?
+        .map(|peak| peak as i16)
+        .unwrap_or(i16::MIN);
+
+    info!("Peak temperature so far: {}C", stored_peak);
+
+    loop {
+        let _started = bus.service.started_when_enabled().await?;
+
+        loop {
+            match select(bus.service.wait_disabled(), Timer::after(PERIOD)).await {
+                Either::First(other) => break other?,
+                Either::Second(_) => {
+                    let celsius = read_celsius();
+
+                    let mut throttling = None;
+
+                    thermal.modify(|thermal| {
+                        thermal.celsius = celsius;
+                        thermal.peak = thermal.peak.max(celsius);
+
+                        let throttled = if thermal.throttled {
+                            celsius > THROTTLE_OFF_CELSIUS
+                        } else {
+                            celsius >= THROTTLE_ON_CELSIUS
+                        };
+
+                        if throttled != thermal.throttled {
+                            thermal.throttled = throttled;
+                            throttling = Some(throttled);
+                        }
+
+                        true
+                    });
+
+                    if let Some(throttled) = throttling {
+                        if throttled {
+                            warn!("Overheating ({}C), throttling", celsius);
+                            diagnostics.send(Diagnostic::Overheat { celsius });
+                        } else {
+                            info!("Cooled down ({}C)", celsius);
+                        }
+
+                        bus.service.sys_suspend(if throttled {
+                            NONESSENTIAL
+                        } else {
+                            EnumSet::EMPTY
+                        });
+                    }
+
+                    if celsius > stored_peak {
+                        stored_peak = celsius;
+                        storage.set_u32(PEAK_KEY, celsius as u32).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn read_celsius() -> i16 {
+    let fahrenheit = unsafe { temprature_sens_read() } as i16;
+
+    (fahrenheit - 32) * 5 / 9
+}
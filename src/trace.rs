@@ -0,0 +1,104 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use embassy_time::Instant;
+
+use enumset::{EnumSet, EnumSetType};
+
+use log::info;
+
+const MAX_RECORDS: usize = 128;
+
+/// The key fields of an event, e.g. its status and the low bytes of the peer address
+pub const MAX_ARGS: usize = 2;
+
+#[derive(Debug, EnumSetType)]
+#[enumset(repr = "u8")]
+pub enum Profile {
+    A2dp,
+    Avrcp,
+    Hfp,
+}
+
+impl Profile {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "a2dp" => Some(Self::A2dp),
+            "avrcp" => Some(Self::Avrcp),
+            "hfp" => Some(Self::Hfp),
+            _ => None,
+        }
+    }
+}
+
+/// A compact, fixed-size record: the time, the profile, the event variant and its key fields
+struct Record {
+    millis: u32,
+    profile: Profile,
+    event: &'static str,
+    args: [u32; MAX_ARGS],
+}
+
+static ENABLED: AtomicU8 = AtomicU8::new(0);
+
+static RECORDS: Mutex<CriticalSectionRawMutex, RefCell<heapless::Deque<Record, MAX_RECORDS>>> =
+    Mutex::new(RefCell::new(heapless::Deque::new()));
+
+pub fn enable(profile: Profile, enabled: bool) {
+    let mut profiles = enabled_profiles();
+
+    if enabled {
+        profiles |= profile;
+    } else {
+        profiles -= profile;
+    }
+
+    ENABLED.store(profiles.as_repr(), Ordering::SeqCst);
+}
+
+pub fn enabled_profiles() -> EnumSet<Profile> {
+    EnumSet::from_repr_truncated(ENABLED.load(Ordering::SeqCst))
+}
+
+/// Cheap unless tracing is enabled for the profile, as only then the event is described.
+/// The oldest records are dropped once full
+pub fn record<F>(profile: Profile, describe: F)
+where
+    F: FnOnce() -> (&'static str, [u32; MAX_ARGS]),
+{
+    if !enabled_profiles().contains(profile) {
+        return;
+    }
+
+    let (event, args) = describe();
+
+    let record = Record {
+        millis: Instant::now().as_millis() as _,
+        profile,
+        event,
+        args,
+    };
+
+    RECORDS.lock(|records| {
+        let mut records = records.borrow_mut();
+
+        if records.is_full() {
+            records.pop_front();
+        }
+
+        let _ = records.push_back(record);
+    });
+}
+
+/// Logs and clears the records, oldest first, i.e. over the console, as there is no HTTP portal
+pub fn dump() {
+    while let Some(record) = RECORDS.lock(|records| records.borrow_mut().pop_front()) {
+        info!(
+            "{:>8} {:?}: {} {:08x?}",
+            record.millis, record.profile, record.event, record.args
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use core::fmt::Write;
 use core::pin::pin;
 
 use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
@@ -15,47 +16,98 @@ use esp_idf_svc::{
     wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi},
 };
 
-use crate::{bus::BusSubscription, error::Error, select_spawn::SelectSpawn, signal::Receiver};
+use log::{info, warn};
+
+use crate::{
+    arena::Arena,
+    bus::{can::Notification, diag::Diagnostic, BusSubscription, DisplayString, UpdateRequest},
+    error::Error,
+    select_spawn::SelectSpawn,
+    service::ServiceLifecycle,
+    signal::{QueueSender, Receiver, StatefulSender},
+};
+
+pub const OTA_BUF_SIZE: usize = 4096;
+
+const CHECK_DURATION: core::time::Duration = core::time::Duration::from_secs(10);
 
 pub async fn process(
     bus: BusSubscription<'_>,
     modem: &Mutex<impl RawMutex, impl Peripheral<P = impl WifiModemPeripheral>>,
     sysloop: EspSystemEventLoop,
     timer_service: EspTaskTimerService,
+    arena: &Arena<'_>,
+    notification: StatefulSender<'_, impl RawMutex, Notification>,
+    diagnostics: QueueSender<'_, impl RawMutex, Diagnostic>,
 ) -> Result<(), Error> {
     loop {
         bus.service.wait_enabled().await?;
 
         bus.service.starting();
 
-        let mut modem = modem.lock().await;
-
-        let mut driver = AsyncWifi::wrap(
-            create(&mut modem, sysloop.clone())?,
-            sysloop.clone(),
-            timer_service.clone(),
-        )?;
-
         let _started = bus.service.started();
 
         SelectSpawn::run(&mut pin!(bus.service.wait_disabled()))
-            .chain(&mut pin!(process_update(&mut driver, &bus.update)))
+            .chain(&mut pin!(process_update(
+                modem,
+                &sysloop,
+                &timer_service,
+                &bus.update,
+                &bus.service,
+                arena,
+                &notification,
+                &diagnostics
+            )))
             .await?;
     }
 }
 
+/// Idles without the WiFi driver, and only borrows the modem for the duration of an update
+#[allow(clippy::too_many_arguments)]
 async fn process_update(
-    driver: &mut AsyncWifi<EspWifi<'_>>,
-    update_request: &Receiver<'_, impl RawMutex, ()>,
+    modem: &Mutex<impl RawMutex, impl Peripheral<P = impl WifiModemPeripheral>>,
+    sysloop: &EspSystemEventLoop,
+    timer_service: &EspTaskTimerService,
+    update_request: &Receiver<'_, impl RawMutex, UpdateRequest>,
+    service: &ServiceLifecycle<'_, impl RawMutex>,
+    arena: &Arena<'_>,
+    notification: &StatefulSender<'_, impl RawMutex, Notification>,
+    diagnostics: &QueueSender<'_, impl RawMutex, Diagnostic>,
 ) -> Result<(), Error> {
     loop {
-        update_request.recv().await;
+        let request = update_request.recv().await;
 
-        connect(driver).await?;
+        let mut buf = match arena.borrow(OTA_BUF_SIZE) {
+            Some(buf) => buf,
+            None => {
+                diagnostics.send(Diagnostic::ArenaExhausted { len: OTA_BUF_SIZE });
+                continue;
+            }
+        };
 
-        update().await?;
+        let mut modem = modem.lock().await;
+
+        let mut driver = AsyncWifi::wrap(
+            create(&mut modem, sysloop.clone())?,
+            sysloop.clone(),
+            timer_service.clone(),
+        )?;
+
+        connect(&mut driver).await?;
+
+        if let Err(err) = update(&mut buf, request, notification).await {
+            warn!("Update failed: {}", err);
+
+            diagnostics.send(Diagnostic::UpdateFailed);
+        }
 
         driver.stop().await?;
+
+        info!("Update check done, releasing the modem");
+
+        if request == UpdateRequest::Check {
+            service.sys_set_normal_mode();
+        }
     }
 }
 
@@ -90,7 +142,11 @@ async fn connect(driver: &mut AsyncWifi<EspWifi<'_>>) -> Result<(), Error> {
     }
 }
 
-async fn update() -> Result<(), Error> {
+async fn update(
+    buf: &mut [u8],
+    request: UpdateRequest,
+    notification: &StatefulSender<'_, impl RawMutex, Notification>,
+) -> Result<(), Error> {
     let mut http = EspHttpConnection::new(&client::Configuration {
         buffer_size: Some(1024),
         follow_redirects_policy: FollowRedirectsPolicy::FollowAll,
@@ -104,9 +160,7 @@ async fn update() -> Result<(), Error> {
 
     let mut firmware_info_loader = EspFirmwareInfoLoader::new();
 
-    let mut buf = [0; 1024]; // TODO
-
-    let size = try_read_full(&mut http, &mut buf).map_err(|(e, _)| e.0)?;
+    let size = try_read_full(&mut http, buf).map_err(|(e, _)| e.0)?;
 
     firmware_info_loader.load(&buf[..size])?;
 
@@ -122,13 +176,24 @@ async fn update() -> Result<(), Error> {
         true
     };
 
-    if update {
+    if !update {
+        info!("Firmware up to date");
+
+        post(notification, "UP TO DATE");
+    } else if request == UpdateRequest::Check {
+        info!("Firmware {} available", new_firmware.version);
+
+        let mut text = DisplayString::new();
+        let _ = write!(&mut text, "V{} AVAILABLE", new_firmware.version);
+
+        post(notification, &text);
+    } else {
         let mut update = ota.initiate_update()?;
 
         loop {
             update.write(&buf[..size])?;
 
-            let size = try_read_full(&mut http, &mut buf).map_err(|(e, _)| e.0)?;
+            let size = try_read_full(&mut http, buf).map_err(|(e, _)| e.0)?;
 
             if size == 0 {
                 break;
@@ -141,6 +206,16 @@ async fn update() -> Result<(), Error> {
     Ok(())
 }
 
+fn post(notification: &StatefulSender<'_, impl RawMutex, Notification>, text: &str) {
+    notification.modify(|notification| {
+        notification.post(text, CHECK_DURATION);
+        true
+    });
+}
+
+// NOTE: A slimmer network path (the WiFi driver without esp-netif and lwIP, with `embassy-net`
+// on top) is not offered, as the firmware is downloaded over HTTPS, and the TLS client of ESP-IDF
+// (esp-tls) only runs over lwIP sockets. The update mode anyway runs with Bluetooth stopped
 fn create<'d>(
     modem: impl Peripheral<P = impl WifiModemPeripheral> + 'd,
     sysloop: EspSystemEventLoop,
@@ -0,0 +1,20 @@
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("FIAT_GIT_HASH");
+pub const BUILD_DATE: &str = env!("FIAT_BUILD_DATE");
+
+pub fn major() -> u8 {
+    env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0)
+}
+
+pub fn minor() -> u8 {
+    env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0)
+}
+
+pub fn patch() -> u8 {
+    env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0)
+}
+
+/// The git hash as a number, or 0 if the firmware was not built from a git checkout
+pub fn build() -> u32 {
+    u32::from_str_radix(GIT_HASH, 16).unwrap_or(0)
+}
@@ -0,0 +1,168 @@
+//! Contributor workflow: building, flashing and monitoring the firmware with feature presets.
+//!
+//! Being a host tool, it is built for the host target rather than for the ESP32, which the
+//! `xtask` alias of `.cargo/config.toml` takes care of:
+//!
+//! ```sh
+//! cargo xtask flash full
+//! ```
+
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{exit, Command, ExitStatus, Stdio};
+
+const BINARY: &str = "target/xtensa-esp32-espidf/release/fiat-a2dp";
+
+/// Feature sets of the firmware variants
+const PRESETS: &[(&str, &[&str])] = &[
+    ("full", &[]),
+    ("standalone", &["standalone"]),
+    ("spdif", &["spdif"]),
+    ("pcm5102", &["dac-pcm5102"]),
+    ("uda1334", &["dac-uda1334"]),
+    ("no-mic", &["no-mic"]),
+    ("sim", &["sim"]),
+];
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    let result = match args.as_slice() {
+        ["build", preset] => build(preset),
+        ["flash", preset] => build(preset).and_then(|_| flash()),
+        ["monitor"] => monitor(),
+        _ => Err(usage()),
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn usage() -> String {
+    let presets = PRESETS
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join("|");
+
+    format!("Usage: xtask build <{presets}> | flash <{presets}> | monitor")
+}
+
+fn build(preset: &str) -> Result<(), String> {
+    let features = PRESETS
+        .iter()
+        .find(|(name, _)| *name == preset)
+        .map(|(_, features)| features.join(","))
+        .ok_or_else(usage)?;
+
+    // The toolchain and target of the firmware are picked up from its own directory
+    let mut command = Command::new("cargo");
+    command
+        .current_dir(root())
+        .env_remove("RUSTUP_TOOLCHAIN")
+        .args(["build", "--release"]);
+
+    if !features.is_empty() {
+        command.args(["--features", &features]);
+    }
+
+    check(command.status())
+}
+
+fn flash() -> Result<(), String> {
+    check(
+        Command::new("espflash")
+            .current_dir(root())
+            .args(["flash", BINARY])
+            .status(),
+    )?;
+
+    monitor()
+}
+
+/// Runs the espflash monitor, condensing the log lines of the firmware
+fn monitor() -> Result<(), String> {
+    let mut child = Command::new("espflash")
+        .arg("monitor")
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Running espflash failed: {err}"))?;
+
+    let stdout = child.stdout.take().unwrap();
+
+    for line in BufReader::new(stdout).lines() {
+        let line = line.map_err(|err| err.to_string())?;
+
+        println!("{}", decode(&strip_ansi(&line)).unwrap_or(line));
+    }
+
+    check(child.wait())
+}
+
+/// Decodes an ESP-IDF log line (`I (1234) fiat_a2dp::bt: message`)
+/// into `  1.234 INFO  bt: message`
+fn decode(line: &str) -> Option<String> {
+    let (level, rest) = line.split_once(" (")?;
+    let (millis, rest) = rest.split_once(") ")?;
+    let (module, message) = rest.split_once(": ")?;
+
+    let level = match level {
+        "E" => "ERROR",
+        "W" => "WARN",
+        "I" => "INFO",
+        "D" => "DEBUG",
+        "V" => "TRACE",
+        _ => return None,
+    };
+
+    let millis = millis.parse::<u64>().ok()?;
+    let module = module.strip_prefix("fiat_a2dp::").unwrap_or(module);
+
+    Some(format!(
+        "{:>4}.{:03} {:<5} {}: {}",
+        millis / 1000,
+        millis % 1000,
+        level,
+        module,
+        message
+    ))
+}
+
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the CSI sequence up to and including its final letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+fn check(status: std::io::Result<ExitStatus>) -> Result<(), String> {
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("Failed: {status}")),
+        Err(err) => Err(format!("Failed: {err}")),
+    }
+}